@@ -1,50 +1,143 @@
+use num_rational::BigRational;
+
+use crate::error::Span;
+use crate::units::Unit;
+
 #[derive(Debug, Clone)]
 pub(crate) enum Stmt {
-    Fun { idx: u64, body: Box<Expr> },
-    Assign { idx: u64, expr: Box<Expr> },
-    Expr { expr: Box<Expr> },
-    Magic { kind: MagicKind },
+    Fun {
+        idx: u64,
+        params: Vec<u64>,
+        body: Box<Expr>,
+        memo: bool,
+    },
+    Assign {
+        idx: u64,
+        expr: Box<Expr>,
+    },
+    Expr {
+        expr: Box<Expr>,
+    },
+    Magic {
+        idx: u64,
+        args: Vec<MagicExpr>,
+        span: Span,
+    },
+    // 'diff(f)' shows f'
+    Diff {
+        idx: u64,
+        span: Span,
+    },
 }
 
+// an arg of a magic, parsed as its `MagicArg` says
 #[derive(Debug, Clone)]
-pub(crate) enum MagicKind {
-    Plot2d(u64, Box<Expr>, Box<Expr>, Box<Expr>),
+pub(crate) enum MagicExpr {
+    Names(Vec<MagicName>, Span),
+    Expr(Box<Expr>),
+    // an `OptExpr` left out, at the closing ')'
+    Omitted(Span),
+}
+
+// a name given to a magic, or a function like 'x -> x^2' in place of one
+#[derive(Debug, Clone)]
+pub(crate) enum MagicName {
+    Idx(u64),
+    Lambda(Box<Expr>),
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Valuable {
-    Value(f64), // normal number
-    Arg(usize), // arg in function
-    Var(u64),   // variable
+    Value(f64, Box<BigRational>), // normal number, and its exact value
+    Imag(f64),                    // imaginary number like 4i
+    Quantity(f64, Box<Unit>),     // number with a unit like 3 km
+    Arg(usize),                   // arg in function
+    Var(u64),                     // variable
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Expr {
     Literal {
         value: Valuable,
+        span: Span,
     },
     Group {
         body: Box<Expr>,
+        span: Span,
     },
     Unary {
         op: UnaryOp,
         operand: Box<Expr>,
+        span: Span,
     },
     Binary {
         left: Box<Expr>,
         op: BinaryOp,
         right: Box<Expr>,
+        span: Span,
     },
     Call {
         idx: u64,
         args: Vec<Expr>,
+        span: Span,
+    },
+    List {
+        items: Vec<Expr>,
+        span: Span,
+    },
+    // only the taken branch is evaluated
+    Cond {
+        cond: Box<Expr>,
+        then: Box<Expr>,
+        other: Box<Expr>,
+        span: Span,
+    },
+    // 'expr in unit'
+    Convert {
+        expr: Box<Expr>,
+        unit: Box<Unit>,
+        span: Span,
     },
+    // 'x -> x^2' or '(x, y) -> x + y', only an arg of a function like `map`
+    // or a magic, the params are the args after the ones in scope
+    Lambda {
+        params: Vec<u64>,
+        body: Box<Expr>,
+        span: Span,
+    },
+    // 'let a = 1, b = a + 1 in a * b' or '{ a = 1; b = a + 1; a * b }', the
+    // names are the args after the ones in scope, each value sees the names
+    // before it, and each is evaluated once
+    Let {
+        bindings: Vec<(u64, Expr)>,
+        body: Box<Expr>,
+        block: bool,
+        span: Span,
+    },
+}
+
+impl Expr {
+    pub(crate) fn span(&self) -> Span {
+        match self {
+            Expr::Literal { span, .. }
+            | Expr::Group { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::List { span, .. }
+            | Expr::Cond { span, .. }
+            | Expr::Convert { span, .. }
+            | Expr::Lambda { span, .. }
+            | Expr::Let { span, .. } => *span,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum UnaryOp {
     Minus,
     Ftl,
+    Not,
 }
 
 #[derive(Debug, Clone)]
@@ -53,5 +146,15 @@ pub(crate) enum BinaryOp {
     Sub,
     Mult,
     Div,
+    MatMul, // '@', the product of matrices
     Square,
+    Less,
+    LessEq,
+    Eq,
+    NotEq,
+    Greater,
+    GreaterEq,
+    And,   // short-circuit
+    Or,    // short-circuit
+    Range, // 'a..b', both ends included
 }
@@ -0,0 +1,307 @@
+use std::rc::Rc;
+
+use num_complex::Complex64;
+
+use crate::{
+    error::{CalcError, CalcResult, ErrorKind},
+    matrix,
+    onemore::OneMore,
+    random::{self, Rng},
+    stats,
+};
+
+// shared, so compiled expressions can keep the functions they call
+pub(crate) type CalcFn = Rc<dyn Fn(&[f64]) -> CalcResult<OneMore>>;
+
+// takes the args as they are, `None` leaves them to the function on numbers
+type MatrixFn = fn(&[OneMore]) -> CalcResult<Option<OneMore>>;
+
+/// The longest list a range or `linspace` can make.
+pub(crate) const MAX_LIST_LEN: usize = 10_000_000;
+
+/// How a native function takes its args.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    /// Exactly this many numbers, with lists the function is applied
+    /// element-wise.
+    Exact(usize),
+    /// Like `Exact`, but the last args are optional.
+    Between(usize, usize),
+    /// Any numbers and lists, all flattened into one slice.
+    Variadic,
+}
+
+#[derive(Clone)]
+pub(crate) struct NativeFun {
+    pub(crate) fun: CalcFn,
+    pub(crate) arity: Arity,
+    // for a function of one arg, the same function on complex numbers
+    pub(crate) complex: Option<fn(Complex64) -> Complex64>,
+    // the same function on matrices, which are not applied element-wise
+    pub(crate) matrix: Option<MatrixFn>,
+}
+
+impl NativeFun {
+    pub(crate) fn new(arity: Arity, fun: CalcFn) -> Self {
+        NativeFun {
+            fun,
+            arity,
+            complex: None,
+            matrix: None,
+        }
+    }
+
+    fn with_complex(mut self, f: fn(Complex64) -> Complex64) -> Self {
+        self.complex = Some(f);
+        self
+    }
+
+    fn with_matrix(mut self, f: MatrixFn) -> Self {
+        self.matrix = Some(f);
+        self
+    }
+}
+
+macro_rules! f64method_to_native {
+    ($name:tt) => {
+        unary(f64::$name)
+    };
+}
+
+fn native(arity: Arity, f: fn(&[f64]) -> CalcResult<f64>) -> NativeFun {
+    NativeFun::new(
+        arity,
+        Rc::new(move |args: &[f64]| f(args).map(OneMore::One)),
+    )
+}
+
+fn unary(f: fn(f64) -> f64) -> NativeFun {
+    NativeFun::new(
+        Arity::Exact(1),
+        Rc::new(move |args: &[f64]| Ok(OneMore::One(f(args[0])))),
+    )
+}
+
+fn binary(f: fn(f64, f64) -> f64) -> NativeFun {
+    NativeFun::new(
+        Arity::Exact(2),
+        Rc::new(move |args: &[f64]| Ok(OneMore::One(f(args[0], args[1])))),
+    )
+}
+
+fn reduce(f: fn(&[f64]) -> CalcResult<f64>) -> NativeFun {
+    native(Arity::Variadic, f)
+}
+
+fn random(rng: &Rc<Rng>, arity: Arity, f: fn(&Rng, &[f64]) -> CalcResult<f64>) -> NativeFun {
+    let rng = Rc::clone(rng);
+    NativeFun::new(
+        arity,
+        Rc::new(move |args: &[f64]| f(&rng, args).map(OneMore::One)),
+    )
+}
+
+pub(crate) fn builtins() -> Vec<(&'static str, NativeFun)> {
+    // one generator for the random functions of an env, so `seed` sets them all
+    let rng = Rc::new(Rng::new());
+    vec![
+        ("ln", f64method_to_native!(ln).with_complex(Complex64::ln)),
+        (
+            "lg",
+            f64method_to_native!(log10).with_complex(Complex64::log10),
+        ),
+        (
+            "exp",
+            f64method_to_native!(exp).with_complex(Complex64::exp),
+        ),
+        (
+            "sin",
+            f64method_to_native!(sin).with_complex(Complex64::sin),
+        ),
+        (
+            "cos",
+            f64method_to_native!(cos).with_complex(Complex64::cos),
+        ),
+        (
+            "tan",
+            f64method_to_native!(tan).with_complex(Complex64::tan),
+        ),
+        (
+            "acos",
+            f64method_to_native!(acos).with_complex(Complex64::acos),
+        ),
+        (
+            "asin",
+            f64method_to_native!(asin).with_complex(Complex64::asin),
+        ),
+        (
+            "atan",
+            f64method_to_native!(atan).with_complex(Complex64::atan),
+        ),
+        (
+            "sqrt",
+            f64method_to_native!(sqrt).with_complex(Complex64::sqrt),
+        ),
+        (
+            "abs",
+            f64method_to_native!(abs).with_complex(|z| z.norm().into()),
+        ),
+        (
+            "sinh",
+            f64method_to_native!(sinh).with_complex(Complex64::sinh),
+        ),
+        (
+            "cosh",
+            f64method_to_native!(cosh).with_complex(Complex64::cosh),
+        ),
+        ("floor", f64method_to_native!(floor)),
+        ("to_rad", f64method_to_native!(to_radians)),
+        // the parts of a complex number, on a real number they are trivial
+        ("re", unary(|x| x).with_complex(|z| z.re.into())),
+        ("im", unary(|_| 0.0).with_complex(|z| z.im.into())),
+        (
+            "arg",
+            unary(|x| 0f64.atan2(x)).with_complex(|z| z.arg().into()),
+        ),
+        ("conj", unary(|x| x).with_complex(|z| z.conj())),
+        ("atan2", binary(f64::atan2)),
+        ("hypot", binary(f64::hypot)),
+        ("pow", binary(f64::powf)),
+        ("log", binary(|base, x| x.ln() / base.ln())),
+        // the sign follows the divisor, so mod(-1, 3) is 2
+        ("mod", binary(|a, b| a - b * (a / b).floor())),
+        ("gcd", native(Arity::Exact(2), gcd)),
+        ("lcm", native(Arity::Exact(2), lcm)),
+        ("round", native(Arity::Between(1, 2), round)),
+        (
+            "linspace",
+            NativeFun::new(Arity::Exact(3), Rc::new(linspace)),
+        ),
+        // on a number they are trivial, like a 1x1 matrix
+        ("transpose", unary(|x| x).with_matrix(matrix::transpose)),
+        ("det", unary(|x| x).with_matrix(matrix::det)),
+        ("inv", unary(|x| 1.0 / x).with_matrix(matrix::inv)),
+        ("eig", unary(|x| x).with_matrix(matrix::eig)),
+        ("solve", binary(|a, b| b / a).with_matrix(matrix::solve)),
+        // `Sum` of f64 starts from -0, so an empty sum would show '-0'
+        ("sum", reduce(|xs| Ok(xs.iter().fold(0.0, |a, b| a + b)))),
+        ("len", reduce(|xs| Ok(xs.len() as f64))),
+        (
+            "mean",
+            reduce(|xs| {
+                not_empty(xs)?;
+                Ok(xs.iter().sum::<f64>() / xs.len() as f64)
+            }),
+        ),
+        (
+            "min",
+            reduce(|xs| {
+                not_empty(xs)?;
+                Ok(xs.iter().copied().fold(f64::INFINITY, f64::min))
+            }),
+        ),
+        (
+            "max",
+            reduce(|xs| {
+                not_empty(xs)?;
+                Ok(xs.iter().copied().fold(f64::NEG_INFINITY, f64::max))
+            }),
+        ),
+        ("median", reduce(stats::median)),
+        ("var", reduce(stats::var)),
+        ("stdev", reduce(stats::stdev)),
+        // percentile(xs, p), the list is taken whole
+        (
+            "percentile",
+            native(Arity::Exact(2), stats::percentile).with_matrix(stats::percentile_list),
+        ),
+        ("nCr", native(Arity::Exact(2), stats::ncr)),
+        ("nPr", native(Arity::Exact(2), stats::npr)),
+        ("gamma", native(Arity::Exact(1), stats::gamma_checked)),
+        ("erf", unary(stats::erf)),
+        // the mean and the standard deviation are 0 and 1 if left out
+        ("normpdf", native(Arity::Between(1, 3), stats::normpdf)),
+        ("normcdf", native(Arity::Between(1, 3), stats::normcdf)),
+        ("norminv", native(Arity::Between(1, 3), stats::norminv)),
+        ("binompdf", native(Arity::Exact(3), stats::binompdf)),
+        ("poissonpdf", native(Arity::Exact(2), stats::poissonpdf)),
+        ("rand", random(&rng, Arity::Exact(0), random::rand)),
+        ("randint", random(&rng, Arity::Exact(2), random::randint)),
+        ("randn", random(&rng, Arity::Exact(0), random::randn)),
+        ("seed", random(&rng, Arity::Exact(1), random::seed)),
+    ]
+}
+
+fn not_empty(args: &[f64]) -> CalcResult<()> {
+    if args.is_empty() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            "expect at least one number, but get an empty list",
+        ));
+    }
+    Ok(())
+}
+
+fn integer(x: f64) -> CalcResult<f64> {
+    if x.fract() != 0.0 || !x.is_finite() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!("expect an integer, but get {}", x),
+        ));
+    }
+    Ok(x)
+}
+
+fn gcd(args: &[f64]) -> CalcResult<f64> {
+    let (mut a, mut b) = (integer(args[0])?.abs(), integer(args[1])?.abs());
+    while b != 0.0 {
+        (a, b) = (b, a % b);
+    }
+    Ok(a)
+}
+
+fn lcm(args: &[f64]) -> CalcResult<f64> {
+    let g = gcd(args)?;
+    if g == 0.0 {
+        return Ok(0.0);
+    }
+    Ok((args[0] / g * args[1]).abs())
+}
+
+// round(x) or round(x, digits), the digits can be negative
+fn round(args: &[f64]) -> CalcResult<f64> {
+    let digits = match args.get(1) {
+        Some(d) => integer(*d)?,
+        None => 0.0,
+    };
+    let scale = 10f64.powf(digits);
+    let scaled = args[0] * scale;
+    // so big it has no digits that far down, or the scale is inf past 308
+    if !scaled.is_finite() {
+        return Ok(args[0]);
+    }
+    // the scale is 0 past -323, every f64 is less than half of 10^309
+    if scale == 0.0 {
+        return Ok(0.0);
+    }
+    Ok(scaled.round() / scale)
+}
+
+fn linspace(args: &[f64]) -> CalcResult<OneMore> {
+    let (a, b, n) = (args[0], args[1], args[2]);
+    if n < 1.0 || n.fract() != 0.0 || n > MAX_LIST_LEN as f64 {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "the count of linspace must be an integer in 1..{}, but get {}",
+                MAX_LIST_LEN, n
+            ),
+        ));
+    }
+    let n = n as usize;
+    if n == 1 {
+        return Ok(OneMore::More(vec![a]));
+    }
+    let step = (b - a) / (n - 1) as f64;
+    Ok(OneMore::More((0..n).map(|i| a + step * i as f64).collect()))
+}
@@ -0,0 +1,664 @@
+// Expressions lowered to a flat stack bytecode, evaluated without walking
+// the `Expr` tree, see `Env::compile`.
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{
+    ast::{BinaryOp, Expr, UnaryOp, Valuable},
+    builtin::{Arity, NativeFun},
+    env::{arity_error, binary_fn, boolean, ftl, truth, Env, Function},
+    error::{CalcError, CalcResult, ErrorKind, Span},
+    units,
+    utils::hash_it,
+};
+
+enum Op {
+    Const(f64),
+    // a variable given to `eval`
+    Var(usize),
+    // an arg of the function being called
+    Arg(usize),
+    // a name of a let in the function being called
+    Local(usize),
+    // the value on the stack is the next name of a let
+    Bind,
+    // the names of a let go out of scope
+    Unbind(usize),
+    Neg,
+    Not,
+    // any number to 1 or 0
+    Truth,
+    Fact(Span),
+    Binary(fn(f64, f64) -> f64),
+    Native {
+        f: NativeFun,
+        argc: usize,
+        name: Option<String>,
+        span: Span,
+    },
+    Call {
+        chunk: usize,
+        span: Span,
+    },
+    Jump(usize),
+    JumpIfFalse(usize),
+    // an error found when compiling, but only raised if it is reached,
+    // like an undefined name in a branch not taken
+    Fail(Box<CalcError>),
+    Return,
+}
+
+// the code of the expression (chunk 0) or of a user function
+struct Chunk {
+    ops: Vec<Op>,
+    arity: usize,
+    name: Option<String>,
+    memo: Option<RefCell<HashMap<Vec<u64>, f64>>>,
+}
+
+struct Frame {
+    chunk: usize,
+    pc: usize,
+    base: usize,
+    local: usize,
+    span: Span,
+}
+
+/// An expression compiled by `Env::compile`, evaluated on numbers many
+/// times much faster than `Env::eval`.
+///
+/// Globals and functions are taken as they are when compiling, so it must
+/// be compiled again to see later changes of them.
+///
+/// It computes on real numbers only: where `Env::run` gives a complex
+/// number, like `sqrt(-1)` or `(-8)^(1/3)`, it gives NaN, so a plot leaves
+/// a gap there.
+pub struct CompiledExpr {
+    vars: Vec<String>,
+    chunks: Vec<Chunk>,
+    max_depth: usize,
+}
+
+impl CompiledExpr {
+    /// The names of the variables, in the order `eval` takes them.
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    pub fn eval(&self, values: &[f64]) -> Result<f64, CalcError> {
+        if values.len() != self.vars.len() {
+            return Err(CalcError::new(
+                ErrorKind::Arity,
+                format!(
+                    "expect {} variables, but get {}",
+                    self.vars.len(),
+                    values.len()
+                ),
+            ));
+        }
+        self.run(values)
+    }
+
+    /// Like `eval`, but the variables are looked up by name.
+    pub fn eval_map(&self, vars: &HashMap<&str, f64>) -> Result<f64, CalcError> {
+        let mut values = Vec::with_capacity(self.vars.len());
+        for name in &self.vars {
+            match vars.get(name.as_str()) {
+                Some(v) => values.push(*v),
+                None => {
+                    return Err(CalcError::new(
+                        ErrorKind::UnknownName,
+                        format!("can't find variable named '{}'", name),
+                    )
+                    .with_name(Some(name)))
+                }
+            }
+        }
+        self.run(&values)
+    }
+
+    fn run(&self, values: &[f64]) -> CalcResult<f64> {
+        // a cached result may depend on the variables
+        for chunk in &self.chunks {
+            if let Some(memo) = &chunk.memo {
+                memo.borrow_mut().clear();
+            }
+        }
+        let mut stack: Vec<f64> = Vec::new();
+        let mut frames: Vec<Frame> = Vec::new();
+        // the values of the lets, apart from the stack so they stay put
+        let mut locals: Vec<f64> = Vec::new();
+        let (mut chunk, mut pc, mut base, mut local) = (0, 0, 0, 0);
+        loop {
+            let op = &self.chunks[chunk].ops[pc];
+            pc += 1;
+            match op {
+                Op::Const(v) => stack.push(*v),
+                Op::Var(i) => stack.push(values[*i]),
+                Op::Arg(i) => stack.push(stack[base + i]),
+                Op::Local(i) => stack.push(locals[local + i]),
+                Op::Bind => locals.push(stack.pop().unwrap()),
+                Op::Unbind(n) => locals.truncate(locals.len() - n),
+                Op::Neg => top(&mut stack, |x| -x),
+                Op::Not => top(&mut stack, |x| boolean(!truth(x))),
+                Op::Truth => top(&mut stack, |x| boolean(truth(x))),
+                Op::Fact(span) => {
+                    let x = stack.pop().unwrap();
+                    match ftl(x) {
+                        Ok(v) => stack.push(v),
+                        Err(e) => return Err(at_call(e.with_span(*span), &frames)),
+                    }
+                }
+                Op::Binary(f) => {
+                    let b = stack.pop().unwrap();
+                    top(&mut stack, |a| f(a, b));
+                }
+                Op::Native {
+                    f,
+                    argc,
+                    name,
+                    span,
+                } => {
+                    let at = stack.len() - argc;
+                    let v = (f.fun)(&stack[at..]).and_then(|v| {
+                        v.one().ok_or_else(|| {
+                            CalcError::new(ErrorKind::Domain, "expect a real number")
+                        })
+                    });
+                    let v = v.map_err(|e| {
+                        at_call(e.with_name(name.as_deref()).with_span(*span), &frames)
+                    })?;
+                    stack.truncate(at);
+                    stack.push(v);
+                }
+                Op::Call { chunk: c, span } => {
+                    let callee = &self.chunks[*c];
+                    let at = stack.len() - callee.arity;
+                    if let Some(memo) = &callee.memo {
+                        if let Some(v) = memo.borrow().get(&key(&stack[at..])) {
+                            stack.truncate(at);
+                            stack.push(*v);
+                            continue;
+                        }
+                    }
+                    if frames.len() >= self.max_depth {
+                        let name = callee.name.as_deref();
+                        let e = CalcError::new(
+                            ErrorKind::Recursion,
+                            format!(
+                                "recursion limit exceeded: more than {} calls deep in {}",
+                                self.max_depth,
+                                name.unwrap_or("Unknown")
+                            ),
+                        )
+                        .with_name(name)
+                        .with_span(*span);
+                        return Err(at_call(e, &frames));
+                    }
+                    frames.push(Frame {
+                        chunk,
+                        pc,
+                        base,
+                        local,
+                        span: *span,
+                    });
+                    (chunk, pc, base, local) = (*c, 0, at, locals.len());
+                }
+                Op::Jump(to) => pc = *to,
+                Op::JumpIfFalse(to) => {
+                    if !truth(stack.pop().unwrap()) {
+                        pc = *to;
+                    }
+                }
+                Op::Fail(e) => return Err(at_call((**e).clone(), &frames)),
+                Op::Return => {
+                    let v = stack.pop().unwrap();
+                    let frame = match frames.pop() {
+                        Some(frame) => frame,
+                        None => return Ok(v),
+                    };
+                    let callee = &self.chunks[chunk];
+                    if let Some(memo) = &callee.memo {
+                        let args = &stack[base..base + callee.arity];
+                        memo.borrow_mut().insert(key(args), v);
+                    }
+                    stack.truncate(base);
+                    stack.push(v);
+                    (chunk, pc, base, local) = (frame.chunk, frame.pc, frame.base, frame.local);
+                }
+            }
+        }
+    }
+}
+
+fn top(stack: &mut [f64], f: impl Fn(f64) -> f64) {
+    let x = stack.last_mut().unwrap();
+    *x = f(*x);
+}
+
+fn key(args: &[f64]) -> Vec<u64> {
+    args.iter().map(|x| x.to_bits()).collect()
+}
+
+// the body of a function comes from another input, so like `Env::run` an
+// error in it points at the outermost call
+fn at_call(e: CalcError, frames: &[Frame]) -> CalcError {
+    match frames.first() {
+        Some(frame) => e.with_span(frame.span),
+        None => e,
+    }
+}
+
+/// Compile `expr`, the names in `vars` are the variables given to `eval`,
+/// the other names are taken from `env`.
+pub(crate) fn compile(env: &Env, expr: &Expr, vars: &[&str]) -> CalcResult<CompiledExpr> {
+    let mut compiler = Compiler::new(env, vars);
+    let mut ops = Vec::new();
+    compiler.expr(expr, &mut ops)?;
+    Ok(compiler.finish(ops))
+}
+
+/// Compile a call of the user function `idx`, its args are the variables,
+/// for plotting and the solvers.
+pub(crate) fn compile_fn(
+    env: &Env,
+    idx: u64,
+    f: &Function,
+    span: Span,
+) -> CalcResult<CompiledExpr> {
+    let vars: Vec<String> = (0..f.arity).map(|i| format!("x{}", i)).collect();
+    let vars: Vec<&str> = vars.iter().map(|x| x.as_str()).collect();
+    let mut compiler = Compiler::new(env, &vars);
+    // the args are given by position, so a global named like them is itself
+    compiler.slots.clear();
+    let chunk = compiler.function(idx, f).map_err(|e| e.with_span(span))?;
+    let mut ops: Vec<Op> = (0..f.arity).map(Op::Var).collect();
+    ops.push(Op::Call { chunk, span });
+    Ok(compiler.finish(ops))
+}
+
+struct Compiler<'a> {
+    env: &'a Env,
+    vars: Vec<String>,
+    slots: HashMap<u64, usize>,
+    // the args of the chunk being compiled, the names of its lets are the
+    // args after them
+    arity: usize,
+    chunks: Vec<Chunk>,
+    // the chunks of the user functions already compiled
+    compiled: HashMap<u64, usize>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(env: &'a Env, vars: &[&str]) -> Self {
+        let slots = vars
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (hash_it(*name), i))
+            .collect();
+        Compiler {
+            env,
+            vars: vars.iter().map(|x| x.to_string()).collect(),
+            slots,
+            arity: 0,
+            // chunk 0 is filled by `finish`
+            chunks: vec![Chunk {
+                ops: Vec::new(),
+                arity: 0,
+                name: None,
+                memo: None,
+            }],
+            compiled: HashMap::new(),
+        }
+    }
+
+    fn finish(mut self, mut ops: Vec<Op>) -> CompiledExpr {
+        ops.push(Op::Return);
+        self.chunks[0].ops = ops;
+        CompiledExpr {
+            vars: self.vars,
+            chunks: self.chunks,
+            max_depth: self.env.max_depth,
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, ops: &mut Vec<Op>) -> CalcResult<()> {
+        match expr {
+            Expr::Literal { value, span } => {
+                let op = match value {
+                    Valuable::Value(v, _) => Op::Const(*v),
+                    Valuable::Imag(_) => return Err(complex_error(*span)),
+                    Valuable::Quantity(..) => return Err(unit_error(*span)),
+                    Valuable::Arg(i) if *i < self.arity => Op::Arg(*i),
+                    Valuable::Arg(i) => Op::Local(*i - self.arity),
+                    Valuable::Var(idx) => self.var(*idx, *span),
+                };
+                ops.push(op);
+            }
+            Expr::Group { body, .. } => self.expr(body, ops)?,
+            Expr::Unary { op, operand, span } => {
+                self.expr(operand, ops)?;
+                ops.push(match op {
+                    UnaryOp::Minus => Op::Neg,
+                    UnaryOp::Not => Op::Not,
+                    UnaryOp::Ftl => Op::Fact(*span),
+                });
+            }
+            Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            } => match op {
+                // a && b is a ? truth(b) : 0, a || b is a ? 1 : truth(b)
+                BinaryOp::And | BinaryOp::Or => {
+                    self.expr(left, ops)?;
+                    let skip = emit_jump(ops, Op::JumpIfFalse(0));
+                    if matches!(op, BinaryOp::And) {
+                        self.expr(right, ops)?;
+                        ops.push(Op::Truth);
+                    } else {
+                        ops.push(Op::Const(1.0));
+                    }
+                    let end = emit_jump(ops, Op::Jump(0));
+                    patch(ops, skip);
+                    if matches!(op, BinaryOp::And) {
+                        ops.push(Op::Const(0.0));
+                    } else {
+                        self.expr(right, ops)?;
+                        ops.push(Op::Truth);
+                    }
+                    patch(ops, end);
+                }
+                BinaryOp::Range => return Err(list_error(*span)),
+                BinaryOp::MatMul => return Err(matrix_error(*span)),
+                _ => {
+                    self.expr(left, ops)?;
+                    self.expr(right, ops)?;
+                    ops.push(Op::Binary(binary_fn(op)));
+                }
+            },
+            Expr::Cond {
+                cond, then, other, ..
+            } => {
+                self.expr(cond, ops)?;
+                let skip = emit_jump(ops, Op::JumpIfFalse(0));
+                self.expr(then, ops)?;
+                let end = emit_jump(ops, Op::Jump(0));
+                patch(ops, skip);
+                self.expr(other, ops)?;
+                patch(ops, end);
+            }
+            Expr::Call { idx, args, span } => {
+                for arg in args {
+                    self.expr(arg, ops)?;
+                }
+                ops.push(self.call(*idx, args.len(), *span)?);
+            }
+            Expr::List { span, .. } => return Err(list_error(*span)),
+            Expr::Convert { span, .. } => return Err(unit_error(*span)),
+            Expr::Lambda { span, .. } => return Err(lambda_error(*span)),
+            Expr::Let { bindings, body, .. } => {
+                for (_, value) in bindings {
+                    self.expr(value, ops)?;
+                    ops.push(Op::Bind);
+                }
+                self.expr(body, ops)?;
+                ops.push(Op::Unbind(bindings.len()));
+            }
+        }
+        Ok(())
+    }
+
+    fn var(&self, idx: u64, span: Span) -> Op {
+        if let Some(slot) = self.slots.get(&idx) {
+            return Op::Var(*slot);
+        }
+        let name = self.env.find_name(idx);
+        let e = match self.env.var(idx) {
+            Some(v) => match v.one() {
+                Some(v) => return Op::Const(v),
+                None => CalcError::new(
+                    ErrorKind::Domain,
+                    format!(
+                        "compiled expressions only work on real numbers, but '{}' is not",
+                        name.unwrap_or("Unknown")
+                    ),
+                ),
+            },
+            None if name.and_then(units::lookup).is_some() => unit_error(span),
+            None => CalcError::new(
+                ErrorKind::UnknownName,
+                format!("can't find variable named '{}'", name.unwrap_or("Unknown")),
+            ),
+        };
+        Op::Fail(Box::new(e.with_name(name).with_span(span)))
+    }
+
+    fn call(&mut self, idx: u64, argc: usize, span: Span) -> CalcResult<Op> {
+        let env = self.env;
+        if let Some(f) = env.function(idx).map_err(|e| e.with_span(span))? {
+            if argc != f.arity {
+                let e = arity_error(env, idx, &f.arity, argc, span);
+                return Ok(Op::Fail(Box::new(e)));
+            }
+            let chunk = self.function(idx, &f).map_err(|e| e.with_span(span))?;
+            Ok(Op::Call { chunk, span })
+        } else if let Some(f) = env.builtin.get(&idx) {
+            let (min, max) = match f.arity {
+                Arity::Exact(n) => (n, n),
+                Arity::Between(min, max) => (min, max),
+                Arity::Variadic => (0, usize::MAX),
+            };
+            if argc < min || argc > max {
+                let expect = if min == max {
+                    min.to_string()
+                } else {
+                    format!("{} to {}", min, max)
+                };
+                let e = arity_error(env, idx, &expect, argc, span);
+                return Ok(Op::Fail(Box::new(e)));
+            }
+            Ok(Op::Native {
+                f: f.clone(),
+                argc,
+                name: env.find_name(idx).map(|x| x.to_owned()),
+                span,
+            })
+        } else if env.higher.contains_key(&idx) {
+            let name = env.find_name(idx);
+            Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "{} is not supported in compiled expressions",
+                    name.unwrap_or("Unknown")
+                ),
+            )
+            .with_name(name)
+            .with_span(span))
+        } else {
+            let name = env.find_name(idx);
+            let e = CalcError::new(
+                ErrorKind::UnknownName,
+                format!("function {} is not defined", name.unwrap_or("Unknown")),
+            )
+            .with_name(name)
+            .with_span(span);
+            Ok(Op::Fail(Box::new(e)))
+        }
+    }
+
+    // every function is compiled once, even if it calls itself
+    fn function(&mut self, idx: u64, f: &Function) -> CalcResult<usize> {
+        if let Some(chunk) = self.compiled.get(&idx) {
+            return Ok(*chunk);
+        }
+        let chunk = self.chunks.len();
+        self.chunks.push(Chunk {
+            ops: Vec::new(),
+            arity: f.arity,
+            name: self.env.find_name(idx).map(|x| x.to_owned()),
+            memo: f.memo.as_ref().map(|_| RefCell::new(HashMap::new())),
+        });
+        self.compiled.insert(idx, chunk);
+        let mut ops = Vec::new();
+        let outer = std::mem::replace(&mut self.arity, f.arity);
+        let body = self.expr(&f.body, &mut ops);
+        self.arity = outer;
+        body?;
+        ops.push(Op::Return);
+        self.chunks[chunk].ops = ops;
+        Ok(chunk)
+    }
+}
+
+// push a jump to be patched, give back where it is
+fn emit_jump(ops: &mut Vec<Op>, op: Op) -> usize {
+    ops.push(op);
+    ops.len() - 1
+}
+
+// make the jump at `at` go to the next op
+fn patch(ops: &mut [Op], at: usize) {
+    let to = ops.len();
+    match &mut ops[at] {
+        Op::Jump(x) | Op::JumpIfFalse(x) => *x = to,
+        _ => unreachable!(),
+    }
+}
+
+#[cold]
+fn complex_error(span: Span) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        "complex numbers are not supported in compiled expressions",
+    )
+    .with_span(span)
+}
+
+#[cold]
+fn list_error(span: Span) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        "lists are not supported in compiled expressions",
+    )
+    .with_span(span)
+}
+
+#[cold]
+fn matrix_error(span: Span) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        "matrices are not supported in compiled expressions",
+    )
+    .with_span(span)
+}
+
+#[cold]
+fn lambda_error(span: Span) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        "functions like x -> x^2 are not supported in compiled expressions",
+    )
+    .with_span(span)
+}
+
+#[cold]
+fn unit_error(span: Span) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        "units are not supported in compiled expressions",
+    )
+    .with_span(span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onemore::OneMore;
+
+    #[test]
+    fn test_compile() {
+        let mut env = Env::new();
+        env.run(
+            "k = 3; fun f(a b) = a * k + b; memo fun fib(n) = n < 2 ? n : fib(n - 1) + fib(n - 2)",
+        )
+        .unwrap();
+        env.run("fun fact(n) = n <= 1 ? 1 : n * fact(n - 1)")
+            .unwrap();
+        env.run("fun w(n) = { s = n * 2; n > 3 ? s : s + w(n + 1) }")
+            .unwrap();
+        // the same value as the tree-walking `Env::run` on real numbers
+        for s in [
+            "1 + 2 * 3 - -4 / 2^2",
+            "f(x, 1) + sin(x) + round(PI, 2)",
+            "x > 1 && x < 3 || !x",
+            "x < 0 ? 1 : 0 ? 2 : 3!",
+            "fact(x + 3) + fib(30) + max(x, 4, 2) + atan2(x, 1)",
+            "let a = x + 1, b = f(a, x) in a * { c = w(b); c * fact(a) } + a",
+        ] {
+            let c = env.compile(s, &["x"]).unwrap();
+            env.set_var("x", 2.0);
+            assert_eq!(
+                Some(OneMore::One(c.eval(&[2.0]).unwrap())),
+                env.run(s).unwrap()
+            );
+            env.remove_var("x");
+        }
+        // a complex value is NaN
+        for s in ["sqrt(x)", "(x - 10)^(1/3)"] {
+            let c = env.compile(s, &["x"]).unwrap();
+            assert!(c.eval(&[-2.0]).unwrap().is_nan(), "{}", s);
+        }
+
+        let c = env.compile("x * y + k", &["x", "y"]).unwrap();
+        assert_eq!(c.vars(), ["x", "y"]);
+        assert_eq!(
+            c.eval_map(&HashMap::from([("x", 2.0), ("y", 5.0)])),
+            Ok(13.0)
+        );
+        assert_eq!(c.eval(&[1.0]).unwrap_err().kind(), ErrorKind::Arity);
+        // globals are taken when compiling
+        env.run("k = 100").unwrap();
+        assert_eq!(c.eval(&[2.0, 5.0]), Ok(13.0));
+
+        // a plotted function is compiled with its args by position, a global
+        // doesn't take the place of one
+        env.run("x0 = 7; x = 9; fun p(x) = x + x0").unwrap();
+        let p = env.function(hash_it("p")).unwrap().unwrap();
+        let c = compile_fn(&env, hash_it("p"), &p, Span::default()).unwrap();
+        assert_eq!(c.eval(&[1.0]), Ok(8.0));
+    }
+
+    #[test]
+    fn test_compile_error() {
+        let mut env = Env::new();
+        env.run("fun g(x) = x > 0 ? x : undefined; fun loop(x) = loop(x)")
+            .unwrap();
+        let c = env.compile("1 + g(x)", &["x"]).unwrap();
+        assert_eq!(c.eval(&[1.0]), Ok(2.0));
+        let err = c.eval(&[-1.0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownName);
+        assert_eq!(err.name(), Some("undefined"));
+        assert_eq!(err.span(), Some(Span::new(4, 8)));
+
+        env.set_max_depth(50);
+        let c = env.compile("loop(1)", &[]).unwrap();
+        assert_eq!(c.eval(&[]).unwrap_err().kind(), ErrorKind::Recursion);
+
+        let c = env.compile("sqrt(x) + (-x)!", &["x"]).unwrap();
+        let err = c.eval(&[2.0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        assert_eq!(err.span(), Some(Span::new(10, 15)));
+        let c = env.compile("sin(1, 2)", &[]).unwrap();
+        assert_eq!(c.eval(&[]).unwrap_err().kind(), ErrorKind::Arity);
+
+        let err = env.compile("sum(1..x)", &["x"]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        assert_eq!(err.span(), Some(Span::new(4, 8)));
+        assert_eq!(
+            env.compile("x = 1", &[]).err().unwrap().kind(),
+            ErrorKind::Parse
+        );
+    }
+}
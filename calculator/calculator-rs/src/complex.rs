@@ -0,0 +1,104 @@
+// Complex numbers, made by the imaginary unit `i` or literals like `4i`.
+use num_complex::Complex64;
+
+use crate::{
+    ast::BinaryOp,
+    env::boolean,
+    error::{CalcError, CalcResult, ErrorKind},
+    onemore::OneMore,
+};
+
+/// A single number as a complex one, `None` for lists.
+pub(crate) fn to_complex(v: &OneMore) -> Option<Complex64> {
+    match v {
+        OneMore::Complex(z) => Some(*z),
+        _ => v.one().map(|x| Complex64::new(x, 0.0)),
+    }
+}
+
+/// A complex number without imaginary part is a real one.
+pub(crate) fn normalize(z: Complex64) -> OneMore {
+    if z.im == 0.0 {
+        OneMore::One(z.re)
+    } else {
+        OneMore::Complex(z)
+    }
+}
+
+/// `None` if neither side is complex, then the caller goes on with reals.
+pub(crate) fn binary(op: &BinaryOp, a: &OneMore, b: &OneMore) -> CalcResult<Option<OneMore>> {
+    // a negative number to a fractional power is complex, like `sqrt(-1)`
+    if let (BinaryOp::Square, Some(x), Some(y)) = (op, a.one(), b.one()) {
+        if x < 0.0 && y.is_finite() && y.fract() != 0.0 {
+            return Ok(Some(normalize(negative_pow(x, y))));
+        }
+    }
+    if !matches!(a, OneMore::Complex(_)) && !matches!(b, OneMore::Complex(_)) {
+        return Ok(None);
+    }
+    let (a, b) = match (to_complex(a), to_complex(b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                "lists of complex numbers are not supported",
+            ))
+        }
+    };
+    let v = match op {
+        BinaryOp::Plus => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mult => a * b,
+        BinaryOp::Div => a / b,
+        BinaryOp::Square => pow(a, b),
+        BinaryOp::Eq => return Ok(Some(OneMore::One(boolean(a == b)))),
+        BinaryOp::NotEq => return Ok(Some(OneMore::One(boolean(a != b)))),
+        _ => {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                "complex numbers can't be compared",
+            ))
+        }
+    };
+    Ok(Some(normalize(v)))
+}
+
+// (-r)^y = r^y (cos(pi y) + i sin(pi y)), a half power is on the imaginary
+// axis exactly, so `(-1)^0.5` is `1i` like `sqrt(-1)`
+fn negative_pow(x: f64, y: f64) -> Complex64 {
+    let r = (-x).powf(y);
+    if (2.0 * y).fract() == 0.0 {
+        let sign = if (y - 0.5).rem_euclid(2.0) == 0.0 {
+            1.0
+        } else {
+            -1.0
+        };
+        Complex64::new(0.0, sign * r)
+    } else {
+        Complex64::from_polar(r, std::f64::consts::PI * y)
+    }
+}
+
+// an integer power by multiplying, so `i^2` is exactly -1
+fn pow(a: Complex64, b: Complex64) -> Complex64 {
+    if b.im == 0.0 && b.re.fract() == 0.0 && b.re.abs() <= i32::MAX as f64 {
+        a.powi(b.re as i32)
+    } else if a == Complex64::new(0.0, 0.0) {
+        Complex64::new(0.0, 0.0)
+    } else {
+        a.powc(b)
+    }
+}
+
+/// `3+4i`, or `5∠0.9272952180016122` (the angle in radians) for polar,
+/// with every real number shown by `real`.
+pub(crate) fn show(z: &Complex64, polar: bool, real: impl Fn(f64) -> String) -> String {
+    if polar {
+        return format!("{}∠{}", real(z.norm()), real(z.arg()));
+    }
+    if z.re == 0.0 {
+        return format!("{}i", real(z.im));
+    }
+    let sign = if z.im.is_sign_negative() { '-' } else { '+' };
+    format!("{}{}{}i", real(z.re), sign, real(z.im.abs()))
+}
@@ -0,0 +1,760 @@
+// Symbolic derivatives of user functions, for `diff(f)`.
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, Zero};
+
+use crate::{
+    ast::{BinaryOp, Expr, UnaryOp, Valuable},
+    env::{Env, Function},
+    error::{CalcError, CalcResult, ErrorKind, Span},
+    exact,
+    utils::hash_it,
+};
+
+/// The derivative of the user function `f` by its arg `var`, simplified.
+pub(crate) fn derive(env: &Env, f: &Function, var: usize) -> CalcResult<Expr> {
+    // a lambda can't be inlined, its params would clash with the lets
+    if let Some(span) = find_lambda(&f.body) {
+        return Err(not_differentiable("'->'", span));
+    }
+    let mut d = Deriver {
+        env,
+        inlining: Vec::new(),
+    };
+    Ok(simplify(d.derive(&inline_lets(f), var)?))
+}
+
+struct Deriver<'a> {
+    env: &'a Env,
+    // the user functions whose bodies are being derived, to stop recursion
+    inlining: Vec<u64>,
+}
+
+impl Deriver<'_> {
+    fn derive(&mut self, e: &Expr, var: usize) -> CalcResult<Expr> {
+        if !depends(e, var) {
+            return Ok(int(0));
+        }
+        let span = e.span();
+        let d = match e {
+            // the only leaf which depends on the arg is the arg itself
+            Expr::Literal { .. } => int(1),
+            Expr::Group { body, .. } => self.derive(body, var)?,
+            Expr::Unary {
+                op: UnaryOp::Minus,
+                operand,
+                ..
+            } => neg(self.derive(operand, var)?),
+            Expr::Unary { op, .. } => {
+                let what = match op {
+                    UnaryOp::Ftl => "'!'",
+                    _ => "'!x'",
+                };
+                return Err(not_differentiable(what, span));
+            }
+            Expr::Binary {
+                left, op, right, ..
+            } => self.binary(left, op, right, var, span)?,
+            // only the branches are derived, the condition stays as it is
+            Expr::Cond {
+                cond, then, other, ..
+            } => Expr::Cond {
+                cond: cond.clone(),
+                then: Box::new(self.derive(then, var)?),
+                other: Box::new(self.derive(other, var)?),
+                span,
+            },
+            Expr::Call { idx, args, .. } => self.call(*idx, args, var, span)?,
+            Expr::List { .. } => return Err(not_differentiable("a list", span)),
+            Expr::Convert { .. } => return Err(not_differentiable("'in'", span)),
+            Expr::Lambda { .. } => return Err(not_differentiable("'->'", span)),
+            Expr::Let { .. } => unreachable!("the lets are inlined before deriving"),
+        };
+        Ok(d)
+    }
+
+    fn binary(
+        &mut self,
+        u: &Expr,
+        op: &BinaryOp,
+        v: &Expr,
+        var: usize,
+        span: Span,
+    ) -> CalcResult<Expr> {
+        let (du, dv) = (self.derive(u, var)?, self.derive(v, var)?);
+        let (u, v) = (u.clone(), v.clone());
+        let d = match op {
+            BinaryOp::Plus | BinaryOp::Sub => bin(du, op.clone(), dv),
+            // (uv)' = u'v + uv'
+            BinaryOp::Mult => add(mul(du, v), mul(u, dv)),
+            BinaryOp::Div if !depends(&v, var) => div(du, v),
+            // (u/v)' = (u'v - uv') / v^2
+            BinaryOp::Div => div(sub(mul(du, v.clone()), mul(u, dv)), pow(v, int(2))),
+            // (u^n)' = n u^(n-1) u'
+            BinaryOp::Square if !depends(&v, var) => {
+                mul(mul(v.clone(), pow(u, sub(v, int(1)))), du)
+            }
+            // (a^v)' = a^v ln(a) v'
+            BinaryOp::Square if !depends(&u, var) => {
+                mul(mul(pow(u.clone(), v), call(self.env, "ln", vec![u])), dv)
+            }
+            // (u^v)' = u^v (v' ln(u) + v u' / u)
+            BinaryOp::Square => mul(
+                pow(u.clone(), v.clone()),
+                add(
+                    mul(dv, call(self.env, "ln", vec![u.clone()])),
+                    div(mul(v, du), u),
+                ),
+            ),
+            // comparisons are flat where they are defined
+            BinaryOp::Less
+            | BinaryOp::LessEq
+            | BinaryOp::Eq
+            | BinaryOp::NotEq
+            | BinaryOp::Greater
+            | BinaryOp::GreaterEq
+            | BinaryOp::And
+            | BinaryOp::Or => int(0),
+            BinaryOp::Range => return Err(not_differentiable("a range", span)),
+            BinaryOp::MatMul => return Err(not_differentiable("'@'", span)),
+        };
+        Ok(d)
+    }
+
+    fn call(&mut self, idx: u64, args: &[Expr], var: usize, span: Span) -> CalcResult<Expr> {
+        let env = self.env;
+        let name = env.find_name(idx).unwrap_or("Unknown");
+        if let Some(f) = env.function(idx).map_err(|e| e.with_span(span))? {
+            if f.arity != args.len() {
+                return Err(crate::env::arity_error(
+                    env,
+                    idx,
+                    &f.arity,
+                    args.len(),
+                    span,
+                ));
+            }
+            if self.inlining.contains(&idx) {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!("can't differentiate the recursive function {}", name),
+                )
+                .with_name(Some(name))
+                .with_span(span));
+            }
+            if find_lambda(&f.body).is_some() {
+                let what = format!("{}, which uses '->'", name);
+                return Err(not_differentiable(&what, span).with_name(Some(name)));
+            }
+            // the chain rule, f(a, b)' = f_a(a, b) a' + f_b(a, b) b'
+            self.inlining.push(idx);
+            let mut d = int(0);
+            for (k, arg) in args.iter().enumerate() {
+                if depends(arg, var) {
+                    let partial = substitute(&self.derive(&inline_lets(&f), k)?, args);
+                    d = add(d, mul(partial, self.derive(arg, var)?));
+                }
+            }
+            self.inlining.pop();
+            return Ok(d);
+        }
+        if env.higher.contains_key(&idx) {
+            return Err(not_differentiable(name, span).with_name(Some(name)));
+        }
+        if !env.builtin.contains_key(&idx) {
+            return Err(CalcError::new(
+                ErrorKind::UnknownName,
+                format!("function {} is not defined", name),
+            )
+            .with_name(Some(name))
+            .with_span(span));
+        }
+
+        let c = |name: &str, u: &Expr| call(env, name, vec![u.clone()]);
+        let d = match (name, args) {
+            (_, [u]) => {
+                let outer = match name {
+                    "sin" => c("cos", u),
+                    "cos" => neg(c("sin", u)),
+                    "tan" => div(int(1), pow(c("cos", u), int(2))),
+                    "exp" => c("exp", u),
+                    "ln" => div(int(1), u.clone()),
+                    "lg" => div(int(1), mul(u.clone(), c("ln", &int(10)))),
+                    "sqrt" => div(int(1), mul(int(2), c("sqrt", u))),
+                    "asin" => div(int(1), c("sqrt", &sub(int(1), pow(u.clone(), int(2))))),
+                    "acos" => neg(div(int(1), c("sqrt", &sub(int(1), pow(u.clone(), int(2)))))),
+                    "atan" => div(int(1), add(int(1), pow(u.clone(), int(2)))),
+                    "sinh" => c("cosh", u),
+                    "cosh" => c("sinh", u),
+                    "abs" => div(u.clone(), c("abs", u)),
+                    "to_rad" => c("to_rad", &int(1)),
+                    "floor" | "round" => int(0),
+                    "normcdf" => c("normpdf", u),
+                    // 2 / sqrt(PI) exp(-u^2) as a normal density
+                    "erf" => mul(
+                        mul(int(2), c("sqrt", &int(2))),
+                        c("normpdf", &mul(c("sqrt", &int(2)), u.clone())),
+                    ),
+                    _ => return Err(not_differentiable(name, span).with_name(Some(name))),
+                };
+                mul(outer, self.derive(u, var)?)
+            }
+            ("round", [_, digits]) if !depends(digits, var) => int(0),
+            ("pow", [u, v]) => self.binary(u, &BinaryOp::Square, v, var, span)?,
+            // log(b, x) = ln(x) / ln(b)
+            ("log", [b, x]) => self.derive(&div(c("ln", x), c("ln", b)), var)?,
+            // hypot(a, b)' = (a a' + b b') / hypot(a, b)
+            ("hypot", [a, b]) => div(
+                add(
+                    mul(a.clone(), self.derive(a, var)?),
+                    mul(b.clone(), self.derive(b, var)?),
+                ),
+                call(env, "hypot", args.to_vec()),
+            ),
+            // atan2(y, x)' = (x y' - y x') / (x^2 + y^2)
+            ("atan2", [y, x]) => div(
+                sub(
+                    mul(x.clone(), self.derive(y, var)?),
+                    mul(y.clone(), self.derive(x, var)?),
+                ),
+                add(pow(x.clone(), int(2)), pow(y.clone(), int(2))),
+            ),
+            // mod(a, b) = a - b floor(a / b)
+            ("mod", [a, b]) => sub(
+                self.derive(a, var)?,
+                mul(self.derive(b, var)?, c("floor", &div(a.clone(), b.clone()))),
+            ),
+            _ => return Err(not_differentiable(name, span).with_name(Some(name))),
+        };
+        Ok(d)
+    }
+}
+
+#[cold]
+fn not_differentiable(what: &str, span: Span) -> CalcError {
+    CalcError::new(ErrorKind::Domain, format!("can't differentiate {}", what)).with_span(span)
+}
+
+// if the arg `var` is used in `e`, the globals are constants
+fn depends(e: &Expr, var: usize) -> bool {
+    match e {
+        Expr::Literal { value, .. } => matches!(value, Valuable::Arg(i) if *i == var),
+        Expr::Group { body, .. } => depends(body, var),
+        Expr::Unary { operand, .. } => depends(operand, var),
+        Expr::Binary { left, right, .. } => depends(left, var) || depends(right, var),
+        Expr::Cond {
+            cond, then, other, ..
+        } => depends(cond, var) || depends(then, var) || depends(other, var),
+        Expr::Call { args, .. } => args.iter().any(|e| depends(e, var)),
+        Expr::List { items, .. } => items.iter().any(|e| depends(e, var)),
+        Expr::Convert { expr, .. } => depends(expr, var),
+        Expr::Lambda { body, .. } => depends(body, var),
+        Expr::Let { bindings, body, .. } => {
+            bindings.iter().any(|(_, e)| depends(e, var)) || depends(body, var)
+        }
+    }
+}
+
+// where '->' is used in `e`, if it is
+fn find_lambda(e: &Expr) -> Option<Span> {
+    match e {
+        Expr::Literal { .. } => None,
+        Expr::Group { body, .. } => find_lambda(body),
+        Expr::Unary { operand, .. } => find_lambda(operand),
+        Expr::Binary { left, right, .. } => find_lambda(left).or_else(|| find_lambda(right)),
+        Expr::Cond {
+            cond, then, other, ..
+        } => find_lambda(cond)
+            .or_else(|| find_lambda(then))
+            .or_else(|| find_lambda(other)),
+        Expr::Call { args, .. } => args.iter().find_map(find_lambda),
+        Expr::List { items, .. } => items.iter().find_map(find_lambda),
+        Expr::Convert { expr, .. } => find_lambda(expr),
+        Expr::Lambda { span, .. } => Some(*span),
+        Expr::Let { bindings, body, .. } => bindings
+            .iter()
+            .find_map(|(_, e)| find_lambda(e))
+            .or_else(|| find_lambda(body)),
+    }
+}
+
+// the body of `f` with the values of its lets in place of their names
+fn inline_lets(f: &Function) -> Expr {
+    let args: Vec<Expr> = (0..f.arity)
+        .map(|i| Expr::Literal {
+            value: Valuable::Arg(i),
+            span: Span::default(),
+        })
+        .collect();
+    substitute(&f.body, &args)
+}
+
+// put `args` in place of the args of a function body
+fn substitute(e: &Expr, args: &[Expr]) -> Expr {
+    let sub = |e: &Expr| Box::new(substitute(e, args));
+    match e {
+        Expr::Literal {
+            value: Valuable::Arg(i),
+            ..
+        } => args[*i].clone(),
+        Expr::Literal { .. } => e.clone(),
+        Expr::Group { body, span } => Expr::Group {
+            body: sub(body),
+            span: *span,
+        },
+        Expr::Unary { op, operand, span } => Expr::Unary {
+            op: op.clone(),
+            operand: sub(operand),
+            span: *span,
+        },
+        Expr::Binary {
+            left,
+            op,
+            right,
+            span,
+        } => Expr::Binary {
+            left: sub(left),
+            op: op.clone(),
+            right: sub(right),
+            span: *span,
+        },
+        Expr::Cond {
+            cond,
+            then,
+            other,
+            span,
+        } => Expr::Cond {
+            cond: sub(cond),
+            then: sub(then),
+            other: sub(other),
+            span: *span,
+        },
+        Expr::Call { idx, args: a, span } => Expr::Call {
+            idx: *idx,
+            args: a.iter().map(|e| substitute(e, args)).collect(),
+            span: *span,
+        },
+        Expr::List { items, span } => Expr::List {
+            items: items.iter().map(|e| substitute(e, args)).collect(),
+            span: *span,
+        },
+        Expr::Convert { expr, unit, span } => Expr::Convert {
+            expr: sub(expr),
+            unit: unit.clone(),
+            span: *span,
+        },
+        // its params would have to be moved after the args of the caller
+        Expr::Lambda { .. } => unreachable!("a body with '->' is never inlined"),
+        // its names are the args after `args`, so it is gone with them
+        Expr::Let { bindings, body, .. } => {
+            let mut scope = args.to_vec();
+            for (_, value) in bindings {
+                let v = substitute(value, &scope);
+                scope.push(v);
+            }
+            substitute(body, &scope)
+        }
+    }
+}
+
+// the expressions made here point at nothing, errors in a derived function
+// are pointed at its call anyway
+
+fn number(v: BigRational) -> Expr {
+    Expr::Literal {
+        value: Valuable::Value(exact::to_f64(&v), Box::new(v)),
+        span: Span::default(),
+    }
+}
+
+fn int(n: i64) -> Expr {
+    number(BigRational::from_integer(BigInt::from(n)))
+}
+
+fn bin(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+    Expr::Binary {
+        left: Box::new(left),
+        op,
+        right: Box::new(right),
+        span: Span::default(),
+    }
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    bin(a, BinaryOp::Plus, b)
+}
+
+fn sub(a: Expr, b: Expr) -> Expr {
+    bin(a, BinaryOp::Sub, b)
+}
+
+fn mul(a: Expr, b: Expr) -> Expr {
+    bin(a, BinaryOp::Mult, b)
+}
+
+fn div(a: Expr, b: Expr) -> Expr {
+    bin(a, BinaryOp::Div, b)
+}
+
+fn pow(a: Expr, b: Expr) -> Expr {
+    bin(a, BinaryOp::Square, b)
+}
+
+fn neg(a: Expr) -> Expr {
+    Expr::Unary {
+        op: UnaryOp::Minus,
+        operand: Box::new(a),
+        span: Span::default(),
+    }
+}
+
+// a builtin, the env knows its name since it is interned by `Env::new`
+fn call(env: &Env, name: &str, args: Vec<Expr>) -> Expr {
+    debug_assert!(env.find_name(hash_it(name)).is_some());
+    Expr::Call {
+        idx: hash_it(name),
+        args,
+        span: Span::default(),
+    }
+}
+
+fn value(e: &Expr) -> Option<&BigRational> {
+    match e {
+        Expr::Literal {
+            value: Valuable::Value(_, v),
+            ..
+        } => Some(v),
+        _ => None,
+    }
+}
+
+fn is(e: &Expr, n: i64) -> bool {
+    value(e).is_some_and(|v| *v == BigRational::from_integer(BigInt::from(n)))
+}
+
+/// Fold the numbers and drop the trivial parts like `x * 1` and `x + 0`.
+pub(crate) fn simplify(e: Expr) -> Expr {
+    match e {
+        Expr::Group { body, .. } => simplify(*body),
+        Expr::Unary {
+            op: UnaryOp::Minus,
+            operand,
+            span,
+        } => match simplify(*operand) {
+            Expr::Unary {
+                op: UnaryOp::Minus,
+                operand,
+                ..
+            } => *operand,
+            e => match value(&e) {
+                Some(v) => number(-v),
+                None => Expr::Unary {
+                    op: UnaryOp::Minus,
+                    operand: Box::new(e),
+                    span,
+                },
+            },
+        },
+        Expr::Binary {
+            left,
+            op,
+            right,
+            span,
+        } => {
+            let (l, r) = (simplify(*left), simplify(*right));
+            if let (Some(a), Some(b)) = (value(&l), value(&r)) {
+                if let Ok(Some(v)) = exact::binary(&op, a, b) {
+                    return number(v);
+                }
+            }
+            simplify_binary(l, op, r, span)
+        }
+        Expr::Cond {
+            cond,
+            then,
+            other,
+            span,
+        } => {
+            let cond = simplify(*cond);
+            let (then, other) = (simplify(*then), simplify(*other));
+            match value(&cond) {
+                Some(v) if v.is_zero() => other,
+                Some(_) => then,
+                None if same(&then, &other) => then,
+                None => Expr::Cond {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    other: Box::new(other),
+                    span,
+                },
+            }
+        }
+        Expr::Call { idx, args, span } => Expr::Call {
+            idx,
+            args: args.into_iter().map(simplify).collect(),
+            span,
+        },
+        e => e,
+    }
+}
+
+fn simplify_binary(l: Expr, op: BinaryOp, r: Expr, span: Span) -> Expr {
+    let unary_minus = |e: &Expr| match e {
+        Expr::Unary {
+            op: UnaryOp::Minus,
+            operand,
+            ..
+        } => Some((**operand).clone()),
+        _ => None,
+    };
+    let minus = |e: &Expr| {
+        unary_minus(e).or_else(|| value(e).filter(|v| v.is_negative()).map(|v| number(-v)))
+    };
+    match op {
+        BinaryOp::Plus if is(&l, 0) => r,
+        BinaryOp::Plus | BinaryOp::Sub if is(&r, 0) => l,
+        BinaryOp::Sub if is(&l, 0) => simplify(neg(r)),
+        BinaryOp::Sub if same(&l, &r) => int(0),
+        // 'x + -y' is 'x - y'
+        BinaryOp::Plus | BinaryOp::Sub if minus(&r).is_some() => {
+            let op = match op {
+                BinaryOp::Plus => BinaryOp::Sub,
+                _ => BinaryOp::Plus,
+            };
+            bin(l, op, minus(&r).unwrap())
+        }
+        BinaryOp::Mult if is(&l, 0) || is(&r, 0) => int(0),
+        BinaryOp::Mult if is(&l, 1) => r,
+        BinaryOp::Mult | BinaryOp::Div if is(&r, 1) => l,
+        BinaryOp::Mult if is(&l, -1) => simplify(neg(r)),
+        BinaryOp::Mult if same(&l, &r) => pow(l, int(2)),
+        // the number goes first, '2 * cos(x)' not 'cos(x) * 2'
+        BinaryOp::Mult if value(&r).is_some() => simplify(mul(r, l)),
+        BinaryOp::Mult | BinaryOp::Div if unary_minus(&l).is_some() => {
+            simplify(neg(bin(unary_minus(&l).unwrap(), op, r)))
+        }
+        BinaryOp::Mult | BinaryOp::Div if unary_minus(&r).is_some() => {
+            simplify(neg(bin(l, op, unary_minus(&r).unwrap())))
+        }
+        // 'a * (1 / b)' is 'a / b'
+        BinaryOp::Mult if reciprocal(&r).is_some() => simplify(div(l, reciprocal(&r).unwrap())),
+        BinaryOp::Mult if reciprocal(&l).is_some() => simplify(div(r, reciprocal(&l).unwrap())),
+        BinaryOp::Div if is(&l, 0) => int(0),
+        BinaryOp::Div if same(&l, &r) => int(1),
+        BinaryOp::Square if is(&r, 0) || is(&l, 1) => int(1),
+        BinaryOp::Square if is(&r, 1) => l,
+        op => {
+            // '(x + 1) - 1' is 'x'
+            if let (
+                BinaryOp::Plus | BinaryOp::Sub,
+                Some(c),
+                Expr::Binary {
+                    left: x,
+                    op: inner @ (BinaryOp::Plus | BinaryOp::Sub),
+                    right,
+                    ..
+                },
+            ) = (&op, value(&r), &l)
+            {
+                if let Some(b) = value(right) {
+                    let b = if matches!(inner, BinaryOp::Sub) {
+                        -b
+                    } else {
+                        b.clone()
+                    };
+                    let c = if matches!(op, BinaryOp::Sub) {
+                        -c
+                    } else {
+                        c.clone()
+                    };
+                    return simplify(add((**x).clone(), number(b + c)));
+                }
+            }
+            // '2 * (3 * x)' is '6 * x'
+            if let (
+                BinaryOp::Mult,
+                Some(a),
+                Expr::Binary {
+                    left,
+                    op: BinaryOp::Mult,
+                    right,
+                    ..
+                },
+            ) = (&op, value(&l), &r)
+            {
+                if let Some(b) = value(left) {
+                    return simplify(mul(number(a * b), (**right).clone()));
+                }
+            }
+            Expr::Binary {
+                left: Box::new(l),
+                op,
+                right: Box::new(r),
+                span,
+            }
+        }
+    }
+}
+
+// b of '1 / b'
+fn reciprocal(e: &Expr) -> Option<Expr> {
+    match e {
+        Expr::Binary {
+            left,
+            op: BinaryOp::Div,
+            right,
+            ..
+        } if is(left, 1) => Some((**right).clone()),
+        _ => None,
+    }
+}
+
+// the same expression, apart from where it is
+fn same(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Literal { value: a, .. }, Expr::Literal { value: b, .. }) => match (a, b) {
+            (Valuable::Value(_, a), Valuable::Value(_, b)) => a == b,
+            (Valuable::Imag(a), Valuable::Imag(b)) => a == b,
+            (Valuable::Quantity(a, u), Valuable::Quantity(b, v)) => a == b && u == v,
+            (Valuable::Arg(a), Valuable::Arg(b)) => a == b,
+            (Valuable::Var(a), Valuable::Var(b)) => a == b,
+            _ => false,
+        },
+        (Expr::Group { body: a, .. }, b) | (b, Expr::Group { body: a, .. }) => same(a, b),
+        (
+            Expr::Unary {
+                op: a, operand: x, ..
+            },
+            Expr::Unary {
+                op: b, operand: y, ..
+            },
+        ) => std::mem::discriminant(a) == std::mem::discriminant(b) && same(x, y),
+        (
+            Expr::Binary {
+                left: a,
+                op: p,
+                right: b,
+                ..
+            },
+            Expr::Binary {
+                left: x,
+                op: q,
+                right: y,
+                ..
+            },
+        ) => std::mem::discriminant(p) == std::mem::discriminant(q) && same(a, x) && same(b, y),
+        (
+            Expr::Cond {
+                cond: a,
+                then: b,
+                other: c,
+                ..
+            },
+            Expr::Cond {
+                cond: x,
+                then: y,
+                other: z,
+                ..
+            },
+        ) => same(a, x) && same(b, y) && same(c, z),
+        (
+            Expr::Call {
+                idx: a, args: x, ..
+            },
+            Expr::Call {
+                idx: b, args: y, ..
+            },
+        ) => a == b && all_same(x, y),
+        (Expr::List { items: x, .. }, Expr::List { items: y, .. }) => all_same(x, y),
+        _ => false,
+    }
+}
+
+fn all_same(a: &[Expr], b: &[Expr]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Env, ErrorKind, OneMore};
+
+    #[test]
+    fn test_diff() {
+        let mut env = Env::new();
+        env.run(
+            "fun f(x) = x^3 + sin(2 * x); fun g(x) = x * ln(x) - x
+            fun h(t) = E^(2*t) / (1 + t^2) + log(2, t) + hypot(t, 3)
+            fun k(x) = sqrt(1 - x^2) + atan(x) + 2^x + x^x - x / 3
+            fun p(x) = g(x^2) + f(x) * 3 + (x > 0.5 ? x^2 : -x)
+            fun q(x) = let u = x^2, v = u + x in u * v + g(v)
+            fun r(x) = 2 * q(x) - { s = x + 1; s^2 }",
+        )
+        .unwrap();
+        assert_eq!(
+            env.diff("f").unwrap(),
+            "fun f'(x) = 3 * x^2 + 2 * cos(2 * x)"
+        );
+        assert_eq!(
+            env.diff("f'").unwrap(),
+            "fun f''(x) = 6 * x - 4 * sin(2 * x)"
+        );
+        assert_eq!(env.diff("g").unwrap(), "fun g'(x) = ln(x)");
+        // the names of a let are put back as their values
+        env.run("fun sq(x) = { y = 3 * x; y^2 }").unwrap();
+        assert_eq!(env.diff("sq").unwrap(), "fun sq'(x) = 18 * x");
+
+        // the same as a numerical derivative, and the same when read back
+        for name in ["f", "g", "h", "k", "p", "q", "r"] {
+            let d = env.diff(name).unwrap();
+            let mut other = Env::new();
+            other.run(&env.show_fn(name).unwrap()).unwrap();
+            other.run(&d).unwrap();
+            for x in [0.3, 0.7] {
+                let at = |env: &mut Env, s: String| env.run(&s).unwrap().unwrap().one().unwrap();
+                let h = 1e-6;
+                let numeric = (at(&mut env, format!("{}({})", name, x + h))
+                    - at(&mut env, format!("{}({})", name, x - h)))
+                    / (2.0 * h);
+                let exact = at(&mut env, format!("{}'({})", name, x));
+                assert!((numeric - exact).abs() < 1e-6, "{} at {}", name, x);
+                assert_eq!(exact, at(&mut other, format!("{}'({})", name, x)));
+            }
+        }
+
+        env.run("fun fact(n) = n <= 1 ? 1 : n * fact(n - 1); fun two(a b) = a * b")
+            .unwrap();
+        env.run("fun m(x) = x!; fun l(x) = sum(x, 1)").unwrap();
+        assert_eq!(env.diff("fact").unwrap_err().kind(), ErrorKind::Domain);
+        assert_eq!(env.diff("m").unwrap_err().kind(), ErrorKind::Domain);
+        assert_eq!(env.diff("l").unwrap_err().kind(), ErrorKind::Domain);
+        // `f'` is there without `diff(f)`, and follows `f` when it changes
+        let mut env = Env::new();
+        env.run("fun f(x) = sqrt(x); fun g(x) = f'(x) * 2").unwrap();
+        assert_eq!(env.run("f'(4)").unwrap(), Some(OneMore::One(0.25)));
+        env.run("fun f(x) = x^3").unwrap();
+        assert_eq!(env.run("f'(2) + f''(1)").unwrap(), Some(OneMore::One(18.0)));
+        assert_eq!(env.run("g(1) + g'(1)").unwrap(), Some(OneMore::One(18.0)));
+        let x = env
+            .run("%root(f'', -1, 2)")
+            .unwrap()
+            .unwrap()
+            .one()
+            .unwrap();
+        assert!(x.abs() < 1e-6);
+        env.run("fun loops(x) = loops'(x)").unwrap();
+        assert_eq!(env.run("loops'(1)").unwrap_err().kind(), ErrorKind::Domain);
+        env.run("fun two(a b) = a * b").unwrap();
+        assert_eq!(env.run("two'(1)").unwrap_err().kind(), ErrorKind::Arity);
+        assert_eq!(
+            env.run("nope'(1)").unwrap_err().kind(),
+            ErrorKind::UnknownName
+        );
+
+        // a body with '->' is not inlined, it is an error and not a panic
+        env.run("fun lam(x) = map(y -> y * x, [1])").unwrap();
+        let err = env.diff("lam").unwrap_err();
+        assert_eq!(err.message(), "can't differentiate '->'");
+        assert_eq!(env.diff("two").unwrap_err().kind(), ErrorKind::Arity);
+        assert_eq!(env.diff("nope").unwrap_err().kind(), ErrorKind::UnknownName);
+    }
+}
@@ -1,119 +1,833 @@
 // todo: the impl of vm-like env is really complicated.
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
 
-use drawille::Canvas;
+use num_complex::Complex64;
+use num_traits::Zero;
 
 use crate::{
-    ast::{BinaryOp, Expr, MagicKind, Stmt, UnaryOp, Valuable},
+    ast::{BinaryOp, Expr, MagicExpr, MagicName, Stmt, UnaryOp, Valuable},
+    builtin::{builtins, Arity, NativeFun, MAX_LIST_LEN},
+    compile::{compile, compile_fn, CompiledExpr},
+    complex, diff,
+    error::{CalcError, CalcResult, ErrorKind, Span},
+    exact,
+    format::{self, Notation},
+    higher::{higher, HigherFun},
     lexer::Scanner,
+    magic::{self, Given, Magic, MagicArgs},
+    matrix::{self, Matrix},
     onemore::OneMore,
     parser::Parser,
-    utils::{factorial, hash_it, print_err},
+    show::{value_source, Show},
+    stats::factorial,
+    units::{self, quantity},
+    utils::hash_it,
 };
 
-type CalcFn = Box<dyn Fn(&[f64]) -> OneMore>;
+// a function defined by `fun`
+pub(crate) struct Function {
+    pub(crate) arity: usize,
+    // the names of the args, to show the function
+    pub(crate) params: Vec<u64>,
+    pub(crate) body: Box<Expr>,
+    // results keyed by the bits of the args, only for `memo fun`
+    pub(crate) memo: Option<RefCell<HashMap<Vec<u64>, OneMore>>>,
+}
 
-macro_rules! f64method_to_native {
-    ($name:tt) => {
-        NativeFun {
-            fun: Box::new(|arg: &[f64]| OneMore::One(f64::$name(arg[0]))),
-            arg_num: 1,
-            return_num: 1,
-        }
-    };
+/// How deep user functions can call each other by default, deep enough for
+/// normal recursion while the native stack is still far from overflow.
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+/// How numbers are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// 64-bit floats, `0.1 + 0.2` is `0.30000000000000004`.
+    #[default]
+    Float,
+    /// Number literals are exact rationals, and `+ - * /`, `^` with an
+    /// integer exponent, `!` and comparisons keep them exact, so `20!` and
+    /// `1/3 + 1/6` are exact. Other functions and lists still use floats.
+    Exact,
+}
+
+/// An expression parsed once by `Env::prepare`, to be evaluated many times.
+#[derive(Debug, Clone)]
+pub struct Prepared {
+    expr: Box<Expr>,
 }
 
-#[allow(unused)]
-struct NativeFun {
-    fun: CalcFn,
-    arg_num: usize,
-    return_num: usize,
+/// A function which can be called, as listed by `Env::functions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub arity: Arity,
+    /// `false` for the functions defined by `fun`
+    pub builtin: bool,
+}
+
+/// What a statement gives back to `Env::run_each`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    /// The value of an expression.
+    Value(OneMore),
+    /// The definition shown by `diff(f)`, like `fun f'(x) = 2 * x`.
+    Definition(String),
+    /// Text to show as it is, like the plot drawn by `%plot2d`.
+    Text(String),
+}
+
+impl From<OneMore> for Output {
+    fn from(v: OneMore) -> Self {
+        Output::Value(v)
+    }
 }
 
 pub struct Env {
-    functions: HashMap<u64, Box<Expr>>,
-    builtin: HashMap<u64, NativeFun>,
-    global: HashMap<u64, f64>,
+    pub(crate) functions: HashMap<u64, Rc<Function>>,
+    // the derivatives `f'` of the functions defined by `fun`, derived the
+    // first time they are used, dropped when any function changes
+    derived: RefCell<HashMap<u64, Rc<Function>>>,
+    // the derivatives being derived, so `fun f(x) = f'(x)` doesn't loop
+    deriving: RefCell<Vec<u64>>,
+    pub(crate) builtin: HashMap<u64, NativeFun>,
+    // the builtins taking a function, like `map`
+    pub(crate) higher: HashMap<u64, HigherFun>,
+    pub(crate) global: HashMap<u64, OneMore>,
     name_space: Option<HashMap<u64, String>>,
+    depth: Cell<usize>,
+    pub(crate) max_depth: usize,
+    mode: Mode,
+    // show complex numbers in polar form
+    polar: bool,
+    notation: Notation,
+    separators: bool,
+    magics: HashMap<u64, Rc<dyn Magic>>,
+    // the last result given by `set_ans`, read as `ans` and `_`
+    ans: Option<OneMore>,
 }
 
 impl Env {
     pub fn new() -> Self {
-        let builtin = HashMap::from([
-            (hash_it(&"ln"), f64method_to_native!(ln)),
-            (hash_it(&"lg"), f64method_to_native!(log10)),
-            (hash_it(&"sin"), f64method_to_native!(sin)),
-            (hash_it(&"cos"), f64method_to_native!(cos)),
-            (hash_it(&"tan"), f64method_to_native!(tan)),
-            (hash_it(&"acos"), f64method_to_native!(acos)),
-            (hash_it(&"asin"), f64method_to_native!(asin)),
-            (hash_it(&"atan"), f64method_to_native!(atan)),
-            (hash_it(&"sqrt"), f64method_to_native!(sqrt)),
-            (hash_it(&"abs"), f64method_to_native!(abs)),
-            (hash_it(&"sinh"), f64method_to_native!(sinh)),
-            (hash_it(&"cosh"), f64method_to_native!(cosh)),
-            (hash_it(&"floor"), f64method_to_native!(floor)),
-            (hash_it(&"to_rad"), f64method_to_native!(to_radians)),
-        ]);
-        let global = HashMap::from([
-            (hash_it(&"PI"), std::f64::consts::PI),
-            (hash_it(&"E"), std::f64::consts::E),
-        ]);
-        Env {
+        let mut env = Env {
             functions: HashMap::new(),
-            builtin,
-            global,
+            derived: RefCell::new(HashMap::new()),
+            deriving: RefCell::new(Vec::new()),
+            builtin: HashMap::new(),
+            higher: HashMap::new(),
+            global: HashMap::new(),
             name_space: None,
+            depth: Cell::new(0),
+            max_depth: DEFAULT_MAX_DEPTH,
+            mode: Mode::Float,
+            polar: false,
+            notation: Notation::Plain,
+            separators: false,
+            magics: HashMap::new(),
+            ans: None,
+        };
+        for (name, f) in builtins() {
+            let idx = env.intern(name);
+            env.builtin.insert(idx, f);
+        }
+        for (name, f) in higher() {
+            let idx = env.intern(name);
+            env.higher.insert(idx, f);
+        }
+        for (name, magic) in magic::builtins() {
+            let idx = env.intern(name);
+            env.magics.insert(idx, magic);
+        }
+        for (name, value) in constants() {
+            env.set_var(name, value);
+        }
+        env
+    }
+
+    /// Set how deep user functions can call each other, calling deeper
+    /// gives an `ErrorKind::Recursion` error instead of a stack overflow.
+    ///
+    /// Every level costs native stack, so a big limit should only be used
+    /// with a big stack.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Change how the numbers are computed from now on, also done by
+    /// `%mode(exact)` and `%mode(float)`. The values already computed are
+    /// kept as they are.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Show complex numbers as `5∠0.9272952180016122` instead of `3+4i`,
+    /// also done by `%complex(polar)` and `%complex(rect)`.
+    pub fn set_polar(&mut self, polar: bool) {
+        self.polar = polar;
+    }
+
+    /// Show the real numbers in `notation`, also done by `%format` like
+    /// `%format(fix, 2)` or `%format(hex)`.
+    pub fn set_notation(&mut self, notation: Notation) {
+        self.notation = notation;
+    }
+
+    pub fn notation(&self) -> Notation {
+        self.notation
+    }
+
+    /// Show big numbers as `1,234,567`, also done by `%sep(on)` and
+    /// `%sep(off)`.
+    pub fn set_separators(&mut self, separators: bool) {
+        self.separators = separators;
+    }
+
+    /// Format a value as the REPL shows it, as `set_notation`,
+    /// `set_separators` and `set_polar` say.
+    ///
+    /// ```
+    /// use calculator::{Env, Notation};
+    ///
+    /// let mut env = Env::new();
+    /// let v = env.run("1e6 / 7").unwrap().unwrap();
+    /// env.set_notation(Notation::Fixed(2));
+    /// env.set_separators(true);
+    /// assert_eq!(env.format(&v), "142,857.14");
+    /// ```
+    pub fn format(&self, value: &OneMore) -> String {
+        format::format(value, self.notation, self.separators, self.polar)
+    }
+
+    /// Register a native function, replacing the builtin with the same name.
+    /// A function defined by `fun` still shadows it.
+    ///
+    /// With `Arity::Exact` and `Arity::Between`, `f` only gets numbers and
+    /// is applied element-wise on lists, with `Arity::Variadic` it gets all
+    /// the args flattened.
+    ///
+    /// ```
+    /// use calculator::{Arity, Env, OneMore};
+    ///
+    /// let mut env = Env::new();
+    /// env.register_fn("double", Arity::Exact(1), |args| Ok(OneMore::One(args[0] * 2.0)));
+    /// assert_eq!(env.run("double([1, 2])"), Ok(Some(OneMore::More(vec![2.0, 4.0]))));
+    /// ```
+    pub fn register_fn<F>(&mut self, name: &str, arity: Arity, f: F)
+    where
+        F: Fn(&[f64]) -> Result<OneMore, CalcError> + 'static,
+    {
+        let idx = self.intern(name);
+        self.derived.borrow_mut().clear();
+        self.builtin.insert(idx, NativeFun::new(arity, Rc::new(f)));
+    }
+
+    /// Register a magic run by `%name(args)`, replacing the one with the
+    /// same name. See `Magic` for an example.
+    pub fn register_magic(&mut self, name: &str, magic: impl Magic + 'static) {
+        let idx = self.intern(name);
+        self.magics.insert(idx, Rc::new(magic));
+    }
+
+    /// Set a global variable, as `name = value` does.
+    pub fn set_var(&mut self, name: &str, value: impl Into<OneMore>) {
+        let idx = self.intern(name);
+        self.clear_memo();
+        self.global.insert(idx, value.into());
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&OneMore> {
+        self.var(hash_it(name))
+    }
+
+    /// Keep the last result, read as `ans` and `_` unless a variable has
+    /// that name. It is not a variable, so `vars` and `to_source` leave it
+    /// out, and setting it keeps the results cached by `memo fun`.
+    pub fn set_ans(&mut self, value: impl Into<OneMore>) {
+        self.ans = Some(value.into());
+    }
+
+    // a global variable, or the last result
+    pub(crate) fn var(&self, idx: u64) -> Option<&OneMore> {
+        self.global.get(&idx).or_else(|| {
+            let ans = idx == hash_it("ans") || idx == hash_it("_");
+            ans.then_some(self.ans.as_ref()).flatten()
+        })
+    }
+
+    /// Remove a global variable, give back its value if it was set.
+    pub fn remove_var(&mut self, name: &str) -> Option<OneMore> {
+        self.clear_memo();
+        self.global.remove(&hash_it(name))
+    }
+
+    /// All the global variables (including `PI`, `E` and `i`), sorted by name.
+    pub fn vars(&self) -> Vec<(&str, &OneMore)> {
+        let mut vars: Vec<_> = self
+            .global
+            .iter()
+            .filter_map(|(idx, v)| Some((self.find_name(*idx)?, v)))
+            .collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        vars
+    }
+
+    /// All the functions which can be called, sorted by name. A builtin
+    /// shadowed by a `fun` is not listed.
+    pub fn functions(&self) -> Vec<FunctionInfo> {
+        let user = self
+            .functions
+            .iter()
+            .map(|(idx, f)| (idx, Arity::Exact(f.arity), false));
+        let builtin = self
+            .builtin
+            .iter()
+            .map(|(idx, f)| (idx, f.arity))
+            .chain(self.higher.iter().map(|(idx, f)| (idx, f.arity)))
+            .filter(|(idx, _)| !self.functions.contains_key(idx))
+            .map(|(idx, arity)| (idx, arity, true));
+        let mut functions: Vec<_> = user
+            .chain(builtin)
+            .filter_map(|(idx, arity, builtin)| {
+                Some(FunctionInfo {
+                    name: self.find_name(*idx)?.to_owned(),
+                    arity,
+                    builtin,
+                })
+            })
+            .collect();
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+        functions
+    }
+
+    /// The names of all the magics, like `plot2d` for `%plot2d`, sorted.
+    pub fn magics(&self) -> Vec<&str> {
+        let mut magics: Vec<_> = self
+            .magics
+            .keys()
+            .filter_map(|idx| self.find_name(*idx))
+            .collect();
+        magics.sort();
+        magics
+    }
+
+    /// Remove the function defined by `fun` with this name, or the builtin
+    /// if there is none. `false` if nothing is removed.
+    pub fn remove_fn(&mut self, name: &str) -> bool {
+        let idx = hash_it(name);
+        self.clear_memo();
+        self.derived.borrow_mut().clear();
+        self.functions.remove(&idx).is_some()
+            || self.builtin.remove(&idx).is_some()
+            || self.higher.remove(&idx).is_some()
+    }
+
+    /// The variables and the functions defined by `fun` as statements which
+    /// define them again, like `%mode(exact)`, `x = 1/3` and
+    /// `fun f(x) = x^2`, one per line. `PI`, `E` and `i` are left out unless
+    /// they are changed.
+    ///
+    /// ```
+    /// use calculator::Env;
+    ///
+    /// let mut env = Env::new();
+    /// env.run("k = 2; fun f(x) = k * x + 1").unwrap();
+    /// assert_eq!(env.to_source(), "k = 2\nfun f(x) = k * x + 1\n");
+    ///
+    /// let mut other = Env::new();
+    /// other.run(&env.to_source()).unwrap();
+    /// assert_eq!(other.run("f(3)").unwrap().unwrap().to_string(), "7");
+    /// ```
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        // the mode first, so exact values are read back exact
+        if self.mode == Mode::Exact {
+            source.push_str("%mode(exact)\n");
+        }
+        if self.polar {
+            source.push_str("%complex(polar)\n");
+        }
+        if self.notation != Notation::Plain {
+            source.push_str(&self.notation.magic());
+            source.push('\n');
+        }
+        if self.separators {
+            source.push_str("%sep(on)\n");
+        }
+        let constants = constants();
+        for (name, value) in self.vars() {
+            if constants.contains(&(name, value.clone())) {
+                continue;
+            }
+            source.push_str(&format!("{} = {}\n", name, value_source(value)));
+        }
+        for f in self.functions().into_iter().filter(|f| !f.builtin) {
+            if let Some(fun) = self.show_fn(&f.name) {
+                source.push_str(&fun);
+                source.push('\n');
+            }
+        }
+        source
+    }
+
+    /// Parse a single expression for `eval` and `eval_with`. The names in
+    /// it are looked up when it is evaluated, not when it is prepared.
+    pub fn prepare(&mut self, s: &str) -> Result<Prepared, CalcError> {
+        self.parse_expr(s).map(|expr| Prepared { expr })
+    }
+
+    pub fn eval(&self, prepared: &Prepared) -> Result<OneMore, CalcError> {
+        prepared.expr.value(self, None)
+    }
+
+    /// Evaluate with some variables bound to the given values, the old
+    /// values are put back afterwards.
+    ///
+    /// ```
+    /// use calculator::{Env, OneMore};
+    ///
+    /// let mut env = Env::new();
+    /// let p = env.prepare("x^2 + y").unwrap();
+    /// let v = env.eval_with(&p, &[("x", 3.0), ("y", 1.0)]);
+    /// assert_eq!(v, Ok(OneMore::One(10.0)));
+    /// assert_eq!(env.get_var("x"), None);
+    /// ```
+    pub fn eval_with(
+        &mut self,
+        prepared: &Prepared,
+        vars: &[(&str, f64)],
+    ) -> Result<OneMore, CalcError> {
+        let mut old = Vec::with_capacity(vars.len());
+        for (name, value) in vars {
+            let idx = self.intern(name);
+            old.push((idx, self.global.insert(idx, OneMore::One(*value))));
+        }
+        self.clear_memo();
+        let result = self.eval(prepared);
+        // put back in reverse, so a name bound twice gets its first value
+        for (idx, v) in old.into_iter().rev() {
+            match v {
+                Some(v) => self.global.insert(idx, v),
+                None => self.global.remove(&idx),
+            };
+        }
+        self.clear_memo();
+        result
+    }
+
+    /// Compile a single expression for evaluating on numbers many times,
+    /// e.g. for plots and tables. The names in `vars` are the variables
+    /// given to `CompiledExpr::eval`, the other globals are taken as they
+    /// are now. Lists are not supported, and a complex value is NaN.
+    ///
+    /// ```
+    /// use calculator::Env;
+    ///
+    /// let mut env = Env::new();
+    /// env.run("fun f(x) = x^2; k = 10").unwrap();
+    /// let c = env.compile("f(t) + k", &["t"]).unwrap();
+    /// let ys: Vec<f64> = (0..3).map(|t| c.eval(&[t as f64]).unwrap()).collect();
+    /// assert_eq!(ys, [10.0, 11.0, 14.0]);
+    /// ```
+    pub fn compile(&mut self, s: &str, vars: &[&str]) -> Result<CompiledExpr, CalcError> {
+        let expr = self.parse_expr(s)?;
+        for name in vars {
+            self.intern(name);
+        }
+        compile(self, &expr, vars)
+    }
+
+    /// The definition of `f'`, the derivative of the function `f` with 1 arg
+    /// defined by `fun`, as `diff(f)` shows it. `f'` can be called without
+    /// it, and follows `f` when `f` is defined again.
+    ///
+    /// ```
+    /// use calculator::Env;
+    ///
+    /// let mut env = Env::new();
+    /// env.run("fun f(x) = x^3 + sin(2 * x)").unwrap();
+    /// assert_eq!(env.diff("f").unwrap(), "fun f'(x) = 3 * x^2 + 2 * cos(2 * x)");
+    /// assert_eq!(env.run("f'(0)").unwrap().unwrap().to_string(), "2");
+    /// ```
+    pub fn diff(&mut self, name: &str) -> Result<String, CalcError> {
+        let idx = self.intern(name);
+        self.diff_impl(idx, Span::default())
+    }
+
+    /// The definition of a function defined by `fun`, as it can be read
+    /// back, like `fun f(x) = x^2 + 1`.
+    pub fn show_fn(&self, name: &str) -> Option<String> {
+        self.show(hash_it(name))
+    }
+
+    /// Run every statement in `s`, give back the value of the last one.
+    pub fn run(&mut self, s: &str) -> Result<Option<OneMore>, CalcError> {
+        let mut last = None;
+        for stmt in self.parse(s)? {
+            last = match self.run_impl(stmt)? {
+                Some(Output::Value(v)) => Some(v),
+                _ => None,
+            };
+        }
+        Ok(last)
+    }
+
+    /// Like `run`, but `each` gets what every statement which has an output
+    /// gives, as soon as it is computed, with the env to `format` a value.
+    pub fn run_each<F>(&mut self, s: &str, mut each: F) -> Result<(), CalcError>
+    where
+        F: FnMut(&Env, Output),
+    {
+        for stmt in self.parse(s)? {
+            if let Some(v) = self.run_impl(stmt)? {
+                each(self, v);
+            }
         }
+        Ok(())
     }
 
-    pub fn run(&mut self, s: &str) -> Option<OneMore> {
+    // the whole input is parsed before running, so a syntax error in a
+    // script stops it before anything is done
+    fn parse(&mut self, s: &str) -> CalcResult<Vec<Stmt>> {
         let mut lexer = Scanner::new(s.chars());
         let tokens = lexer.scan();
         let namespace = lexer.pop_namespace();
         let mut parser = Parser::new(tokens.into_iter());
         parser.push_namespace(namespace);
-        let ast = parser.parse()?;
+        parser.push_magic(
+            self.magics
+                .iter()
+                .map(|(idx, m)| (*idx, m.args()))
+                .collect(),
+        );
+        let prog = parser.parse();
         let namespace = parser.pop_namespace();
         self.push_namespace(namespace);
-        self.run_impl(*ast)
+        prog
+    }
+
+    fn parse_expr(&mut self, s: &str) -> CalcResult<Box<Expr>> {
+        let mut prog = self.parse(s)?;
+        match (prog.pop(), prog.is_empty()) {
+            (Some(Stmt::Expr { expr }), true) => Ok(expr),
+            _ => Err(CalcError::new(
+                ErrorKind::Parse,
+                "expect a single expression",
+            )),
+        }
     }
 
-    fn run_impl(&mut self, stmt: Stmt) -> Option<OneMore> {
+    fn run_impl(&mut self, stmt: Stmt) -> CalcResult<Option<Output>> {
         match stmt {
-            Stmt::Fun { idx, body } => {
-                self.functions.insert(idx, body);
-                None
+            Stmt::Fun {
+                idx,
+                params,
+                body,
+                memo,
+            } => {
+                self.clear_memo();
+                self.derived.borrow_mut().clear();
+                let memo = memo.then(|| RefCell::new(HashMap::new()));
+                let f = Function {
+                    arity: params.len(),
+                    params,
+                    body,
+                    memo,
+                };
+                self.functions.insert(idx, Rc::new(f));
+                Ok(None)
             }
-            Stmt::Expr { expr } => expr.value(self, None),
+            Stmt::Diff { idx, span } => self
+                .diff_impl(idx, span)
+                .map(|d| Some(Output::Definition(d))),
+            Stmt::Expr { expr } => expr.value(self, None).map(|v| Some(Output::Value(v))),
             Stmt::Assign { idx, expr } => {
-                let value = expr.value(self, None)?.one()?;
+                let value = expr.value(self, None)?;
+                self.clear_memo();
                 self.global.insert(idx, value);
-                None
-            }
-            Stmt::Magic { kind } => match kind {
-                MagicKind::Plot2d(idx, e1, e2, e3) => {
-                    if let Some((_, body)) = self.functions.get_key_value(&idx) {
-                        let mut c = Canvas::new();
-                        let mut x = e1.value(self, None)?.one()?;
-                        let end = e2.value(self, None)?.one()?;
-                        let step = e3.value(self, None)?.one()?;
-                        while x < end {
-                            let y = body.value(self, Some(&[x]))?.one()?;
-                            c.set(x, y);
-                            x += step;
-                        }
-                        println!("{}", c.frame());
-                        None
-                    } else {
-                        print_err!(
-                            "can't find function {}",
-                            self.find_name(idx).unwrap_or("Unknown")
-                        );
-                        None
-                    }
+                Ok(None)
+            }
+            Stmt::Magic { idx, args, span } => self.magic(idx, args, span),
+        }
+    }
+
+    // the definition of `f'`, errors point at 'diff(f)'
+    fn diff_impl(&mut self, idx: u64, span: Span) -> CalcResult<String> {
+        let name = self.find_name(idx).unwrap_or("Unknown").to_owned();
+        if self.function(idx).map_err(|e| e.with_span(span))?.is_none() {
+            return Err(CalcError::new(
+                ErrorKind::UnknownName,
+                format!("can't find function {}", name),
+            )
+            .with_name(Some(&name))
+            .with_span(span));
+        }
+        let prime = self.intern(&format!("{}'", name));
+        self.function(prime).map_err(|e| e.with_span(span))?;
+        Ok(self.show(prime).unwrap())
+    }
+
+    /// The function defined by `fun`, or the derivative `f'` of one, derived
+    /// from `f` as it is now. `None` if there is neither.
+    pub(crate) fn function(&self, idx: u64) -> CalcResult<Option<Rc<Function>>> {
+        if let Some(f) = self.functions.get(&idx) {
+            return Ok(Some(Rc::clone(f)));
+        }
+        if let Some(f) = self.derived.borrow().get(&idx) {
+            return Ok(Some(Rc::clone(f)));
+        }
+        let Some(name) = self.find_name(idx) else {
+            return Ok(None);
+        };
+        let Some(base) = name.strip_suffix('\'') else {
+            return Ok(None);
+        };
+        let Some(f) = self.function(hash_it(base))? else {
+            return Ok(None);
+        };
+        if f.arity != 1 {
+            return Err(CalcError::new(
+                ErrorKind::Arity,
+                format!(
+                    "diff need a function with 1 arg, but {} has {}",
+                    base, f.arity
+                ),
+            )
+            .with_name(Some(base)));
+        }
+        if self.deriving.borrow().contains(&idx) {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!("can't differentiate {}, which uses {}", base, name),
+            )
+            .with_name(Some(base)));
+        }
+        self.deriving.borrow_mut().push(idx);
+        let body = diff::derive(self, &f, 0);
+        self.deriving.borrow_mut().pop();
+        let d = Rc::new(Function {
+            arity: 1,
+            params: f.params.clone(),
+            body: Box::new(body?),
+            memo: None,
+        });
+        self.derived.borrow_mut().insert(idx, Rc::clone(&d));
+        Ok(Some(d))
+    }
+
+    fn show(&self, idx: u64) -> Option<String> {
+        let f = self.function(idx).ok()??;
+        let params: Vec<&str> = f.params.iter().filter_map(|i| self.find_name(*i)).collect();
+        let show = Show {
+            env: self,
+            params: &f.params,
+            expr: &f.body,
+        };
+        Some(format!(
+            "{}fun {}({}) = {}",
+            if f.memo.is_some() { "memo " } else { "" },
+            self.find_name(idx)?,
+            params.join(" "),
+            show
+        ))
+    }
+
+    fn magic(&mut self, idx: u64, args: Vec<MagicExpr>, span: Span) -> CalcResult<Option<Output>> {
+        let name = self.find_name(idx).unwrap_or("Unknown").to_owned();
+        // it may be removed after a statement using it is parsed
+        let magic = match self.magics.get(&idx) {
+            Some(magic) => Rc::clone(magic),
+            None => {
+                return Err(CalcError::new(
+                    ErrorKind::UnknownName,
+                    format!("can't find magic function named: {}", name),
+                )
+                .with_name(Some(&name))
+                .with_span(span))
+            }
+        };
+        // a lambda is a function named by its source while the magic runs
+        let mut lambdas = Vec::new();
+        let mut given = Vec::with_capacity(args.len());
+        for arg in args {
+            given.push(match arg {
+                MagicExpr::Names(names, span) => {
+                    let names = names
+                        .into_iter()
+                        .map(|name| match name {
+                            MagicName::Idx(idx) => {
+                                self.find_name(idx).unwrap_or("Unknown").to_owned()
+                            }
+                            MagicName::Lambda(lambda) => {
+                                let name = self.lambda(*lambda);
+                                lambdas.push(hash_it(&name));
+                                name
+                            }
+                        })
+                        .collect();
+                    (Given::Names(names), span)
                 }
-            },
+                MagicExpr::Expr(expr) => (Given::Value(expr.value(self, None)?), expr.span()),
+                MagicExpr::Omitted(span) => (Given::Omitted, span),
+            });
+        }
+        let args = MagicArgs { args: given, span };
+        let result = magic.run(self, &args);
+        for idx in lambdas {
+            self.functions.remove(&idx);
+        }
+        result.map_err(|e| {
+            let e = if e.name().is_none() {
+                e.with_name(Some(&name))
+            } else {
+                e
+            };
+            if e.span().is_none() {
+                e.with_span(span)
+            } else {
+                e
+            }
+        })
+    }
+
+    // define a lambda given to a magic as a function named like `x -> x^2`
+    fn lambda(&mut self, lambda: Expr) -> String {
+        let name = Show {
+            env: self,
+            params: &[],
+            expr: &lambda,
+        }
+        .to_string();
+        if let Expr::Lambda { params, body, .. } = lambda {
+            let idx = self.intern(&name);
+            let f = Function {
+                arity: params.len(),
+                params,
+                body,
+                memo: None,
+            };
+            self.functions.insert(idx, Rc::new(f));
+        }
+        name
+    }
+
+    // the user function `name` with `arity` args on numbers, for the magics
+    // which call it many times
+    pub(crate) fn numeric_fn(
+        &self,
+        name: &str,
+        arity: usize,
+        magic: &str,
+        span: Span,
+    ) -> CalcResult<impl Fn(&[f64]) -> CalcResult<f64> + '_> {
+        let idx = hash_it(name);
+        let f = match self.function(idx).map_err(|e| e.with_span(span))? {
+            Some(f) if f.arity == arity => f,
+            Some(f) => {
+                return Err(CalcError::new(
+                    ErrorKind::Arity,
+                    format!(
+                        "{} need a function with {} arg{}, but {} has {}",
+                        magic,
+                        arity,
+                        if arity == 1 { "" } else { "s" },
+                        name,
+                        f.arity
+                    ),
+                )
+                .with_name(Some(name))
+                .with_span(span))
+            }
+            None => {
+                return Err(CalcError::new(
+                    ErrorKind::UnknownName,
+                    format!("can't find function {}", name),
+                )
+                .with_name(Some(name))
+                .with_span(span))
+            }
+        };
+        // a body using lists can't be compiled, so walk it instead
+        let compiled = compile_fn(self, idx, &f, span).ok();
+        Ok(move |xs: &[f64]| {
+            // the body comes from another input, so point at the magic
+            match &compiled {
+                Some(compiled) => compiled.eval(xs),
+                None => {
+                    let args: Vec<OneMore> = xs.iter().map(|x| OneMore::One(*x)).collect();
+                    self.call(idx, &f, &args).and_then(|y| one(y, span))
+                }
+            }
+            .map_err(|e| e.with_span(span))
+        })
+    }
+
+    // a cached result may depend on any global or function
+    fn clear_memo(&self) {
+        for f in self.functions.values() {
+            if let Some(memo) = &f.memo {
+                memo.borrow_mut().clear();
+            }
+        }
+    }
+
+    pub(crate) fn call(&self, idx: u64, f: &Function, args: &[OneMore]) -> CalcResult<OneMore> {
+        let key = f.memo.as_ref().map(|_| memo_key(args));
+        if let (Some(memo), Some(key)) = (&f.memo, &key) {
+            if let Some(v) = memo.borrow().get(key) {
+                return Ok(v.clone());
+            }
+        }
+
+        let depth = self.depth.get();
+        if depth >= self.max_depth {
+            return Err(self.recursion_error(idx));
+        }
+        self.depth.set(depth + 1);
+        let result = f.body.value(self, Some(args));
+        self.depth.set(depth);
+
+        if let (Some(memo), Some(key), Ok(v)) = (&f.memo, key, &result) {
+            memo.borrow_mut().insert(key, v.clone());
+        }
+        result
+    }
+
+    #[cold]
+    fn recursion_error(&self, idx: u64) -> CalcError {
+        let name = self.find_name(idx);
+        CalcError::new(
+            ErrorKind::Recursion,
+            format!(
+                "recursion limit exceeded: more than {} calls deep in {}",
+                self.max_depth,
+                name.unwrap_or("Unknown")
+            ),
+        )
+        .with_name(name)
+    }
+
+    // the hash of a name given by the host, remembered for errors and lists
+    fn intern(&mut self, name: &str) -> u64 {
+        let idx = hash_it(name);
+        self.push_namespace(HashMap::from([(idx, name.to_owned())]));
+        idx
+    }
+
+    // a boolean made by the logical operators
+    fn boolean(&self, b: bool) -> OneMore {
+        match self.mode {
+            Mode::Float => OneMore::One(boolean(b)),
+            Mode::Exact => OneMore::Exact(exact::boolean(b)),
         }
     }
 
@@ -125,7 +839,7 @@ impl Env {
         }
     }
 
-    fn find_name(&self, idx: u64) -> Option<&str> {
+    pub(crate) fn find_name(&self, idx: u64) -> Option<&str> {
         if let Some(namespace) = &self.name_space {
             namespace.get(&idx).map(|x| &**x)
         } else {
@@ -134,91 +848,499 @@ impl Env {
     }
 }
 
-trait Value {
-    fn value(&self, env: &Env, locals: Option<&[f64]>) -> Option<OneMore>;
+// every arg is its length (0 for a number) and then its bits, an exact
+// number is a marker and then its hash, a complex one a marker and its parts,
+// a quantity a marker, its value and the hash of its unit, a matrix a marker,
+// its shape and its bits
+fn memo_key(args: &[OneMore]) -> Vec<u64> {
+    let mut key = Vec::with_capacity(args.len() * 2);
+    for arg in args {
+        match arg {
+            OneMore::Exact(v) => key.extend([u64::MAX, hash_it(v)]),
+            OneMore::Complex(z) => key.extend([u64::MAX - 1, z.re.to_bits(), z.im.to_bits()]),
+            OneMore::Quantity(q) => {
+                key.extend([u64::MAX - 2, q.value().to_bits(), hash_it(q.unit())])
+            }
+            OneMore::Matrix(m) => {
+                key.extend([u64::MAX - 3, m.rows() as u64, m.cols() as u64]);
+                key.extend(m.values().iter().map(|x| x.to_bits()));
+            }
+            _ => {
+                key.push(arg.more().map_or(0, |v| v.len() as u64));
+                key.extend(arg.values().iter().map(|x| x.to_bits()));
+            }
+        }
+    }
+    key
+}
+
+// the variables every env starts with
+fn constants() -> [(&'static str, OneMore); 3] {
+    [
+        ("PI", OneMore::One(std::f64::consts::PI)),
+        ("E", OneMore::One(std::f64::consts::E)),
+        ("i", OneMore::Complex(Complex64::new(0.0, 1.0))),
+    ]
+}
+
+pub(crate) fn one(value: OneMore, span: Span) -> CalcResult<f64> {
+    value.one().ok_or_else(|| {
+        CalcError::new(
+            ErrorKind::Domain,
+            format!("expect a real number, but get {}", what_value(&value)),
+        )
+        .with_span(span)
+    })
+}
+
+// like 'a list' or 'a 2x2 matrix', for errors
+fn what_value(value: &OneMore) -> String {
+    match value {
+        OneMore::Complex(_) => "a complex number".to_owned(),
+        OneMore::Quantity(q) => format!("a quantity in {}", q.unit()),
+        OneMore::Matrix(m) => format!("a {} matrix", m.shape()),
+        OneMore::More(_) => "a list".to_owned(),
+        _ => "a number".to_owned(),
+    }
+}
+
+// any non-zero number is true
+pub(crate) fn truth(value: f64) -> bool {
+    value != 0.0
+}
+
+pub(crate) fn boolean(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+pub(crate) trait Value {
+    fn value(&self, env: &Env, locals: Option<&[OneMore]>) -> CalcResult<OneMore>;
 }
 
 impl Value for Valuable {
-    fn value(&self, env: &Env, locals: Option<&[f64]>) -> Option<OneMore> {
+    fn value(&self, env: &Env, locals: Option<&[OneMore]>) -> CalcResult<OneMore> {
         match self {
-            Self::Value(v) => Some(OneMore::One(*v)),
+            Self::Value(v, exact) => Ok(match env.mode {
+                Mode::Float => OneMore::One(*v),
+                Mode::Exact => OneMore::Exact((**exact).clone()),
+            }),
+            Self::Imag(v) => Ok(Complex64::new(0.0, *v).into()),
+            Self::Quantity(v, unit) => Ok(quantity(*v, (**unit).clone())),
             Self::Arg(i) => {
-                if let Some(v) = locals?.get(*i) {
-                    Some(OneMore::One(*v))
+                if let Some(v) = locals.and_then(|l| l.get(*i)) {
+                    Ok(v.clone())
                 } else {
-                    print_err!("too little values give");
-                    None
+                    Err(CalcError::new(ErrorKind::Arity, "too little values give"))
                 }
             }
             Self::Var(idx) => {
-                if let Some(v) = env.global.get(idx) {
-                    Some(OneMore::One(*v))
+                if let Some(v) = env.var(*idx) {
+                    Ok(v.clone())
+                } else if let Some(unit) = env.find_name(*idx).and_then(units::lookup) {
+                    // a unit alone is one of it, like 'km' in 'x * km'
+                    Ok(quantity(1.0, unit))
                 } else {
-                    print_err!(
-                        "can't find variable named '{}'",
-                        env.find_name(*idx).unwrap_or("Unknown")
-                    );
-                    None
+                    let name = env.find_name(*idx);
+                    Err(CalcError::new(
+                        ErrorKind::UnknownName,
+                        format!("can't find variable named '{}'", name.unwrap_or("Unknown")),
+                    )
+                    .with_name(name))
                 }
             }
         }
     }
 }
 
+// every arm is in its own function, so a deep recursion of user functions
+// only keeps small frames on the native stack
 impl Value for Expr {
-    fn value(&self, env: &Env, locals: Option<&[f64]>) -> Option<OneMore> {
+    fn value(&self, env: &Env, locals: Option<&[OneMore]>) -> CalcResult<OneMore> {
         match self {
-            Expr::Literal { value } => value.value(env, locals),
-            Expr::Binary { left, op, right } => {
-                let lv = left.value(env, locals)?.one()?;
-                let rv = right.value(env, locals)?.one()?;
-                let result = match op {
-                    BinaryOp::Plus => lv + rv,
-                    BinaryOp::Sub => lv - rv,
-                    BinaryOp::Mult => lv * rv,
-                    BinaryOp::Div => lv / rv,
-                    BinaryOp::Square => lv.powf(rv),
-                };
-                Some(OneMore::One(result))
+            Expr::Literal { value, span } => {
+                value.value(env, locals).map_err(|e| e.with_span(*span))
             }
-            Expr::Call { idx, args } => {
-                let mut this_locals = Vec::new();
-                for e in args {
-                    let v = e.value(env, locals)?.one()?;
-                    this_locals.push(v);
-                }
-                if let Some((_, body)) = env.functions.get_key_value(idx) {
-                    body.value(env, Some(&this_locals))
-                } else if let Some((_, f)) = env.builtin.get_key_value(idx) {
-                    if this_locals.len() != f.arg_num {
-                        print_err!(
-                            "expect {} arguments, but get {}",
-                            f.arg_num,
-                            this_locals.len()
-                        );
-                        return None;
-                    }
-                    let v = (f.fun)(&this_locals);
-                    Some(v)
+            Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            } => binary(env, locals, left, op, right, *span),
+            Expr::Cond {
+                cond, then, other, ..
+            } => {
+                if truth(one(cond.value(env, locals)?, cond.span())?) {
+                    then.value(env, locals)
                 } else {
-                    print_err!(
-                        "function {} is not defined",
-                        env.find_name(*idx).unwrap_or("Unknown")
-                    );
-                    None
+                    other.value(env, locals)
                 }
             }
-            Expr::Unary { op, operand } => {
-                let value = operand.value(env, locals)?.one()?;
-                let result = match op {
-                    UnaryOp::Minus => -value,
-                    UnaryOp::Ftl => factorial(value as u32),
-                };
-                Some(OneMore::One(result))
+            Expr::Call { idx, args, span } => call(env, locals, *idx, args, *span),
+            Expr::Unary { op, operand, span } => unary(env, locals, op, operand, *span),
+            Expr::List { items, span } => list(env, locals, items, *span),
+            Expr::Group { body, .. } => body.value(env, locals),
+            Expr::Convert { expr, unit, span } => {
+                units::convert(&expr.value(env, locals)?, unit).map_err(|e| e.with_span(*span))
+            }
+            Expr::Let { bindings, body, .. } => let_in(env, locals, bindings, body),
+            Expr::Lambda { span, .. } => Err(CalcError::new(
+                ErrorKind::Domain,
+                "a function like x -> x^2 is not a value, it can only be given to a function like map or a magic, or assigned to a name",
+            )
+            .with_span(*span)),
+        }
+    }
+}
+
+// a list of numbers, or a matrix if its items are lists, which are its rows
+fn list(env: &Env, locals: Option<&[OneMore]>, items: &[Expr], span: Span) -> CalcResult<OneMore> {
+    let mut values = Vec::with_capacity(items.len());
+    let mut rows = Vec::new();
+    for e in items {
+        match e.value(env, locals)? {
+            OneMore::More(row) if values.is_empty() => rows.push(row),
+            v if !rows.is_empty() || matches!(v, OneMore::More(_) | OneMore::Matrix(_)) => {
+                let what = if rows.is_empty() { "numbers" } else { "rows" };
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!(
+                        "a list can't hold {} and {}, a matrix is written like [[1, 2], [3, 4]]",
+                        what,
+                        what_value(&v)
+                    ),
+                )
+                .with_span(e.span()));
             }
-            Expr::Group { body } => body.value(env, locals),
+            v => values.push(one(v, e.span())?),
         }
     }
+    if rows.is_empty() {
+        return Ok(OneMore::More(values));
+    }
+    Matrix::from_rows(rows)
+        .map(OneMore::Matrix)
+        .map_err(|e| e.with_span(span))
+}
+
+// the values are put after the args in scope, each once
+fn let_in(
+    env: &Env,
+    locals: Option<&[OneMore]>,
+    bindings: &[(u64, Expr)],
+    body: &Expr,
+) -> CalcResult<OneMore> {
+    let mut all = locals.map_or_else(Vec::new, |l| l.to_vec());
+    for (_, value) in bindings {
+        let v = value.value(env, Some(&all))?;
+        all.push(v);
+    }
+    body.value(env, Some(&all))
+}
+
+fn binary(
+    env: &Env,
+    locals: Option<&[OneMore]>,
+    left: &Expr,
+    op: &BinaryOp,
+    right: &Expr,
+    span: Span,
+) -> CalcResult<OneMore> {
+    let lv = left.value(env, locals)?;
+    match op {
+        // the right side of '&&' and '||' is only evaluated when needed
+        BinaryOp::And | BinaryOp::Or => {
+            let l = truth(one(lv, left.span())?);
+            if l == matches!(op, BinaryOp::Or) {
+                return Ok(env.boolean(l));
+            }
+            let r = truth(one(right.value(env, locals)?, right.span())?);
+            return Ok(env.boolean(r));
+        }
+        BinaryOp::Range => {
+            let from = one(lv, left.span())?;
+            let to = one(right.value(env, locals)?, right.span())?;
+            return range(from, to, span);
+        }
+        _ => {}
+    }
+
+    let rv = right.value(env, locals)?;
+    if let Some(v) = matrix::binary(op, &lv, &rv).map_err(|e| e.with_span(span))? {
+        return Ok(v);
+    }
+    if let (OneMore::Exact(a), OneMore::Exact(b)) = (&lv, &rv) {
+        if let Some(v) = exact::binary(op, a, b).map_err(|e| e.with_span(span))? {
+            return Ok(OneMore::Exact(v));
+        }
+    }
+    if let Some(v) = units::binary(op, &lv, &rv).map_err(|e| e.with_span(span))? {
+        return Ok(v);
+    }
+    if let Some(v) = complex::binary(op, &lv, &rv).map_err(|e| e.with_span(span))? {
+        return Ok(v);
+    }
+    lv.zip_with(&rv, binary_fn(op))
+        .ok_or_else(|| length_error(&[lv, rv], span))
+}
+
+// the operators which work on two numbers
+pub(crate) fn binary_fn(op: &BinaryOp) -> fn(f64, f64) -> f64 {
+    match op {
+        BinaryOp::Plus => |a, b| a + b,
+        BinaryOp::Sub => |a, b| a - b,
+        BinaryOp::Mult => |a, b| a * b,
+        BinaryOp::Div => |a, b| a / b,
+        BinaryOp::Square => f64::powf,
+        BinaryOp::Less => |a, b| boolean(a < b),
+        BinaryOp::LessEq => |a, b| boolean(a <= b),
+        BinaryOp::Eq => |a, b| boolean(a == b),
+        BinaryOp::NotEq => |a, b| boolean(a != b),
+        BinaryOp::Greater => |a, b| boolean(a > b),
+        BinaryOp::GreaterEq => |a, b| boolean(a >= b),
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Range | BinaryOp::MatMul => unreachable!(),
+    }
+}
+
+fn range(from: f64, to: f64, span: Span) -> CalcResult<OneMore> {
+    if !from.is_finite() || !to.is_finite() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "the ends of a range must be finite, but get {}..{}",
+                from, to
+            ),
+        )
+        .with_span(span));
+    }
+    if to < from {
+        return Ok(OneMore::More(Vec::new()));
+    }
+    let len = (to - from).floor() + 1.0;
+    if len > MAX_LIST_LEN as f64 {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!("a range can't be longer than {}", MAX_LIST_LEN),
+        )
+        .with_span(span));
+    }
+    let values = (0..len as usize).map(|i| from + i as f64).collect();
+    Ok(OneMore::More(values))
+}
+
+fn call(
+    env: &Env,
+    locals: Option<&[OneMore]>,
+    idx: u64,
+    args: &[Expr],
+    span: Span,
+) -> CalcResult<OneMore> {
+    // a fun shadows them too, they get their args unevaluated
+    if let (Some(f), false) = (env.higher.get(&idx), env.functions.contains_key(&idx)) {
+        return f.call(env, locals, idx, args, span);
+    }
+    let mut this_locals = Vec::with_capacity(args.len());
+    for e in args {
+        this_locals.push(e.value(env, locals)?);
+    }
+    if let Some(f) = env.function(idx).map_err(|e| e.with_span(span))? {
+        if this_locals.len() != f.arity {
+            return Err(arity_error(env, idx, &f.arity, this_locals.len(), span));
+        }
+        // the body may come from another input, so point at the call
+        env.call(idx, &f, &this_locals)
+            .map_err(|e| e.with_span(span))
+    } else if let Some(f) = env.builtin.get(&idx) {
+        call_native(env, idx, f, &this_locals, span)
+            .map_err(|e| e.with_name(env.find_name(idx)).with_span(span))
+    } else {
+        let name = env.find_name(idx);
+        Err(CalcError::new(
+            ErrorKind::UnknownName,
+            format!("function {} is not defined", name.unwrap_or("Unknown")),
+        )
+        .with_name(name)
+        .with_span(span))
+    }
+}
+
+pub(crate) fn call_native(
+    env: &Env,
+    idx: u64,
+    f: &NativeFun,
+    args: &[OneMore],
+    span: Span,
+) -> CalcResult<OneMore> {
+    let (min, max) = match f.arity {
+        Arity::Variadic => {
+            if let Some(z) = args.iter().find(|x| not_real(x)) {
+                return Err(one(z.clone(), span).unwrap_err());
+            }
+            let flat: Vec<f64> = args.iter().flat_map(|x| x.values().into_owned()).collect();
+            return (f.fun)(&flat);
+        }
+        Arity::Exact(n) => (n, n),
+        Arity::Between(min, max) => (min, max),
+    };
+    if args.len() < min || args.len() > max {
+        let expect = if min == max {
+            min.to_string()
+        } else {
+            format!("{} to {}", min, max)
+        };
+        return Err(arity_error(env, idx, &expect, args.len(), span));
+    }
+    if let Some(m) = f.matrix {
+        if let Some(v) = m(args)? {
+            return Ok(v);
+        }
+    }
+    // a matrix goes element-wise like a list, and keeps its shape
+    if let Some(v) = matrix::elementwise(args, |flat| call_native(env, idx, f, flat, span))
+        .map_err(|e| e.with_span(span))?
+    {
+        return Ok(v);
+    }
+    let n = args.len();
+    if let (Some(c), [OneMore::Complex(z)]) = (f.complex, args) {
+        return Ok(c(*z).into());
+    }
+    if let Some(z) = args.iter().find(|x| not_real(x)) {
+        return Err(one(z.clone(), span).unwrap_err());
+    }
+
+    // all numbers, or broadcast to the length of the lists
+    let len = match args.iter().filter_map(|x| x.more()).map(|x| x.len()).max() {
+        None => {
+            let scalars: Vec<f64> = args.iter().map(|x| x.values()[0]).collect();
+            let v = (f.fun)(&scalars)?;
+            // out of the real domain, like sqrt(-1), try it on complex numbers
+            if let (Some(c), [x], Some(y)) = (f.complex, &scalars[..], v.one()) {
+                if y.is_nan() && !x.is_nan() {
+                    return Ok(c(Complex64::new(*x, 0.0)).into());
+                }
+            }
+            return Ok(v);
+        }
+        Some(len) => len,
+    };
+    if args
+        .iter()
+        .any(|x| x.more().is_some_and(|x| x.len() != len))
+    {
+        return Err(length_error(args, span));
+    }
+
+    let mut values = Vec::with_capacity(len);
+    let mut row = vec![0.0; n];
+    for i in 0..len {
+        for (r, arg) in row.iter_mut().zip(args) {
+            *r = match arg {
+                OneMore::More(v) => v[i],
+                arg => arg.values()[0],
+            };
+        }
+        let v = (f.fun)(&row)?.one().ok_or_else(|| {
+            CalcError::new(
+                ErrorKind::Domain,
+                "a function giving lists can't be applied element-wise",
+            )
+        })?;
+        values.push(v);
+    }
+    Ok(OneMore::More(values))
+}
+
+// the natives only take real numbers and lists of them
+fn not_real(x: &OneMore) -> bool {
+    matches!(x, OneMore::Complex(_) | OneMore::Quantity(_))
+}
+
+fn unary(
+    env: &Env,
+    locals: Option<&[OneMore]>,
+    op: &UnaryOp,
+    operand: &Expr,
+    span: Span,
+) -> CalcResult<OneMore> {
+    let value = operand.value(env, locals)?;
+    if let (OneMore::Complex(z), UnaryOp::Minus) = (&value, op) {
+        return Ok(OneMore::Complex(-z));
+    }
+    if let (OneMore::Quantity(q), UnaryOp::Minus) = (&value, op) {
+        return Ok(q.neg());
+    }
+    if not_real(&value) {
+        return Err(one(value, span).unwrap_err());
+    }
+    if let OneMore::Exact(v) = &value {
+        return match op {
+            UnaryOp::Minus => Ok(OneMore::Exact(-v)),
+            UnaryOp::Not => Ok(OneMore::Exact(exact::boolean(v.is_zero()))),
+            // the factorial of a fraction is a float
+            UnaryOp::Ftl => match exact::factorial(v).map_err(|e| e.with_span(span))? {
+                Some(v) => Ok(OneMore::Exact(v)),
+                None => ftl(value.one().unwrap())
+                    .map(OneMore::One)
+                    .map_err(|e| e.with_span(span)),
+            },
+        };
+    }
+    match op {
+        UnaryOp::Minus => Ok(value.map(|x| -x)),
+        UnaryOp::Not => Ok(value.map(|x| boolean(!truth(x)))),
+        UnaryOp::Ftl => value.try_map(|x| ftl(x).map_err(|e| e.with_span(span))),
+    }
+}
+
+// gamma(x + 1) between the integers
+pub(crate) fn ftl(x: f64) -> CalcResult<f64> {
+    if x < 0.0 && x.fract() == 0.0 {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "factorial is not defined for negative integers, but get {}",
+                x
+            ),
+        ));
+    }
+    Ok(factorial(x))
+}
+
+#[cold]
+fn length_error(values: &[OneMore], span: Span) -> CalcError {
+    let lens: Vec<String> = values
+        .iter()
+        .filter_map(|x| x.more())
+        .map(|x| x.len().to_string())
+        .collect();
+    CalcError::new(
+        ErrorKind::Domain,
+        format!(
+            "can't apply element-wise on lists of different lengths: {}",
+            lens.join(", ")
+        ),
+    )
+    .with_span(span)
+}
+
+#[cold]
+pub(crate) fn arity_error(
+    env: &Env,
+    idx: u64,
+    expect: &dyn Display,
+    get: usize,
+    span: Span,
+) -> CalcError {
+    CalcError::new(
+        ErrorKind::Arity,
+        format!("expect {} arguments, but get {}", expect, get),
+    )
+    .with_name(env.find_name(idx))
+    .with_span(span)
 }
 
 impl Default for Env {
@@ -226,3 +1348,712 @@ impl Default for Env {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(env: &mut Env, s: &str) -> Result<Option<f64>, CalcError> {
+        env.run(s).map(|v| v.and_then(|v| v.one()))
+    }
+
+    #[test]
+    fn test_run() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "1 + 2 * 3"), Ok(Some(7.0)));
+        assert_eq!(run(&mut env, "x = 4"), Ok(None));
+        assert_eq!(run(&mut env, "fun f(a b) = a * x + b"), Ok(None));
+        assert_eq!(run(&mut env, "f(2 1)"), Ok(Some(9.0)));
+        assert_eq!(run(&mut env, ""), Ok(None));
+    }
+
+    #[test]
+    fn test_cond() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "1 < 2 && 2 <= 2 && 3 != 4"), Ok(Some(1.0)));
+        assert_eq!(run(&mut env, "1 > 2 || !(1 == 1)"), Ok(Some(0.0)));
+        assert_eq!(run(&mut env, "-1 < 0 ? 5 : 6"), Ok(Some(5.0)));
+        assert_eq!(run(&mut env, "3! == 6"), Ok(Some(1.0)));
+
+        env.run("fun sign(x) = x > 0 ? 1 : x < 0 ? -1 : 0").unwrap();
+        assert_eq!(run(&mut env, "sign(-3) + sign(0) * 10"), Ok(Some(-1.0)));
+        env.run("fun clamp(x lo hi) = if(x < lo, lo, if(x > hi, hi, x))")
+            .unwrap();
+        assert_eq!(run(&mut env, "clamp(7, 0, 5)"), Ok(Some(5.0)));
+
+        // the untaken branch and the right side of '&&' are not evaluated
+        assert_eq!(run(&mut env, "1 ? 2 : undefined"), Ok(Some(2.0)));
+        assert_eq!(run(&mut env, "0 && undefined"), Ok(Some(0.0)));
+        env.run("fun fact(n) = n <= 1 ? 1 : n * fact(n - 1)")
+            .unwrap();
+        assert_eq!(run(&mut env, "fact(5)"), Ok(Some(120.0)));
+
+        let err = env.run("1 < 2 < 3").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+    }
+
+    #[test]
+    fn test_let() {
+        let mut env = Env::new();
+        assert_eq!(
+            run(&mut env, "let a = 2, b = a + 1 in a * b"),
+            Ok(Some(6.0))
+        );
+        assert_eq!(run(&mut env, "1 + { a = 2; a * 3 }"), Ok(Some(7.0)));
+        let script = "fun f(x) = {\n  a = x + 1\n\n  b = a * 2\n  a * b\n}\nf(2)";
+        assert_eq!(run(&mut env, script), Ok(Some(18.0)));
+        assert_eq!(
+            env.show_fn("f").unwrap(),
+            "fun f(x) = { a = x + 1; b = a * 2; a * b }"
+        );
+
+        // the names shadow the args and the globals, only in the let
+        env.run("x = 10; fun g(x) = let x = x * 2 in let y = x + 1 in x * y")
+            .unwrap();
+        assert_eq!(run(&mut env, "g(1)"), Ok(Some(6.0)));
+        assert_eq!(run(&mut env, "let x = 1 in x"), Ok(Some(1.0)));
+        assert_eq!(run(&mut env, "x"), Ok(Some(10.0)));
+        assert_eq!(
+            env.show_fn("g").unwrap(),
+            "fun g(x) = let x = x * 2 in let y = x + 1 in x * y"
+        );
+        env.run("fun h(n) = (let m = n * 2 in m) + (n > 3 ? 0 : h(n + 1))")
+            .unwrap();
+        assert_eq!(run(&mut env, "h(1)"), Ok(Some(20.0)));
+        assert_eq!(
+            env.show_fn("h").unwrap(),
+            "fun h(n) = (let m = n * 2 in m) + (n > 3 ? 0 : h(n + 1))"
+        );
+        // a value is evaluated once
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        env.register_fn("slow", Arity::Exact(1), move |args| {
+            counter.set(counter.get() + 1);
+            Ok(OneMore::One(args[0]))
+        });
+        assert_eq!(run(&mut env, "{ s = slow(3); s * s + s }"), Ok(Some(12.0)));
+        assert_eq!(calls.get(), 1);
+        env.run("fun t(n) = { s = n * km; s in m }").unwrap();
+        assert_eq!(
+            env.show_fn("t").unwrap(),
+            "fun t(n) = { s = n * km; s in m }"
+        );
+        assert_eq!(
+            env.run("map(x -> let s = x * x in s + 1, 1..3)"),
+            Ok(Some(OneMore::More(vec![2.0, 5.0, 10.0])))
+        );
+
+        for s in [
+            "let a = 1 in",
+            "let a 1 in a",
+            "let a = 1 b",
+            "{ a = 1 }",
+            "{ a = 1; a",
+            "{ a = 1 a }",
+            "{}",
+        ] {
+            assert_eq!(env.run(s).unwrap_err().kind(), ErrorKind::Parse, "{}", s);
+        }
+        // the names are gone after the let, even after an error in it
+        assert_eq!(env.run("let a = 1 in 2; a").unwrap_err().name(), Some("a"));
+        assert!(env.run("fun k(x) = let y = x in").is_err());
+        assert_eq!(env.run("y").unwrap_err().kind(), ErrorKind::UnknownName);
+    }
+
+    #[test]
+    fn test_recursion() {
+        let mut env = Env::new();
+        env.run("fun f(x) = f(x)").unwrap();
+        let err = env.run("1 + f(1)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Recursion);
+        assert_eq!(err.name(), Some("f"));
+        assert_eq!(err.span(), Some(Span::new(4, 8)));
+
+        env.run("fun down(n) = n <= 0 ? 0 : 1 + down(n - 1)")
+            .unwrap();
+        assert_eq!(run(&mut env, "down(100)"), Ok(Some(100.0)));
+        env.set_max_depth(50);
+        let err = env.run("down(100)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Recursion);
+        // the env still works after the error
+        assert_eq!(run(&mut env, "down(10)"), Ok(Some(10.0)));
+
+        let err = env.run("down(1 2)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Arity);
+    }
+
+    #[test]
+    fn test_memo() {
+        let mut env = Env::new();
+        env.run("memo fun fib(n) = n < 2 ? n : fib(n - 1) + fib(n - 2)")
+            .unwrap();
+        // without the cache this would take 2^90 calls
+        assert_eq!(run(&mut env, "fib(90)"), Ok(Some(2880067194370816000.0)));
+
+        // the cache is dropped when a global it may use changes
+        env.run("k = 1; memo fun g(x) = x + k").unwrap();
+        assert_eq!(run(&mut env, "g(1)"), Ok(Some(2.0)));
+        env.run("k = 10").unwrap();
+        assert_eq!(run(&mut env, "g(1)"), Ok(Some(11.0)));
+
+        let err = env.run("memo g(x) = x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+    }
+
+    #[test]
+    fn test_list() {
+        let mut env = Env::new();
+        let list = |v: &[f64]| Ok(Some(OneMore::More(v.to_vec())));
+        assert_eq!(env.run("[1, 2 3]"), list(&[1.0, 2.0, 3.0]));
+        assert_eq!(env.run("[]"), list(&[]));
+        assert_eq!(env.run("1..4"), list(&[1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(env.run("0.5..2"), list(&[0.5, 1.5]));
+        assert_eq!(env.run("3..1"), list(&[]));
+        assert_eq!(
+            env.run("linspace(0, 1, 5)"),
+            list(&[0.0, 0.25, 0.5, 0.75, 1.0])
+        );
+
+        // broadcasting
+        assert_eq!(env.run("[1, 2] * 2 + [10, 20]"), list(&[12.0, 24.0]));
+        assert_eq!(env.run("-(1..3)!"), list(&[-1.0, -2.0, -6.0]));
+        assert_eq!(env.run("1..4 > 2"), list(&[0.0, 0.0, 1.0, 1.0]));
+        assert_eq!(env.run("abs([-1, 2])"), list(&[1.0, 2.0]));
+        env.run("fun sq(x) = x^2").unwrap();
+        assert_eq!(env.run("sq(1..3)"), list(&[1.0, 4.0, 9.0]));
+
+        // reductions
+        env.run("xs = [3, 1, 4, 1, 5]").unwrap();
+        assert_eq!(run(&mut env, "sum(xs)"), Ok(Some(14.0)));
+        assert_eq!(run(&mut env, "mean(xs)"), Ok(Some(2.8)));
+        assert_eq!(run(&mut env, "min(xs) + max(xs)"), Ok(Some(6.0)));
+        assert_eq!(run(&mut env, "len(xs)"), Ok(Some(5.0)));
+        assert_eq!(run(&mut env, "sum(1..100)"), Ok(Some(5050.0)));
+        assert_eq!(env.run("sum([])").unwrap().unwrap().to_string(), "0");
+        assert_eq!(env.run("sum()").unwrap().unwrap().to_string(), "0");
+        assert_eq!(run(&mut env, "max(xs, 9)"), Ok(Some(9.0)));
+
+        let err = env.run("[1, 2] + [1, 2, 3]").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        let err = env.run("mean([])").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        assert_eq!(err.name(), Some("mean"));
+        let err = env.run("[1, [2]]").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        let err = env.run("xs ? 1 : 2").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+    }
+
+    #[test]
+    fn test_native() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "atan2(1, 1) * 4 == PI"), Ok(Some(1.0)));
+        assert_eq!(run(&mut env, "hypot(3, 4) + pow(2, 10)"), Ok(Some(1029.0)));
+        assert_eq!(run(&mut env, "log(2, 1024)"), Ok(Some(10.0)));
+        assert_eq!(run(&mut env, "mod(-1, 3) + mod(7, 3)"), Ok(Some(3.0)));
+        assert_eq!(run(&mut env, "gcd(12, -18) + lcm(4, 6)"), Ok(Some(18.0)));
+        assert_eq!(run(&mut env, "round(1.23456, 3)"), Ok(Some(1.235)));
+        assert_eq!(run(&mut env, "round(2.5)"), Ok(Some(3.0)));
+        assert_eq!(run(&mut env, "round(1234, -2)"), Ok(Some(1200.0)));
+        assert_eq!(run(&mut env, "round(1.5, 400)"), Ok(Some(1.5)));
+        assert_eq!(run(&mut env, "round(1e300, 20)"), Ok(Some(1e300)));
+        assert_eq!(run(&mut env, "round(1234, -400)"), Ok(Some(0.0)));
+        assert_eq!(run(&mut env, "round(1/0, 2)"), Ok(Some(f64::INFINITY)));
+        assert_eq!(
+            run(&mut env, "min(3, 1, 2) + max(1, [5, 2], 4)"),
+            Ok(Some(6.0))
+        );
+        assert_eq!(
+            env.run("pow(2, [1, 2, 3])"),
+            Ok(Some(OneMore::More(vec![2.0, 4.0, 8.0])))
+        );
+
+        let err = env.run("gcd(1.5, 2)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        assert_eq!(err.name(), Some("gcd"));
+        let err = env.run("round(1, 2, 3)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Arity);
+        assert_eq!(err.message(), "expect 1 to 2 arguments, but get 3");
+
+        // the same seed gives the same numbers on rerun
+        let draw = "seed(1); [rand(), randn(), randint(1, 100)]";
+        let first = env.run(draw).unwrap();
+        assert_eq!(env.run(draw).unwrap(), first);
+        let err = env.run("randint(3, 1)").unwrap_err();
+        assert_eq!(err.name(), Some("randint"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut env = Env::new();
+        env.run("xs = [2, 4, 4, 4, 5, 5, 7, 9]").unwrap();
+        assert_eq!(run(&mut env, "median(xs) + median(1, 3, 2)"), Ok(Some(6.5)));
+        assert_eq!(run(&mut env, "var(xs) * 7"), Ok(Some(32.0)));
+        assert_eq!(run(&mut env, "percentile(xs, 25)"), Ok(Some(4.0)));
+        assert_eq!(run(&mut env, "percentile(xs, 100)"), Ok(Some(9.0)));
+        assert_eq!(run(&mut env, "percentile(7, 30)"), Ok(Some(7.0)));
+        assert_eq!(
+            run(&mut env, "percentile([[1, 3], [2, 4]], 50)"),
+            Ok(Some(2.5))
+        );
+        assert_eq!(run(&mut env, "nCr(52, 5)"), Ok(Some(2598960.0)));
+        assert_eq!(run(&mut env, "nPr(5, 2) + nCr(2, 5)"), Ok(Some(20.0)));
+        assert_eq!(run(&mut env, "5! == gamma(6)"), Ok(Some(1.0)));
+        assert_eq!(
+            run(&mut env, "round(0.5!^2 * 4, 12) == round(PI, 12)"),
+            Ok(Some(1.0))
+        );
+        assert_eq!(
+            run(&mut env, "normcdf(0) + normcdf(100, 100, 15)"),
+            Ok(Some(1.0))
+        );
+        assert_eq!(
+            run(&mut env, "round(norminv(0.975), 6)"),
+            Ok(Some(1.959964))
+        );
+        assert_eq!(run(&mut env, "binompdf(4, 0.5, 2)"), Ok(Some(0.375)));
+        assert_eq!(
+            env.run("erf([0, 1]) == [0, 1] * erf(1)"),
+            Ok(Some(OneMore::More(vec![1.0, 1.0])))
+        );
+
+        let err = env.run("(-2)!").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        let err = env.run("stdev(1)").unwrap_err();
+        assert_eq!(err.name(), Some("stdev"));
+        let err = env.run("percentile([10, 20, 50])").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Arity);
+        let err = env.run("percentile(xs, [25, 50])").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        let err = env.run("nCr(1.5, 1)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        let err = env.run("norminv(2)").unwrap_err();
+        assert_eq!(err.name(), Some("norminv"));
+    }
+
+    #[test]
+    fn test_register_fn() {
+        let mut env = Env::new();
+        env.register_fn("clip", Arity::Exact(3), |args| {
+            Ok(OneMore::One(args[0].clamp(args[1], args[2])))
+        });
+        env.register_fn("count", Arity::Variadic, |args| {
+            Ok(OneMore::One(args.len() as f64))
+        });
+        env.register_fn("fail", Arity::Exact(0), |_| {
+            Err(CalcError::new(ErrorKind::Domain, "always fail"))
+        });
+
+        assert_eq!(
+            run(&mut env, "clip(5, 0, 1) + count(1, [2, 3])"),
+            Ok(Some(4.0))
+        );
+        assert_eq!(
+            env.run("clip([-1, 0.5, 2], 0, 1)"),
+            Ok(Some(OneMore::More(vec![0.0, 0.5, 1.0])))
+        );
+        let err = env.run("1 + fail()").unwrap_err();
+        assert_eq!(err.message(), "always fail");
+        assert_eq!(err.name(), Some("fail"));
+        assert_eq!(err.span(), Some(Span::new(4, 10)));
+        let err = env.run("clip(1)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Arity);
+    }
+
+    #[test]
+    fn test_register_magic() {
+        use crate::MagicArg;
+
+        // %count([f, g], x[, y]) counts the names and sets x to y
+        struct Count;
+        impl Magic for Count {
+            fn args(&self) -> Vec<MagicArg> {
+                vec![MagicArg::Idxs, MagicArg::Idx, MagicArg::OptExpr]
+            }
+
+            fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+                if let Some(v) = args.value(2) {
+                    if args.real(2)? < 0.0 {
+                        return Err(CalcError::new(ErrorKind::Domain, "no negative"));
+                    }
+                    env.set_var(args.name(1), v.clone());
+                }
+                Ok(Some(OneMore::One(args.names(0).len() as f64).into()))
+            }
+        }
+
+        let mut env = Env::new();
+        env.register_magic("count", Count);
+        assert_eq!(run(&mut env, "%count([f, g, h], x)"), Ok(Some(3.0)));
+        assert_eq!(run(&mut env, "%count(f, x, 2 + 3)"), Ok(Some(1.0)));
+        assert_eq!(env.get_var("x"), Some(&OneMore::One(5.0)));
+
+        // an error without a span points at the magic
+        let err = env.run("%count(f, x, -1)").unwrap_err();
+        assert_eq!(err.name(), Some("count"));
+        assert_eq!(err.span(), Some(Span::new(0, 16)));
+        let err = env.run("%count(f, x, [1, 2])").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(13, 19)));
+        assert_eq!(
+            env.run("%count(1, x)").unwrap_err().kind(),
+            ErrorKind::Parse
+        );
+        assert_eq!(
+            env.run("%counts(f, x)").unwrap_err().kind(),
+            ErrorKind::UnknownName
+        );
+
+        assert!(env
+            .magics()
+            .starts_with(&["complex", "count", "format", "integrate"]));
+
+        // a builtin magic can be replaced
+        env.register_magic("mode", Count);
+        assert_eq!(run(&mut env, "%mode(exact, x)"), Ok(Some(1.0)));
+        assert_eq!(env.mode(), Mode::Float);
+    }
+
+    #[test]
+    fn test_exact() {
+        let mut env = Env::new();
+        let show = |env: &mut Env, s| env.run(s).unwrap().unwrap().to_string();
+        assert_eq!(show(&mut env, "0.1 + 0.2"), "0.30000000000000004");
+        assert_eq!(show(&mut env, "20!"), "2432902008176640000");
+        env.run("third = 1/3").unwrap();
+
+        env.run("%mode(exact)").unwrap();
+        assert_eq!(env.mode(), Mode::Exact);
+        assert_eq!(show(&mut env, "0.1 + 0.2"), "3/10");
+        assert_eq!(show(&mut env, "1/3 + 1/6"), "1/2");
+        assert_eq!(show(&mut env, "25!"), "15511210043330985984000000");
+        assert_eq!(show(&mut env, "(2/3)^-2 - 0.25"), "2");
+        assert_eq!(show(&mut env, "0.1 + 0.2 == 0.3 && !0"), "1");
+        // functions, lists and values made in float mode are floats
+        assert_eq!(show(&mut env, "third + 0"), "0.3333333333333333");
+        assert_eq!(show(&mut env, "sqrt(4) + 1/4"), "2.25");
+        assert_eq!(show(&mut env, "[1/2, 1] * 2"), "[1, 2]");
+        assert_eq!(show(&mut env, "2^0.5"), "1.4142135623730951");
+        env.run("memo fun fib(n) = n < 2 ? n : fib(n - 1) + fib(n - 2)")
+            .unwrap();
+        assert_eq!(show(&mut env, "fib(100)"), "354224848179261915075");
+
+        let err = env.run("1 + 1/0").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        assert_eq!(err.span(), Some(Span::new(4, 7)));
+        let err = env.run("%mode(fast)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownName);
+        env.set_mode(Mode::Float);
+        assert_eq!(show(&mut env, "1/0"), "inf");
+    }
+
+    #[test]
+    fn test_format() {
+        let mut env = Env::new();
+        assert_eq!(run(&mut env, "0xff + 0b101 + 0o17"), Ok(Some(275.0)));
+        assert_eq!(run(&mut env, "1e-3 + 2.5E+2 - 1e2"), Ok(Some(150.001)));
+        // a prefix without digits is 0 and a name
+        assert_eq!(env.run("0b").unwrap_err().kind(), ErrorKind::Parse);
+        assert_eq!(env.run("2e").unwrap_err().kind(), ErrorKind::Parse);
+        assert_eq!(env.run("1e99999").unwrap_err().kind(), ErrorKind::Lex);
+        assert_eq!(env.run("2e-3i").unwrap().unwrap().to_string(), "0.002i");
+        env.set_mode(Mode::Exact);
+        assert_eq!(
+            env.run("1e-3 * 0x10").unwrap().unwrap().to_string(),
+            "2/125"
+        );
+        env.set_mode(Mode::Float);
+
+        let show = |env: &mut Env, s: &str| {
+            let v = env.run(s).unwrap().unwrap();
+            env.format(&v)
+        };
+        env.run("%format(fix, 3)").unwrap();
+        assert_eq!(show(&mut env, "PI"), "3.142");
+        env.run("%format(fix); %sep(on)").unwrap();
+        assert_eq!(show(&mut env, "1e6 / 3"), "333,333.33");
+        env.run("%format(eng, 2); %sep(off)").unwrap();
+        assert_eq!(show(&mut env, "4.7e-5 km"), "47.0e-6 km");
+        env.run("%format(hex)").unwrap();
+        assert_eq!(show(&mut env, "[255, 0.5]"), "[0xff, 0.5]");
+        assert_eq!(env.to_source(), "%format(hex)\n");
+        env.run("%format(plain)").unwrap();
+        assert_eq!(show(&mut env, "0.1 + 0.2"), "0.30000000000000004");
+
+        let err = env.run("%format(sig, 0)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+        assert_eq!(err.span(), Some(Span::new(13, 14)));
+        let err = env.run("%format(hex, 2)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Arity);
+        let err = env.run("%format(roman)").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "unknown format roman, expect plain or hex or bin or oct or fix or sig or sci or eng"
+        );
+        assert_eq!(env.notation(), Notation::Plain);
+    }
+
+    #[test]
+    fn test_complex() {
+        let mut env = Env::new();
+        let show = |env: &mut Env, s| env.run(s).unwrap().unwrap().to_string();
+        assert_eq!(show(&mut env, "sqrt(-1)"), "1i");
+        assert_eq!(show(&mut env, "(3+4i) * (1-2i)"), "11-2i");
+        assert_eq!(show(&mut env, "abs(3+4i) + re(2-i) + im(2-i)"), "6");
+        assert_eq!(show(&mut env, "i^2 + 1"), "0");
+        assert_eq!(show(&mut env, "conj(1/(1+i))"), "0.5+0.5i");
+        assert_eq!(show(&mut env, "arg(-1) == PI && 2i == 2*i"), "1");
+        assert_eq!(show(&mut env, "-sqrt(-4) / 2"), "-1i");
+        // a negative base to a fractional power is complex, like sqrt
+        assert_eq!(show(&mut env, "(-1)^0.5 == sqrt(-1)"), "1");
+        assert_eq!(show(&mut env, "round(re((-8)^(1/3)), 12)"), "1");
+        assert_eq!(show(&mut env, "round(im((-8)^(1/3))^2, 12)"), "3");
+        assert_eq!(show(&mut env, "(-2)^2 + (-8)^-1"), "3.875");
+        env.run("fun z(r, t) = r * exp(i * t); memo fun sq(x) = x^2")
+            .unwrap();
+        assert_eq!(show(&mut env, "abs(z(2, 1))"), "2");
+        assert_eq!(show(&mut env, "sq(i) + sq(1i)"), "-2");
+        // 'i' is not eaten from a name after a number
+        assert!(env.run("2in").is_err());
+
+        let v = env.run("3+4i").unwrap().unwrap();
+        env.run("%complex(polar)").unwrap();
+        assert_eq!(env.format(&v), "5∠0.9272952180016122");
+        env.set_polar(false);
+        assert_eq!(env.format(&v), "3+4i");
+
+        for s in [
+            "i < 1",
+            "[i]",
+            "[1, 2] * i",
+            "(2i)!",
+            "floor(i)",
+            "sum(i)",
+            "i ? 1 : 2",
+        ] {
+            let err = env.run(s).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Domain, "{}", s);
+        }
+        assert!(env.compile("x + 1i", &["x"]).is_err());
+    }
+
+    #[test]
+    fn test_units() {
+        let mut env = Env::new();
+        let show = |env: &mut Env, s| env.run(s).unwrap().unwrap().to_string();
+        assert_eq!(show(&mut env, "3 km / 20 min"), "2.5 m/s");
+        assert_eq!(show(&mut env, "3 km / 20 min in km/h"), "9 km/h");
+        assert_eq!(show(&mut env, "1 km + 300 m"), "1.3 km");
+        assert_eq!(show(&mut env, "9.81 m/s^2 * 70 kg"), "686.7 N");
+        assert_eq!(show(&mut env, "2 h to min"), "120 min");
+        assert_eq!(show(&mut env, "(2 m)^2 * -3"), "-12 m^2");
+        assert_eq!(show(&mut env, "1 / 4 ms"), "250 Hz");
+        assert_eq!(show(&mut env, "1 km / 1 m"), "1000");
+        assert_eq!(show(&mut env, "1 km > 900 m"), "1");
+        // a name which is not a variable may be a unit
+        env.run("x = 5; fun f(m) = 2 * m + m in kg").unwrap();
+        assert_eq!(show(&mut env, "x * mi in km"), "8.04672 km");
+        assert_eq!(show(&mut env, "f(1000 g)"), "3 kg");
+
+        for s in [
+            "1 m + 1 s",
+            "1 m in s",
+            "3 in m",
+            "sqrt(4 m)",
+            "[1 m]",
+            "1 m < 2",
+        ] {
+            let err = env.run(s).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Domain, "{}", s);
+        }
+        assert_eq!(
+            env.run("1 m in foo").unwrap_err().kind(),
+            ErrorKind::UnknownName
+        );
+        assert!(env.compile("x * km", &["x"]).unwrap().eval(&[1.0]).is_err());
+        assert!(env.compile("x * 2 km", &["x"]).is_err());
+    }
+
+    #[test]
+    fn test_embedding() {
+        let mut env = Env::new();
+        env.set_var("rate", 0.05);
+        env.set_var("xs", vec![1.0, 2.0]);
+        assert_eq!(run(&mut env, "100 * rate"), Ok(Some(5.0)));
+        env.run("x = 2; fun f(a b) = a + b").unwrap();
+        assert_eq!(env.get_var("x"), Some(&OneMore::One(2.0)));
+        assert_eq!(env.get_var("y"), None);
+
+        let names: Vec<&str> = env.vars().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["E", "PI", "i", "rate", "x", "xs"]);
+        let functions = env.functions();
+        let f = functions.iter().find(|f| f.name == "f").unwrap();
+        assert_eq!((f.arity, f.builtin), (Arity::Exact(2), false));
+        let round = functions.iter().find(|f| f.name == "round").unwrap();
+        assert_eq!((round.arity, round.builtin), (Arity::Between(1, 2), true));
+
+        assert_eq!(env.remove_var("x"), Some(OneMore::One(2.0)));
+        assert_eq!(env.remove_var("x"), None);
+        assert!(env.remove_fn("f"));
+        assert!(!env.remove_fn("f"));
+        assert_eq!(
+            env.run("f(1, 2)").unwrap_err().kind(),
+            ErrorKind::UnknownName
+        );
+        // a `fun` shadows the builtin, removing it shows the builtin again
+        env.run("fun sin(x) = 0").unwrap();
+        assert!(env.remove_fn("sin"));
+        assert_eq!(run(&mut env, "sin(0)"), Ok(Some(0.0)));
+        assert!(env.functions().iter().any(|f| f.name == "sin" && f.builtin));
+    }
+
+    #[test]
+    fn test_ans() {
+        let mut env = Env::new();
+        assert!(env.run("ans").is_err());
+        env.run("memo fun f(x) = x^2").unwrap();
+        env.set_ans(3.0);
+        assert_eq!(run(&mut env, "ans * 2 + _"), Ok(Some(9.0)));
+        assert_eq!(env.compile("_ + 1", &[]).unwrap().eval(&[]), Ok(4.0));
+        // it is not a variable, a variable named like it wins
+        assert!(env.vars().iter().all(|(name, _)| *name != "ans"));
+        assert_eq!(env.to_source(), "memo fun f(x) = x^2\n");
+        env.run("_ = 1").unwrap();
+        assert_eq!(run(&mut env, "_ + ans"), Ok(Some(4.0)));
+    }
+
+    #[test]
+    fn test_to_source() {
+        let mut env = Env::new();
+        assert_eq!(env.to_source(), "");
+        env.run("memo fun f(x) = x < 2 ? x : f(x - 1) + f(x - 2); fun g(x) = x^3; diff(g)")
+            .unwrap();
+        env.run("xs = [1, 1/0, 0/0]; z = 3 - 4i; d = 3 km; n = -1/0; PI = 3")
+            .unwrap();
+        let source = env.to_source();
+        assert_eq!(
+            source,
+            "PI = 3\nd = 3 km\nn = -1/0\nxs = [1, 1/0, 0/0]\nz = 3-4i\n\
+             memo fun f(x) = x < 2 ? x : f(x - 1) + f(x - 2)\n\
+             fun g(x) = x^3\n"
+        );
+
+        let mut other = Env::new();
+        other.run(&source).unwrap();
+        assert_eq!(other.to_source(), source);
+        // `g'` is derived again from `g`, it is not saved
+        assert_eq!(run(&mut other, "f(20) + g'(1)"), Ok(Some(6768.0)));
+
+        let mut env = Env::new();
+        env.run("%mode(exact); %complex(polar); x = 1/3").unwrap();
+        let source = env.to_source();
+        assert_eq!(source, "%mode(exact)\n%complex(polar)\nx = 1/3\n");
+        let mut other = Env::new();
+        other.run(&source).unwrap();
+        assert_eq!(other.mode(), Mode::Exact);
+        assert_eq!(other.run("x * 3").unwrap().unwrap().to_string(), "1");
+    }
+
+    #[test]
+    fn test_prepare() {
+        let mut env = Env::new();
+        env.run("memo fun g(t) = t * k").unwrap();
+        let p = env.prepare("g(x) + 1").unwrap();
+        let mut values = Vec::new();
+        for k in 1..=3 {
+            let v = env.eval_with(&p, &[("x", 2.0), ("k", k as f64)]).unwrap();
+            values.push(v);
+        }
+        assert_eq!(values, [3.0, 5.0, 7.0].map(OneMore::One));
+        assert_eq!(env.get_var("x"), None);
+
+        env.set_var("x", 10.0);
+        env.set_var("k", 1.0);
+        assert_eq!(env.eval(&p), Ok(OneMore::One(11.0)));
+        assert_eq!(env.eval_with(&p, &[("x", 0.0)]), Ok(OneMore::One(1.0)));
+        assert_eq!(env.get_var("x"), Some(&OneMore::One(10.0)));
+
+        assert_eq!(env.prepare("x = 1").unwrap_err().kind(), ErrorKind::Parse);
+        assert_eq!(env.prepare("1; 2").unwrap_err().kind(), ErrorKind::Parse);
+        let p = env.prepare("y * 2").unwrap();
+        let err = env.eval(&p).unwrap_err();
+        assert_eq!(err.name(), Some("y"));
+    }
+
+    #[test]
+    fn test_script() {
+        let mut env = Env::new();
+        let script =
+            "# constants\nr = 2\n\nfun area(r) = PI * r^2 # circle\n1; area(r)\ndiff(area)\n";
+        let mut values = Vec::new();
+        env.run_each(script, |_, v| values.push(v)).unwrap();
+        assert_eq!(
+            values,
+            [
+                Output::Value(OneMore::One(1.0)),
+                Output::Value(OneMore::One(std::f64::consts::PI * 4.0)),
+                Output::Definition("fun area'(r) = PI * (2 * r)".to_owned()),
+            ]
+        );
+        assert_eq!(run(&mut env, "a = 1; b = 2; a + b"), Ok(Some(3.0)));
+
+        let script = "x = 1\ny = x +\nz = 3";
+        let err = env.run(script).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        assert!(err.render(script).contains(" --> 2:8"));
+        // nothing runs if the script can't be parsed
+        assert!(env.run("x").is_err());
+
+        let script = "x = 5\nq + 1";
+        let err = env.run(script).unwrap_err();
+        assert!(err.render(script).contains(" --> 2:1"));
+        assert_eq!(run(&mut env, "x"), Ok(Some(5.0)));
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let mut env = Env::new();
+        let err = env.run("1 + .").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Lex);
+
+        let err = env.run("1 + )").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+
+        let err = env.run("y + 1").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownName);
+        assert_eq!(err.name(), Some("y"));
+
+        let err = env.run("g(1)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownName);
+        assert_eq!(err.name(), Some("g"));
+
+        let err = env.run("sin(1 2)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Arity);
+        assert_eq!(err.name(), Some("sin"));
+
+        let err = env.run("(-1)!").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Domain);
+    }
+
+    #[test]
+    fn test_error_span() {
+        let mut env = Env::new();
+        let err = env.run("1 + sin(2) * y").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(13, 14)));
+        assert_eq!(
+            err.render("1 + sin(2) * y"),
+            "error: can't find variable named 'y'\n --> 1:14\n  |\n1 | 1 + sin(2) * y\n  |              ^"
+        );
+
+        let err = env.run("2 * sin(1 2)").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(4, 12)));
+        assert!(err.render("2 * sin(1 2)").ends_with("|     ^~~~~~~~"));
+
+        let err = env.run("1 +").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(3, 3)));
+
+        // errors from a function body point at the call
+        env.run("fun f(x) = x + z").unwrap();
+        let err = env.run("3 - f(1)").unwrap_err();
+        assert_eq!(err.span(), Some(Span::new(4, 8)));
+    }
+}
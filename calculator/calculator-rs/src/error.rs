@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+pub(crate) type CalcResult<T> = Result<T, CalcError>;
+
+/// What went wrong, so a host can tell a typo from a bad call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// the scanner met a character or literal it can't understand
+    Lex,
+    /// the tokens don't make a valid statement
+    Parse,
+    /// a variable, function or magic which is not defined
+    UnknownName,
+    /// wrong number of arguments
+    Arity,
+    /// the value is out of the domain of the operation
+    Domain,
+    /// user functions call each other too deep
+    Recursion,
+}
+
+/// Byte offsets `start..end` into the source given to `Env::run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalcError {
+    kind: ErrorKind,
+    message: String,
+    name: Option<String>,
+    span: Option<Span>,
+}
+
+impl CalcError {
+    /// For native functions registered with `Env::register_fn`, the name
+    /// and the span are filled in by the call.
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        CalcError {
+            kind,
+            message: message.into(),
+            name: None,
+            span: None,
+        }
+    }
+
+    pub(crate) fn with_name(mut self, name: Option<&str>) -> Self {
+        self.name = name.map(|x| x.to_owned());
+        self
+    }
+
+    pub(crate) fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The name of the variable or function which caused the error
+    /// (if there is one).
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Render the error rustc-style, with the line of `source` it comes
+    /// from and a `^~~~` under the bad part:
+    ///
+    /// ```text
+    /// error: can't find variable named 'y'
+    ///  --> 1:5
+    ///   |
+    /// 1 | 1 + y
+    ///   |     ^
+    /// ```
+    ///
+    /// `source` must be the string the error comes from.
+    pub fn render(&self, source: &str) -> String {
+        self.render_impl(None, source)
+    }
+
+    /// Like `render`, but the location is prefixed by the file name,
+    /// e.g. ` --> lib.calc:3:5`.
+    pub fn render_file(&self, path: &str, source: &str) -> String {
+        self.render_impl(Some(path), source)
+    }
+
+    fn render_impl(&self, path: Option<&str>, source: &str) -> String {
+        let span = match self.span {
+            Some(span) if span.start <= source.len() => span,
+            _ => return format!("error: {}", self.message),
+        };
+
+        let line_start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| span.start + i);
+        let line = source[line_start..line_end].trim_end_matches('\r');
+        let line_no = source[..line_start].matches('\n').count() + 1;
+
+        let col = source[line_start..span.start].chars().count();
+        // a span never goes beyond its first line when underlined
+        let end = span.end.clamp(span.start, line_end);
+        let width = source[span.start..end].chars().count().max(1);
+
+        let gutter = " ".repeat(line_no.to_string().len());
+        let path = path.map(|p| format!("{}:", p)).unwrap_or_default();
+        format!(
+            "error: {}\n{}--> {}{}:{}\n{} |\n{} | {}\n{} | {}^{}",
+            self.message,
+            gutter,
+            path,
+            line_no,
+            col + 1,
+            gutter,
+            line_no,
+            line,
+            gutter,
+            " ".repeat(col),
+            "~".repeat(width - 1),
+        )
+    }
+}
+
+impl Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for CalcError {}
@@ -0,0 +1,151 @@
+// Exact arithmetic on big rationals, for `Mode::Exact`.
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+
+use crate::{
+    ast::BinaryOp,
+    error::{CalcError, CalcResult, ErrorKind},
+};
+
+// a power or factorial is only exact while it is smaller than this, bigger
+// ones take too long and are shown as a wall of digits anyway
+const MAX_BITS: u64 = 1 << 20;
+const MAX_FACTORIAL: u64 = 20_000;
+
+/// The exact value of a decimal literal like `12.034` or `1.5e-3`.
+pub(crate) fn parse_decimal(s: &str) -> BigRational {
+    let (s, exp) = match s.split_once(['e', 'E']) {
+        Some((s, exp)) => (s, exp.parse().unwrap()),
+        None => (s, 0),
+    };
+    let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+    let numer: BigInt = format!("{}{}", int, frac).parse().unwrap();
+    let exp = exp - frac.len() as i32;
+    let scale = BigInt::from(10).pow(exp.unsigned_abs());
+    if exp < 0 {
+        BigRational::new(numer, scale)
+    } else {
+        BigRational::from_integer(numer * scale)
+    }
+}
+
+pub(crate) fn to_f64(x: &BigRational) -> f64 {
+    x.to_f64().unwrap_or(f64::NAN)
+}
+
+pub(crate) fn boolean(b: bool) -> BigRational {
+    if b {
+        BigRational::one()
+    } else {
+        BigRational::zero()
+    }
+}
+
+/// `None` if `op` can't give an exact result for these, then the caller
+/// goes on with floats.
+pub(crate) fn binary(
+    op: &BinaryOp,
+    a: &BigRational,
+    b: &BigRational,
+) -> CalcResult<Option<BigRational>> {
+    let v = match op {
+        BinaryOp::Plus => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mult => a * b,
+        BinaryOp::Div => {
+            if b.is_zero() {
+                return Err(CalcError::new(ErrorKind::Domain, "division by zero"));
+            }
+            a / b
+        }
+        BinaryOp::Square => return pow(a, b),
+        BinaryOp::Less => boolean(a < b),
+        BinaryOp::LessEq => boolean(a <= b),
+        BinaryOp::Eq => boolean(a == b),
+        BinaryOp::NotEq => boolean(a != b),
+        BinaryOp::Greater => boolean(a > b),
+        BinaryOp::GreaterEq => boolean(a >= b),
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Range | BinaryOp::MatMul => return Ok(None),
+    };
+    Ok(Some(v))
+}
+
+// only integer exponents are exact
+fn pow(a: &BigRational, b: &BigRational) -> CalcResult<Option<BigRational>> {
+    if !b.is_integer() {
+        return Ok(None);
+    }
+    let bits = a.numer().bits().max(a.denom().bits());
+    let exp = match b.to_integer().abs().to_u64() {
+        Some(exp) if exp.saturating_mul(bits) <= MAX_BITS => exp,
+        _ => return Ok(None),
+    };
+    if a.is_zero() && b.is_negative() {
+        return Err(CalcError::new(ErrorKind::Domain, "division by zero"));
+    }
+    let v = num_traits::pow(a.clone(), exp as usize);
+    Ok(Some(if b.is_negative() { v.recip() } else { v }))
+}
+
+/// `None` if `x` is not an integer, its factorial is not exact.
+pub(crate) fn factorial(x: &BigRational) -> CalcResult<Option<BigRational>> {
+    if !x.is_integer() {
+        return Ok(None);
+    }
+    if x.is_negative() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "factorial is not defined for negative integers, but get {}",
+                x
+            ),
+        ));
+    }
+    let n = match x.to_integer().to_u64() {
+        Some(n) if n <= MAX_FACTORIAL => n,
+        _ => {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "exact factorial is only for numbers up to {}, but get {}",
+                    MAX_FACTORIAL, x
+                ),
+            ))
+        }
+    };
+    let v = (2..=n).fold(BigInt::one(), |acc, i| acc * i);
+    Ok(Some(BigRational::from_integer(v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exact(s: &str) -> BigRational {
+        parse_decimal(s)
+    }
+
+    #[test]
+    fn test_exact() {
+        assert_eq!(exact("0.1") + exact("0.2"), exact("0.3"));
+        assert_eq!(exact("12.50"), BigRational::new(25.into(), 2.into()));
+        assert_eq!(exact("7"), BigRational::from_integer(7.into()));
+        assert_eq!(exact("1.5e-3"), BigRational::new(3.into(), 2000.into()));
+        assert_eq!(exact("2E3"), BigRational::from_integer(2000.into()));
+
+        let pow = |a, b| binary(&BinaryOp::Square, &exact(a), &exact(b)).unwrap();
+        assert_eq!(pow("2", "-2"), Some(exact("0.25")));
+        assert_eq!(pow("2", "0.5"), None);
+        assert_eq!(pow("2", "100000000"), None);
+        assert!(binary(&BinaryOp::Div, &exact("1"), &exact("0")).is_err());
+
+        assert_eq!(
+            factorial(&exact("25")).unwrap().unwrap().to_string(),
+            "15511210043330985984000000"
+        );
+        assert_eq!(factorial(&exact("2.5")), Ok(None));
+        assert!(factorial(&exact("-2")).is_err());
+        assert!(factorial(&exact("100000")).is_err());
+    }
+}
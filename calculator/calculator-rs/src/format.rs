@@ -0,0 +1,267 @@
+// Show the values as `%format` and `%sep` say, for `Env::format`.
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, Signed};
+
+use crate::{complex, exact, matrix, onemore::OneMore};
+
+/// How `Env::format` shows the real numbers, set by `%format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Notation {
+    /// The shortest form which reads back the same, like `0.1`.
+    #[default]
+    Plain,
+    /// This many decimals, `%format(fix, 2)` shows `PI` as `3.14`.
+    Fixed(usize),
+    /// This many significant digits, `%format(sig, 3)` shows `1/800` as
+    /// `0.00125`.
+    Sig(usize),
+    /// Scientific with this many decimals, `%format(sci, 2)` shows `1234`
+    /// as `1.23e3`.
+    Sci(usize),
+    /// Like `Sci` with an exponent which is a multiple of 3,
+    /// `%format(eng, 2)` shows `12345` as `12.3e3`.
+    Eng(usize),
+    /// Integers in base 16, 2 or 8 like `0xff`, `0b11` and `0o17`, other
+    /// numbers are plain.
+    Hex,
+    Bin,
+    Oct,
+}
+
+// as many decimals as a f64 can have
+pub(crate) const MAX_DIGITS: usize = 17;
+
+impl Notation {
+    /// The `%format` which sets it, like `%format(fix, 2)`.
+    pub(crate) fn magic(&self) -> String {
+        match self {
+            Notation::Plain => "%format(plain)".to_owned(),
+            Notation::Fixed(n) => format!("%format(fix, {})", n),
+            Notation::Sig(n) => format!("%format(sig, {})", n),
+            Notation::Sci(n) => format!("%format(sci, {})", n),
+            Notation::Eng(n) => format!("%format(eng, {})", n),
+            Notation::Hex => "%format(hex)".to_owned(),
+            Notation::Bin => "%format(bin)".to_owned(),
+            Notation::Oct => "%format(oct)".to_owned(),
+        }
+    }
+
+    fn radix(&self) -> Option<(u32, &'static str)> {
+        match self {
+            Notation::Hex => Some((16, "0x")),
+            Notation::Bin => Some((2, "0b")),
+            Notation::Oct => Some((8, "0o")),
+            _ => None,
+        }
+    }
+}
+
+/// `value` with its numbers in `notation`, and `1,234,567` for big ones
+/// with `separators`. Complex numbers are in polar form with `polar`.
+pub(crate) fn format(value: &OneMore, notation: Notation, separators: bool, polar: bool) -> String {
+    let real = |x: f64| real(x, notation, separators);
+    match value {
+        OneMore::One(v) => real(*v),
+        OneMore::More(v) => {
+            let items: Vec<String> = v.iter().map(|x| real(*x)).collect();
+            format!("[{}]", items.join(", "))
+        }
+        OneMore::Exact(v) => rational(v, notation, separators),
+        OneMore::Complex(z) => complex::show(z, polar, real),
+        OneMore::Quantity(q) => format!("{} {}", real(q.value()), q.unit()),
+        OneMore::Matrix(m) => matrix::show(m, real),
+    }
+}
+
+fn real(x: f64, notation: Notation, separators: bool) -> String {
+    if !x.is_finite() {
+        return x.to_string();
+    }
+    let s = match notation {
+        Notation::Plain => x.to_string(),
+        Notation::Fixed(n) => format!("{:.*}", n, x),
+        Notation::Sig(n) => sig(x, n),
+        Notation::Sci(n) => format!("{:.*e}", n, x),
+        Notation::Eng(n) => eng(x, n),
+        Notation::Hex | Notation::Bin | Notation::Oct => {
+            match BigInt::from_f64(x).filter(|_| x.fract() == 0.0) {
+                Some(v) => return integer(&v, notation),
+                None => x.to_string(),
+            }
+        }
+    };
+    if separators {
+        group(&s)
+    } else {
+        s
+    }
+}
+
+// an exact number keeps all its digits when it is plain or an integer
+fn rational(v: &BigRational, notation: Notation, separators: bool) -> String {
+    match notation {
+        _ if v.is_integer() && notation.radix().is_some() => integer(v.numer(), notation),
+        Notation::Plain | Notation::Hex | Notation::Bin | Notation::Oct => {
+            let s = v.to_string();
+            if !separators {
+                return s;
+            }
+            match s.split_once('/') {
+                Some((numer, denom)) => format!("{}/{}", group(numer), group(denom)),
+                None => group(&s),
+            }
+        }
+        _ => real(exact::to_f64(v), notation, separators),
+    }
+}
+
+fn integer(v: &BigInt, notation: Notation) -> String {
+    let (radix, prefix) = notation.radix().unwrap();
+    let sign = if v.is_negative() { "-" } else { "" };
+    format!("{}{}{}", sign, prefix, v.abs().to_str_radix(radix))
+}
+
+// the digits and the exponent of `x` rounded to `n` significant digits
+fn digits(x: f64, n: usize) -> (String, i32) {
+    let s = format!("{:.*e}", n.max(1) - 1, x.abs());
+    let (mantissa, exp) = s.split_once('e').unwrap();
+    (mantissa.replace('.', ""), exp.parse().unwrap())
+}
+
+fn sign(x: f64) -> &'static str {
+    if x.is_sign_negative() && x != 0.0 {
+        "-"
+    } else {
+        ""
+    }
+}
+
+// without an exponent unless it is too big or too small, like a f64 in JS
+fn sig(x: f64, n: usize) -> String {
+    let (digits, exp) = digits(x, n);
+    if !(-6..21).contains(&exp) {
+        return format!("{:.*e}", n.max(1) - 1, x);
+    }
+    let len = digits.len() as i32;
+    let s = if exp < 0 {
+        format!("0.{}{}", "0".repeat((-exp - 1) as usize), digits)
+    } else if exp + 1 >= len {
+        format!("{}{}", digits, "0".repeat((exp + 1 - len) as usize))
+    } else {
+        let (int, frac) = digits.split_at(exp as usize + 1);
+        format!("{}.{}", int, frac)
+    };
+    format!("{}{}", sign(x), s)
+}
+
+fn eng(x: f64, n: usize) -> String {
+    let (mut digits, exp) = digits(x, n + 1);
+    // 1 to 3 digits before the point
+    let shift = exp.rem_euclid(3) as usize;
+    while digits.len() <= shift {
+        digits.push('0');
+    }
+    let (int, frac) = digits.split_at(shift + 1);
+    let point = if frac.is_empty() { "" } else { "." };
+    format!("{}{}{}{}e{}", sign(x), int, point, frac, exp - shift as i32)
+}
+
+// '1234567.89' as '1,234,567.89', only the digits before the point
+fn group(s: &str) -> String {
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => ("-", s),
+        None => ("", s),
+    };
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (int, rest) = s.split_at(end);
+    let mut grouped = String::with_capacity(int.len() + int.len() / 3);
+    for (i, c) in int.chars().enumerate() {
+        if i != 0 && (int.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    format!("{}{}{}", sign, grouped, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(x: f64, notation: Notation) -> String {
+        format(&OneMore::One(x), notation, false, false)
+    }
+
+    #[test]
+    fn test_notation() {
+        assert_eq!(show(0.1 + 0.2, Notation::Plain), "0.30000000000000004");
+        assert_eq!(show(std::f64::consts::PI, Notation::Fixed(2)), "3.14");
+        assert_eq!(show(-2.0, Notation::Fixed(0)), "-2");
+
+        assert_eq!(show(1.0 / 800.0, Notation::Sig(3)), "0.00125");
+        assert_eq!(show(123456.0, Notation::Sig(2)), "120000");
+        assert_eq!(show(-9.996, Notation::Sig(3)), "-10.0");
+        assert_eq!(show(1.5, Notation::Sig(4)), "1.500");
+        assert_eq!(show(1e-12, Notation::Sig(2)), "1.0e-12");
+        assert_eq!(show(6.02e20, Notation::Sig(3)), "602000000000000000000");
+        assert_eq!(show(6.02e23, Notation::Sig(3)), "6.02e23");
+
+        assert_eq!(show(1234.0, Notation::Sci(2)), "1.23e3");
+        assert_eq!(show(-0.000123, Notation::Sci(1)), "-1.2e-4");
+
+        assert_eq!(show(12345.0, Notation::Eng(2)), "12.3e3");
+        assert_eq!(show(0.0123, Notation::Eng(2)), "12.3e-3");
+        assert_eq!(show(-999999.0, Notation::Eng(2)), "-1.00e6");
+        assert_eq!(show(100.0, Notation::Eng(0)), "100e0");
+        assert_eq!(show(0.0, Notation::Eng(1)), "0.0e0");
+
+        assert_eq!(show(255.0, Notation::Hex), "0xff");
+        assert_eq!(show(-5.0, Notation::Bin), "-0b101");
+        assert_eq!(show(8.0, Notation::Oct), "0o10");
+        assert_eq!(show(1e20, Notation::Hex), "0x56bc75e2d63100000");
+        assert_eq!(show(0.5, Notation::Hex), "0.5");
+
+        for notation in [Notation::Plain, Notation::Sig(3), Notation::Hex] {
+            assert_eq!(show(f64::NAN, notation), "NaN");
+            assert_eq!(show(f64::NEG_INFINITY, notation), "-inf");
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let big = OneMore::One(-1234567.891);
+        assert_eq!(format(&big, Notation::Plain, true, false), "-1,234,567.891");
+        assert_eq!(
+            format(&big, Notation::Fixed(1), true, false),
+            "-1,234,567.9"
+        );
+        assert_eq!(format(&big, Notation::Sci(2), true, false), "-1.23e6");
+        assert_eq!(
+            format(&OneMore::One(123.0), Notation::Plain, true, false),
+            "123"
+        );
+
+        let exact = OneMore::Exact(BigRational::new(1234567.into(), 1000.into()));
+        assert_eq!(
+            format(&exact, Notation::Plain, true, false),
+            "1,234,567/1,000"
+        );
+        assert_eq!(format(&exact, Notation::Fixed(2), false, false), "1234.57");
+        assert_eq!(format(&exact, Notation::Hex, false, false), "1234567/1000");
+        let exact = OneMore::Exact(BigRational::from_integer(BigInt::from(1) << 70));
+        assert_eq!(
+            format(&exact, Notation::Hex, false, false),
+            "0x400000000000000000"
+        );
+
+        let list = OneMore::More(vec![1.0, 2.5]);
+        assert_eq!(
+            format(&list, Notation::Fixed(1), false, false),
+            "[1.0, 2.5]"
+        );
+        let z = OneMore::Complex(num_complex::Complex64::new(3.0, -4.0));
+        assert_eq!(format(&z, Notation::Fixed(1), false, false), "3.0-4.0i");
+        assert_eq!(format(&z, Notation::Fixed(1), false, true), "5.0∠-0.9");
+    }
+}
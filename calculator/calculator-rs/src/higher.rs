@@ -0,0 +1,310 @@
+// Functions which take a function, like `map(x -> x^2, xs)`. Their args
+// are not evaluated before the call, the first one is the function.
+use std::rc::Rc;
+
+use crate::{
+    ast::{Expr, Valuable},
+    builtin::{Arity, NativeFun},
+    env::{arity_error, call_native, one, truth, Env, Function, Value},
+    error::{CalcError, CalcResult, ErrorKind, Span},
+    onemore::OneMore,
+};
+
+type HigherFn = fn(&Env, Option<&[OneMore]>, &[Expr]) -> CalcResult<OneMore>;
+
+#[derive(Clone, Copy)]
+pub(crate) struct HigherFun {
+    pub(crate) arity: Arity,
+    fun: HigherFn,
+}
+
+pub(crate) fn higher() -> Vec<(&'static str, HigherFun)> {
+    vec![
+        (
+            "map",
+            HigherFun {
+                arity: Arity::Exact(2),
+                fun: map,
+            },
+        ),
+        (
+            "filter",
+            HigherFun {
+                arity: Arity::Exact(2),
+                fun: filter,
+            },
+        ),
+        (
+            "reduce",
+            HigherFun {
+                arity: Arity::Between(2, 3),
+                fun: reduce,
+            },
+        ),
+    ]
+}
+
+impl HigherFun {
+    pub(crate) fn call(
+        &self,
+        env: &Env,
+        locals: Option<&[OneMore]>,
+        idx: u64,
+        args: &[Expr],
+        span: Span,
+    ) -> CalcResult<OneMore> {
+        let (min, max) = match self.arity {
+            Arity::Exact(n) => (n, n),
+            Arity::Between(min, max) => (min, max),
+            Arity::Variadic => (0, usize::MAX),
+        };
+        if args.len() < min || args.len() > max {
+            let expect = if min == max {
+                min.to_string()
+            } else {
+                format!("{} to {}", min, max)
+            };
+            return Err(arity_error(env, idx, &expect, args.len(), span));
+        }
+        (self.fun)(env, locals, args).map_err(|e| {
+            if e.name().is_none() {
+                e.with_name(env.find_name(idx))
+            } else {
+                e
+            }
+        })
+    }
+}
+
+// the function given as an arg
+enum Callee<'a> {
+    Lambda(&'a Expr),
+    Fun(u64, Rc<Function>),
+    Native(u64, &'a NativeFun),
+}
+
+struct FunArg<'a> {
+    callee: Callee<'a>,
+    span: Span,
+}
+
+impl<'a> FunArg<'a> {
+    // a function with `arity` args for `what`, a lambda or a name
+    fn new(env: &'a Env, expr: &'a Expr, arity: usize, what: &str) -> CalcResult<Self> {
+        let span = expr.span();
+        let (callee, has) = match expr {
+            Expr::Group { body, .. } => return FunArg::new(env, body, arity, what),
+            Expr::Lambda { params, body, .. } => (Callee::Lambda(body), Some(params.len())),
+            Expr::Literal {
+                value: Valuable::Var(idx),
+                ..
+            } => {
+                if let Some(f) = env.function(*idx).map_err(|e| e.with_span(span))? {
+                    let arity = f.arity;
+                    (Callee::Fun(*idx, f), Some(arity))
+                } else if let Some(f) = env.builtin.get(idx) {
+                    // a native checks its args itself
+                    (Callee::Native(*idx, f), None)
+                } else {
+                    let name = env.find_name(*idx);
+                    return Err(CalcError::new(
+                        ErrorKind::UnknownName,
+                        format!("can't find function {}", name.unwrap_or("Unknown")),
+                    )
+                    .with_name(name)
+                    .with_span(span));
+                }
+            }
+            _ => {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!("{} need a function like x -> x^2, or the name of one", what),
+                )
+                .with_span(span))
+            }
+        };
+        if let Some(has) = has.filter(|has| *has != arity) {
+            return Err(CalcError::new(
+                ErrorKind::Arity,
+                format!(
+                    "{} need a function with {} arg{}, but get one with {}",
+                    what,
+                    arity,
+                    if arity == 1 { "" } else { "s" },
+                    has
+                ),
+            )
+            .with_span(span));
+        }
+        Ok(FunArg { callee, span })
+    }
+
+    // a lambda also sees the args in scope where it is written
+    fn apply(&self, env: &Env, locals: Option<&[OneMore]>, args: &[f64]) -> CalcResult<f64> {
+        let args: Vec<OneMore> = args.iter().map(|x| OneMore::One(*x)).collect();
+        let v = match &self.callee {
+            Callee::Lambda(body) => {
+                let mut all = locals.map_or_else(Vec::new, |l| l.to_vec());
+                all.extend(args);
+                body.value(env, Some(&all))?
+            }
+            Callee::Fun(idx, f) => env
+                .call(*idx, f, &args)
+                .map_err(|e| e.with_span(self.span))?,
+            Callee::Native(idx, f) => call_native(env, *idx, f, &args, self.span)
+                .map_err(|e| e.with_name(env.find_name(*idx)).with_span(self.span))?,
+        };
+        one(v, self.span)
+    }
+}
+
+// the numbers of a list, a single number is a list of one
+fn numbers(env: &Env, locals: Option<&[OneMore]>, expr: &Expr) -> CalcResult<Vec<f64>> {
+    match expr.value(env, locals)? {
+        OneMore::More(v) => Ok(v),
+        v => Ok(vec![one(v, expr.span())?]),
+    }
+}
+
+// map(f, xs), f on every number
+fn map(env: &Env, locals: Option<&[OneMore]>, args: &[Expr]) -> CalcResult<OneMore> {
+    let f = FunArg::new(env, &args[0], 1, "map")?;
+    let xs = numbers(env, locals, &args[1])?;
+    let mut ys = Vec::with_capacity(xs.len());
+    for x in xs {
+        ys.push(f.apply(env, locals, &[x])?);
+    }
+    Ok(OneMore::More(ys))
+}
+
+// filter(f, xs), the numbers for which f is true
+fn filter(env: &Env, locals: Option<&[OneMore]>, args: &[Expr]) -> CalcResult<OneMore> {
+    let f = FunArg::new(env, &args[0], 1, "filter")?;
+    let mut xs = numbers(env, locals, &args[1])?;
+    let mut keep = Vec::with_capacity(xs.len());
+    for x in &xs {
+        keep.push(truth(f.apply(env, locals, &[*x])?));
+    }
+    let mut keep = keep.into_iter();
+    xs.retain(|_| keep.next().unwrap());
+    Ok(OneMore::More(xs))
+}
+
+// reduce(f, xs) or reduce(f, xs, init), f(f(f(init, x0), x1), ...) from
+// the left, without `init` it starts with x0
+fn reduce(env: &Env, locals: Option<&[OneMore]>, args: &[Expr]) -> CalcResult<OneMore> {
+    let f = FunArg::new(env, &args[0], 2, "reduce")?;
+    let xs = numbers(env, locals, &args[1])?;
+    let (mut acc, rest) = match args.get(2) {
+        Some(init) => (one(init.value(env, locals)?, init.span())?, &xs[..]),
+        None => match xs.split_first() {
+            Some((first, rest)) => (*first, rest),
+            None => {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    "reduce need an initial value for an empty list",
+                )
+                .with_span(args[1].span()))
+            }
+        },
+    };
+    for x in rest {
+        acc = f.apply(env, locals, &[acc, *x])?;
+    }
+    Ok(OneMore::One(acc))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Env, ErrorKind, OneMore, Span};
+
+    fn run(env: &mut Env, s: &str) -> OneMore {
+        env.run(s).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_higher() {
+        let mut env = Env::new();
+        let more = |v: &[f64]| OneMore::More(v.to_vec());
+        assert_eq!(
+            run(&mut env, "map(x -> x^2 + 1, 1..3)"),
+            more(&[2.0, 5.0, 10.0])
+        );
+        assert_eq!(run(&mut env, "map(sqrt, [4, 9])"), more(&[2.0, 3.0]));
+        assert_eq!(
+            run(&mut env, "filter(x -> mod(x, 2) == 0, 1..6)"),
+            more(&[2.0, 4.0, 6.0])
+        );
+        assert_eq!(
+            run(&mut env, "reduce((a, b) -> a * b, 1..5)"),
+            OneMore::One(120.0)
+        );
+        assert_eq!(
+            run(&mut env, "reduce((a b) -> a + b, [], 7)"),
+            OneMore::One(7.0)
+        );
+        assert_eq!(run(&mut env, "map((x) -> x * 2, 3)"), more(&[6.0]));
+
+        // a lambda sees the args of the function it is in, and its own
+        env.run("fun scale(xs, k) = map(x -> x * k, xs)").unwrap();
+        assert_eq!(run(&mut env, "scale([1, 2], 10)"), more(&[10.0, 20.0]));
+        env.run("fun table(n) = map(i -> sum(map(j -> i * j, 1..n)), 1..n)")
+            .unwrap();
+        assert_eq!(run(&mut env, "table(3)"), more(&[6.0, 12.0, 18.0]));
+        assert_eq!(
+            env.show_fn("table").unwrap(),
+            "fun table(n) = map(i -> sum(map(j -> i * j, 1..n)), 1..n)"
+        );
+        // the params shadow the units and the globals
+        env.run("m = 100").unwrap();
+        assert_eq!(run(&mut env, "map(m -> m + 1, [1])"), more(&[2.0]));
+
+        // assigned to a name it is a function
+        env.run("sq = x -> x^2; add = (a, b) -> a + b").unwrap();
+        assert_eq!(
+            run(&mut env, "reduce(add, map(sq, 1..3))"),
+            OneMore::One(14.0)
+        );
+        assert_eq!(env.show_fn("add").unwrap(), "fun add(a b) = a + b");
+        assert!(env.get_var("sq").is_none());
+        // and so is a lambda given to a magic
+        env.run("%root(x -> x^2 - 2, 0, 2)").unwrap();
+        // a derivative is derived from the f of now
+        env.run("fun f(x) = x^2").unwrap();
+        assert_eq!(run(&mut env, "map(f', [1, 2])"), more(&[2.0, 4.0]));
+        env.run("fun f(x) = x^3 - 3 * x").unwrap();
+        assert_eq!(run(&mut env, "filter(f', -2..2)"), more(&[-2.0, 0.0, 2.0]));
+
+        let err = |env: &mut Env, s: &str| env.run(s).unwrap_err();
+        let e = err(&mut env, "map((a, b) -> a, [1])");
+        assert_eq!(e.kind(), ErrorKind::Arity);
+        assert_eq!(
+            e.message(),
+            "map need a function with 1 arg, but get one with 2"
+        );
+        assert_eq!(e.span(), Some(Span::new(4, 15)));
+        assert_eq!(e.name(), Some("map"));
+        assert_eq!(
+            err(&mut env, "map(nope, [1])").kind(),
+            ErrorKind::UnknownName
+        );
+        assert_eq!(err(&mut env, "map(2, [1])").kind(), ErrorKind::Domain);
+        assert_eq!(err(&mut env, "map(x -> x)").kind(), ErrorKind::Arity);
+        assert_eq!(err(&mut env, "reduce(add, [])").kind(), ErrorKind::Domain);
+        assert_eq!(
+            err(&mut env, "map(x -> [x, x], [1])").kind(),
+            ErrorKind::Domain
+        );
+        let e = err(&mut env, "x -> x");
+        assert_eq!(e.kind(), ErrorKind::Domain);
+        assert_eq!(e.span(), Some(Span::new(0, 6)));
+        let e = err(&mut env, "map(x -> y, [1])");
+        assert_eq!(e.kind(), ErrorKind::UnknownName);
+        assert_eq!(e.span(), Some(Span::new(9, 10)));
+        assert_eq!(err(&mut env, "map(x -> , [1])").kind(), ErrorKind::Parse);
+
+        // a fun with the same name shadows it
+        env.run("fun map(a, b) = a + b").unwrap();
+        assert_eq!(run(&mut env, "map(1, 2)"), OneMore::One(3.0));
+    }
+}
@@ -1,30 +1,65 @@
 use std::collections::HashMap;
+use std::iter::Peekable;
 
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+use crate::error::Span;
+use crate::exact;
 use crate::utils;
 
+// the exponent of a number like '1e300' has at most this many digits
+const MAX_EXPONENT_DIGITS: usize = 4;
+
 #[derive(Clone, Debug)]
 pub(crate) enum Token {
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+    DotDot,
     Plus,
     Minus,
     Star,
     Slash,
+    At,
     Bang,
     Square,
     Comma,
     Eq,
     Percent,
+    Semi, // ';' or a new line, the end of a statement
+    Less,
+    LessEq,
+    EqEq,
+    BangEq,
+    Greater,
+    GreaterEq,
+    AndAnd,
+    OrOr,
+    Question,
+    Colon,
+    Arrow, // '->'
     Fun,
-    Number(f64),
+    Memo,
+    If,
+    Let,
+    In, // 'in' or 'to'
+    Diff,
+    Number(f64, Box<BigRational>), // the float and the exact value
+    Imag(f64),
     Ident(u64),
     Unknown(u64),
     Eof,
 }
 
 pub(crate) struct Scanner<T: Iterator<Item = char>> {
-    source: T,
+    source: Peekable<T>,
     next: Option<char>,
+    after: Option<char>, // the char after next, only for '1..2' and '1e-3'
+    pos: usize,          // byte offset of the next char
     kw: HashMap<&'static str, Token>,
     namespace: HashMap<u64, String>,
 }
@@ -32,21 +67,35 @@ pub(crate) struct Scanner<T: Iterator<Item = char>> {
 impl<T: Iterator<Item = char>> Scanner<T> {
     pub(crate) fn new(source: T) -> Self {
         let mut scanner = Scanner {
-            source,
+            source: source.peekable(),
             next: None,
-            kw: HashMap::from([("fun", Token::Fun)]),
+            after: None,
+            pos: 0,
+            kw: HashMap::from([
+                ("fun", Token::Fun),
+                ("memo", Token::Memo),
+                ("if", Token::If),
+                ("let", Token::Let),
+                ("in", Token::In),
+                ("to", Token::In),
+                ("diff", Token::Diff),
+            ]),
             namespace: HashMap::new(),
         };
+        scanner.after = scanner.source.next();
         scanner.eat();
         scanner
     }
 
-    pub(crate) fn scan(&mut self) -> Vec<Token> {
+    pub(crate) fn scan(&mut self) -> Vec<(Token, Span)> {
         let mut tokens = Vec::with_capacity(16);
-        while let Some(t) = self.scan_token() {
-            tokens.push(t);
+        let mut end = 0;
+        while let Some((t, span)) = self.scan_token() {
+            end = span.end;
+            tokens.push((t, span));
         }
-        tokens.push(Token::Eof);
+        // the Eof sticks to the last token, not to the trailing spaces
+        tokens.push((Token::Eof, Span::new(end, end)));
         tokens
     }
 
@@ -54,28 +103,51 @@ impl<T: Iterator<Item = char>> Scanner<T> {
         std::mem::take(&mut self.namespace)
     }
 
-    fn scan_token(&mut self) -> Option<Token> {
+    fn scan_token(&mut self) -> Option<(Token, Span)> {
         self.skip_space();
-        self.skip_comment();
-        let c = self.next.take()?;
+        let start = self.pos;
+        let c = self.next?;
         self.eat();
 
         if utils::is_identifier_start(c) {
-            return Some(self.ident_or_kw(c));
+            let token = self.ident_or_kw(c);
+            return Some((token, Span::new(start, self.pos)));
         }
 
         let token = match c {
             '(' => Token::LeftParen,
             ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            '.' if self.next == Some('.') => {
+                self.eat();
+                Token::DotDot
+            }
             '+' => Token::Plus,
-            '-' => Token::Minus,
+            '-' => self.either('>', Token::Arrow, Token::Minus),
             '*' => Token::Star,
             '/' => Token::Slash,
-            '!' => Token::Bang,
+            '@' => Token::At,
+            '!' => self.either('=', Token::BangEq, Token::Bang),
             '^' => Token::Square,
-            '=' => Token::Eq,
+            '=' => self.either('=', Token::EqEq, Token::Eq),
+            '<' => self.either('=', Token::LessEq, Token::Less),
+            '>' => self.either('=', Token::GreaterEq, Token::Greater),
+            '&' | '|' if self.next == Some(c) => {
+                self.eat();
+                if c == '&' {
+                    Token::AndAnd
+                } else {
+                    Token::OrOr
+                }
+            }
+            '?' => Token::Question,
+            ':' => Token::Colon,
             ',' => Token::Comma,
             '%' => Token::Percent,
+            ';' | '\n' => Token::Semi,
             '0'..='9' => self.number(c),
             c => {
                 let hash = self.get_hash(&c.to_string());
@@ -83,7 +155,17 @@ impl<T: Iterator<Item = char>> Scanner<T> {
             }
         };
 
-        Some(token)
+        Some((token, Span::new(start, self.pos)))
+    }
+
+    // for two-char operators like '<=', `second` is the char after the first
+    fn either(&mut self, second: char, long: Token, short: Token) -> Token {
+        if self.next == Some(second) {
+            self.eat();
+            long
+        } else {
+            short
+        }
     }
 
     fn ident_or_kw(&mut self, start: char) -> Token {
@@ -99,6 +181,12 @@ impl<T: Iterator<Item = char>> Scanner<T> {
             }
         }
 
+        // the derivative f' of f is a name of its own
+        while self.next == Some('\'') {
+            lexeme.push('\'');
+            self.eat();
+        }
+
         if self.kw.contains_key(lexeme.as_str()) {
             let kw = self.kw.get(lexeme.as_str()).unwrap();
             return kw.clone();
@@ -110,18 +198,25 @@ impl<T: Iterator<Item = char>> Scanner<T> {
     }
 
     fn number(&mut self, start: char) -> Token {
-        let mut lexeme = String::with_capacity(4);
-        lexeme.push(start);
-        while let Some(c) = self.next {
-            if utils::is_number(c) {
-                lexeme.push(c);
+        // '0x1f', '0b101' and '0o17', but '0b' alone is 0 and the name b
+        if let (Some(prefix @ ('x' | 'b' | 'o')), Some(c)) = (self.next, self.after) {
+            let radix = match prefix {
+                'x' => 16,
+                'b' => 2,
+                _ => 8,
+            };
+            if start == '0' && c.is_digit(radix) {
                 self.eat();
-            } else {
-                break;
+                return self.integer(radix);
             }
         }
 
-        if let Some('.') = self.next {
+        let mut lexeme = String::with_capacity(4);
+        lexeme.push(start);
+        self.digits(&mut lexeme);
+
+        // '1..2' is a range, not '1.' and '.2'
+        if let (Some('.'), false) = (self.next, self.after == Some('.')) {
             self.eat();
             lexeme.push('.');
             if let Some(c) = self.next {
@@ -131,18 +226,68 @@ impl<T: Iterator<Item = char>> Scanner<T> {
                 }
             }
 
-            while let Some(c) = self.next {
-                if utils::is_number(c) {
-                    lexeme.push(c);
+            self.digits(&mut lexeme);
+        }
+
+        // '1e-3', but '2e' is 2 and the name e
+        if let Some(e @ ('e' | 'E')) = self.next {
+            let sign = matches!(self.after, Some('+' | '-'));
+            let digit = if sign {
+                self.source.peek().copied()
+            } else {
+                self.after
+            };
+            if digit.is_some_and(utils::is_number) {
+                self.eat();
+                lexeme.push(e);
+                if sign {
+                    lexeme.push(self.next.unwrap());
                     self.eat();
-                } else {
-                    break;
+                }
+                let start = lexeme.len();
+                self.digits(&mut lexeme);
+                // the exact value of '1e99999' is already too big
+                if lexeme.len() - start > MAX_EXPONENT_DIGITS {
+                    let hash = self.get_hash(&lexeme);
+                    return Token::Unknown(hash);
                 }
             }
         }
 
         let value = lexeme.parse().unwrap();
-        Token::Number(value)
+        // '4i' is imaginary, but '4in' is a number and a name
+        if self.next == Some('i') && !self.after.is_some_and(utils::is_identifier_continue) {
+            self.eat();
+            return Token::Imag(value);
+        }
+        Token::Number(value, Box::new(exact::parse_decimal(&lexeme)))
+    }
+
+    // the digits of an integer after its '0x', '0b' or '0o'
+    fn integer(&mut self, radix: u32) -> Token {
+        let mut lexeme = String::with_capacity(8);
+        while let Some(c) = self.next {
+            if c.is_digit(radix) {
+                lexeme.push(c);
+                self.eat();
+            } else {
+                break;
+            }
+        }
+        let value = BigInt::parse_bytes(lexeme.as_bytes(), radix).unwrap();
+        let value = BigRational::from_integer(value);
+        Token::Number(exact::to_f64(&value), Box::new(value))
+    }
+
+    fn digits(&mut self, lexeme: &mut String) {
+        while let Some(c) = self.next {
+            if utils::is_number(c) {
+                lexeme.push(c);
+                self.eat();
+            } else {
+                break;
+            }
+        }
     }
 
     fn get_hash(&mut self, lexme: &str) -> u64 {
@@ -151,30 +296,26 @@ impl<T: Iterator<Item = char>> Scanner<T> {
         hash
     }
 
+    // the new line is a token, so a comment stops right before it
     fn skip_space(&mut self) {
+        let mut in_comment = false;
         while let Some(c) = self.next {
             match c {
-                ' ' | '\t' | '\r' | '\n' => self.eat(),
+                '\n' => break,
+                '#' => in_comment = true,
+                ' ' | '\t' | '\r' => {}
+                _ if in_comment => {}
                 _ => break,
             }
-        }
-    }
-
-    fn skip_comment(&mut self) {
-        if Some('#') == self.next {
-            while let Some(c) = self.next {
-                match c {
-                    '\n' => {
-                        self.eat();
-                        break;
-                    }
-                    _ => self.eat(),
-                }
-            }
+            self.eat();
         }
     }
 
     fn eat(&mut self) {
-        self.next = self.source.next();
+        if let Some(c) = self.next {
+            self.pos += c.len_utf8();
+        }
+        self.next = self.after.take();
+        self.after = self.source.next();
     }
 }
@@ -1,9 +1,35 @@
 mod ast;
+mod builtin;
+mod compile;
+mod complex;
+mod diff;
 mod env;
+mod error;
+mod exact;
+mod format;
+mod higher;
 mod lexer;
+mod magic;
+mod matrix;
 mod onemore;
 mod parser;
+mod plot;
+mod random;
+mod show;
+mod solve;
+mod stats;
+mod units;
 mod utils;
 
-// only export the Env
-pub use env::Env;
+// only export the Env and what it gives back
+pub use builtin::Arity;
+pub use compile::CompiledExpr;
+pub use env::{Env, FunctionInfo, Mode, Output, Prepared, DEFAULT_MAX_DEPTH};
+pub use error::{CalcError, ErrorKind, Span};
+pub use format::Notation;
+pub use magic::{Magic, MagicArg, MagicArgs};
+pub use matrix::Matrix;
+pub use num_complex::Complex64;
+pub use num_rational::BigRational;
+pub use onemore::OneMore;
+pub use units::Quantity;
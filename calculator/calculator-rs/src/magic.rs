@@ -0,0 +1,517 @@
+// Magic commands like `%plot2d(f, 0, 1)`, the builtin ones and the ones a
+// host registers with `Env::register_magic`.
+use std::io::{self, IsTerminal};
+use std::rc::Rc;
+
+use crate::{
+    builtin::MAX_LIST_LEN,
+    env::{one, Env, Mode, Output},
+    error::{CalcError, CalcResult, ErrorKind, Span},
+    format::{Notation, MAX_DIGITS},
+    onemore::OneMore,
+    plot, solve,
+};
+
+/// How a magic takes one of its args.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MagicArg {
+    /// A name, like the function in `%root(f, 0, 1)`.
+    Idx,
+    /// A name, or some names in `[` `]`.
+    Idxs,
+    /// An expression, evaluated before the magic runs.
+    Expr,
+    /// An expression which can be left out, only at the end.
+    OptExpr,
+}
+
+/// A magic command, run by `%name(args)`.
+///
+/// ```
+/// use calculator::{CalcError, Env, Magic, MagicArg, MagicArgs, OneMore, Output};
+///
+/// struct Twice;
+///
+/// impl Magic for Twice {
+///     fn args(&self) -> Vec<MagicArg> {
+///         vec![MagicArg::Expr]
+///     }
+///
+///     fn run(&self, _env: &mut Env, args: &MagicArgs) -> Result<Option<Output>, CalcError> {
+///         Ok(Some(OneMore::One(args.real(0)? * 2.0).into()))
+///     }
+/// }
+///
+/// let mut env = Env::new();
+/// env.register_magic("twice", Twice);
+/// assert_eq!(env.run("%twice(1 + 2)"), Ok(Some(OneMore::One(6.0))));
+/// ```
+pub trait Magic {
+    /// How the args are parsed, in order.
+    fn args(&self) -> Vec<MagicArg>;
+
+    /// Run the magic, its output is given to the host like the value of an
+    /// expression. An error without a span points at the whole magic.
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> Result<Option<Output>, CalcError>;
+}
+
+pub(crate) enum Given {
+    Names(Vec<String>),
+    Value(OneMore),
+    Omitted,
+}
+
+/// The args given to a magic, in the order of its `MagicArg`s.
+///
+/// The getters panic when the arg `i` doesn't match them, like `name` on an
+/// `Expr`, since the magic itself decides how its args are parsed.
+pub struct MagicArgs {
+    pub(crate) args: Vec<(Given, Span)>,
+    pub(crate) span: Span,
+}
+
+impl MagicArgs {
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// The name given to an `Idx`, or the first name of an `Idxs`.
+    pub fn name(&self, i: usize) -> &str {
+        &self.names(i)[0]
+    }
+
+    /// The names given to an `Idxs`, one for an `Idx`.
+    pub fn names(&self, i: usize) -> &[String] {
+        match &self.args[i].0 {
+            Given::Names(names) => names,
+            _ => panic!("arg {} of the magic is not a name", i),
+        }
+    }
+
+    /// The value of an `Expr` or `OptExpr`, `None` when it is left out.
+    pub fn value(&self, i: usize) -> Option<&OneMore> {
+        match &self.args[i].0 {
+            Given::Value(v) => Some(v),
+            Given::Omitted => None,
+            Given::Names(_) => panic!("arg {} of the magic is not an expression", i),
+        }
+    }
+
+    /// The value of an `Expr` which must be a real number.
+    pub fn real(&self, i: usize) -> Result<f64, CalcError> {
+        match self.value(i) {
+            Some(v) => one(v.clone(), self.arg_span(i)),
+            None => panic!("arg {} of the magic is left out", i),
+        }
+    }
+
+    /// The span of the arg `i`.
+    pub fn arg_span(&self, i: usize) -> Span {
+        self.args[i].1
+    }
+
+    /// The span of the whole magic, from the `%`.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+pub(crate) fn builtins() -> Vec<(&'static str, Rc<dyn Magic>)> {
+    vec![
+        ("plot2d", Rc::new(Plot2d)),
+        ("plot_param", Rc::new(PlotParam)),
+        ("plot_polar", Rc::new(PlotPolar)),
+        ("plot_implicit", Rc::new(PlotImplicit)),
+        ("root", Rc::new(Root)),
+        ("integrate", Rc::new(Integrate)),
+        ("minimize", Rc::new(Minimize)),
+        ("solve", Rc::new(Solve)),
+        ("mode", Rc::new(SetMode)),
+        ("complex", Rc::new(ComplexForm)),
+        ("format", Rc::new(Format)),
+        ("sep", Rc::new(Separators)),
+    ]
+}
+
+// the args `i` and `i + 1`, a finite range with the start less than the end
+fn range(args: &MagicArgs, i: usize, magic: &str) -> CalcResult<(f64, f64)> {
+    let (x0, x1) = (args.real(i)?, args.real(i + 1)?);
+    if !x0.is_finite() || !x1.is_finite() || x0 >= x1 {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "{} need a finite start less than the end, but get {} and {}",
+                magic, x0, x1
+            ),
+        )
+        .with_span(args.arg_span(i).to(args.arg_span(i + 1))));
+    }
+    Ok((x0, x1))
+}
+
+// the points to plot in the range at `i`, and the optional step after it,
+// one for each dot across the terminal by default
+fn samples(args: &MagicArgs, i: usize, magic: &str) -> CalcResult<Vec<f64>> {
+    let (x0, x1) = range(args, i, magic)?;
+    let n = match args.value(i + 2) {
+        Some(_) => {
+            let step = args.real(i + 2)?;
+            if step <= 0.0 || !step.is_finite() {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!("the step of {} must be positive, but get {}", magic, step),
+                )
+                .with_span(args.arg_span(i + 2)));
+            }
+            ((x1 - x0) / step).floor().clamp(1.0, MAX_LIST_LEN as f64) as usize
+        }
+        None => plot::terminal_size().0 * 2,
+    };
+    // the last one is exactly the end
+    Ok((0..=n)
+        .map(|i| {
+            let t = i as f64 / n as f64;
+            x0 * (1.0 - t) + x1 * t
+        })
+        .collect())
+}
+
+fn draw(
+    curves: Vec<plot::Curve>,
+    x_range: Option<(f64, f64)>,
+    y_range: Option<(f64, f64)>,
+    equal: bool,
+) -> CalcResult<Option<Output>> {
+    let (columns, lines) = plot::terminal_size();
+    let figure = plot::Figure {
+        curves,
+        x_range,
+        y_range,
+        equal,
+        columns,
+        lines,
+        color: io::stdout().is_terminal(),
+    };
+    Ok(Some(Output::Text(figure.render()?)))
+}
+
+// %plot2d(f, start, end) or %plot2d([f, g], start, end, step)
+struct Plot2d;
+
+impl Magic for Plot2d {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![
+            MagicArg::Idxs,
+            MagicArg::Expr,
+            MagicArg::Expr,
+            MagicArg::OptExpr,
+        ]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let env = &*env;
+        let fs = args
+            .names(0)
+            .iter()
+            .map(|name| env.numeric_fn(name, 1, "plot2d", args.span()))
+            .collect::<CalcResult<Vec<_>>>()?;
+        let xs = samples(args, 1, "plot2d")?;
+        let mut curves = Vec::new();
+        for (name, f) in args.names(0).iter().zip(fs) {
+            let points = xs
+                .iter()
+                .map(|&x| Ok((x, f(&[x])?)))
+                .collect::<CalcResult<_>>()?;
+            let name = name.clone();
+            curves.push(plot::Curve { name, points });
+        }
+        let x_range = (xs[0], xs[xs.len() - 1]);
+        draw(curves, Some(x_range), None, false)
+    }
+}
+
+// %plot_param(fx, fy, t0, t1[, step]) draws (fx(t), fy(t))
+struct PlotParam;
+
+impl Magic for PlotParam {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![
+            MagicArg::Idx,
+            MagicArg::Idx,
+            MagicArg::Expr,
+            MagicArg::Expr,
+            MagicArg::OptExpr,
+        ]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let env = &*env;
+        let fx = env.numeric_fn(args.name(0), 1, "plot_param", args.span())?;
+        let fy = env.numeric_fn(args.name(1), 1, "plot_param", args.span())?;
+        let points = samples(args, 2, "plot_param")?
+            .into_iter()
+            .map(|t| Ok((fx(&[t])?, fy(&[t])?)))
+            .collect::<CalcResult<_>>()?;
+        let name = String::new();
+        draw(vec![plot::Curve { name, points }], None, None, true)
+    }
+}
+
+// %plot_polar(r, theta0, theta1[, step]) draws r(theta)
+struct PlotPolar;
+
+impl Magic for PlotPolar {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![
+            MagicArg::Idx,
+            MagicArg::Expr,
+            MagicArg::Expr,
+            MagicArg::OptExpr,
+        ]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let env = &*env;
+        let r = env.numeric_fn(args.name(0), 1, "plot_polar", args.span())?;
+        let points = samples(args, 1, "plot_polar")?
+            .into_iter()
+            .map(|t| r(&[t]).map(|r| (r * t.cos(), r * t.sin())))
+            .collect::<CalcResult<_>>()?;
+        let name = String::new();
+        draw(vec![plot::Curve { name, points }], None, None, true)
+    }
+}
+
+// %plot_implicit(f, xmin, xmax, ymin, ymax) draws f(x, y) = 0
+struct PlotImplicit;
+
+impl Magic for PlotImplicit {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![
+            MagicArg::Idx,
+            MagicArg::Expr,
+            MagicArg::Expr,
+            MagicArg::Expr,
+            MagicArg::Expr,
+        ]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let env = &*env;
+        let f = env.numeric_fn(args.name(0), 2, "plot_implicit", args.span())?;
+        let x_range = range(args, 1, "plot_implicit")?;
+        let y_range = range(args, 3, "plot_implicit")?;
+        // about a cell for each dot of the terminal
+        let (columns, lines) = plot::terminal_size();
+        let grid = (columns * 2, lines * 4);
+        let points = plot::contour(|x, y| f(&[x, y]), x_range, y_range, grid)?;
+        if points.is_empty() {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                "nothing to plot, the function is never 0 in the range",
+            ));
+        }
+        let name = args.name(0).to_owned();
+        let curves = vec![plot::Curve { name, points }];
+        draw(curves, Some(x_range), Some(y_range), false)
+    }
+}
+
+// %root(f, a, b)
+struct Root;
+
+impl Magic for Root {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx, MagicArg::Expr, MagicArg::Expr]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let f = env.numeric_fn(args.name(0), 1, "root", args.span())?;
+        let x = solve::root(|x| f(&[x]), args.real(1)?, args.real(2)?)?;
+        Ok(Some(OneMore::One(x).into()))
+    }
+}
+
+// %integrate(f, a, b) gives the area and its error
+struct Integrate;
+
+impl Magic for Integrate {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx, MagicArg::Expr, MagicArg::Expr]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let f = env.numeric_fn(args.name(0), 1, "integrate", args.span())?;
+        let (v, err) = solve::integrate(|x| f(&[x]), args.real(1)?, args.real(2)?)?;
+        Ok(Some(OneMore::More(vec![v, err]).into()))
+    }
+}
+
+// %minimize(f, a, b) gives x and f(x)
+struct Minimize;
+
+impl Magic for Minimize {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx, MagicArg::Expr, MagicArg::Expr]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let f = env.numeric_fn(args.name(0), 1, "minimize", args.span())?;
+        let (x, y) = solve::minimize(|x| f(&[x]), args.real(1)?, args.real(2)?)?;
+        Ok(Some(OneMore::More(vec![x, y]).into()))
+    }
+}
+
+// %solve(f, g, x, y) gives x and y where f and g are 0
+struct Solve;
+
+impl Magic for Solve {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx, MagicArg::Idx, MagicArg::Expr, MagicArg::Expr]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let env = &*env;
+        let f = env.numeric_fn(args.name(0), 2, "solve", args.span())?;
+        let g = env.numeric_fn(args.name(1), 2, "solve", args.span())?;
+        let (x, y) = solve::solve(
+            |x, y| Ok((f(&[x, y])?, g(&[x, y])?)),
+            args.real(2)?,
+            args.real(3)?,
+        )?;
+        Ok(Some(OneMore::More(vec![x, y]).into()))
+    }
+}
+
+// the option named by the arg of a magic like `%mode(exact)`
+fn choose<T: Copy>(args: &MagicArgs, what: &str, options: &[(&str, T)]) -> CalcResult<T> {
+    let name = args.name(0);
+    if let Some((_, v)) = options.iter().find(|(option, _)| *option == name) {
+        return Ok(*v);
+    }
+    let names: Vec<&str> = options.iter().map(|(name, _)| *name).collect();
+    Err(CalcError::new(
+        ErrorKind::UnknownName,
+        format!("unknown {} {}, expect {}", what, name, names.join(" or ")),
+    )
+    .with_name(Some(name)))
+}
+
+// %mode(exact) or %mode(float)
+struct SetMode;
+
+impl Magic for SetMode {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let modes = [("float", Mode::Float), ("exact", Mode::Exact)];
+        env.set_mode(choose(args, "mode", &modes)?);
+        Ok(None)
+    }
+}
+
+// %complex(polar) or %complex(rect)
+struct ComplexForm;
+
+impl Magic for ComplexForm {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let forms = [("rect", false), ("polar", true)];
+        env.set_polar(choose(args, "complex form", &forms)?);
+        Ok(None)
+    }
+}
+
+// %format(plain), %format(fix, 2), %format(hex) ...
+struct Format;
+
+impl Magic for Format {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx, MagicArg::OptExpr]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        // the ones with digits, and how many by default
+        let digits = [
+            ("fix", Notation::Fixed as fn(usize) -> Notation, 2),
+            ("sig", Notation::Sig, 6),
+            ("sci", Notation::Sci, 6),
+            ("eng", Notation::Eng, 6),
+        ];
+        let plain = [
+            ("plain", Notation::Plain),
+            ("hex", Notation::Hex),
+            ("bin", Notation::Bin),
+            ("oct", Notation::Oct),
+        ];
+        let name = args.name(0);
+        let notation = match digits.iter().find(|(option, ..)| *option == name) {
+            Some((_, notation, default)) => {
+                let n = match args.value(1) {
+                    Some(_) => args.real(1)?,
+                    None => *default as f64,
+                };
+                let min = if name == "sig" { 1.0 } else { 0.0 };
+                if n.fract() != 0.0 || !(min..=MAX_DIGITS as f64).contains(&n) {
+                    return Err(CalcError::new(
+                        ErrorKind::Domain,
+                        format!(
+                            "the digits of {} must be an integer from {} to {}, but get {}",
+                            name, min, MAX_DIGITS, n
+                        ),
+                    )
+                    .with_span(args.arg_span(1)));
+                }
+                notation(n as usize)
+            }
+            None => {
+                let Some((_, notation)) = plain.iter().find(|(option, _)| *option == name) else {
+                    let names: Vec<&str> = plain
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .chain(digits.iter().map(|(name, ..)| *name))
+                        .collect();
+                    return Err(CalcError::new(
+                        ErrorKind::UnknownName,
+                        format!("unknown format {}, expect {}", name, names.join(" or ")),
+                    )
+                    .with_name(Some(name)));
+                };
+                if args.value(1).is_some() {
+                    return Err(CalcError::new(
+                        ErrorKind::Arity,
+                        format!("the format {} takes no digits", name),
+                    )
+                    .with_span(args.arg_span(1)));
+                }
+                *notation
+            }
+        };
+        env.set_notation(notation);
+        Ok(None)
+    }
+}
+
+// %sep(on) or %sep(off)
+struct Separators;
+
+impl Magic for Separators {
+    fn args(&self) -> Vec<MagicArg> {
+        vec![MagicArg::Idx]
+    }
+
+    fn run(&self, env: &mut Env, args: &MagicArgs) -> CalcResult<Option<Output>> {
+        let options = [("off", false), ("on", true)];
+        env.set_separators(choose(args, "separator option", &options)?);
+        Ok(None)
+    }
+}
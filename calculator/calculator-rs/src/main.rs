@@ -1,27 +1,331 @@
-use std::io::{self, Write};
+use std::path::PathBuf;
+use std::{env, fs, process, thread};
 
-use calculator::Env;
+use calculator::{Env, Mode, Output};
+use rustyline::completion::{Completer, FilenameCompleter};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+
+const USAGE: &str = "usage: calculator [--exact] [FILE | -e EXPR]
+    FILE       run the statements in FILE, one per line or separated by ';'
+    -e EXPR    run EXPR and print the result
+    --exact    compute with exact rationals, like `%mode(exact)`
+without arguments, start the REPL, with history in ~/.calculator_history";
+
+const COMMANDS: &str = "commands:
+    :vars         list the variables
+    :funcs        list the functions defined by `fun`, then the builtin ones
+    :del NAME     remove a variable or a function defined by `fun`
+    :reset        remove everything defined, back to the start
+    :save FILE    write the variables and the functions to FILE
+    :load FILE    run the statements in FILE
+    :help         show this
+the last result is in `ans` and `_`";
+
+// the commands to complete
+const COMMAND_NAMES: [&str; 7] = [
+    ":vars", ":funcs", ":del", ":reset", ":save", ":load", ":help",
+];
+
+// user functions can recurse as deep as this, the stack of the main thread
+// is too small for it, so everything runs in a thread with a big stack
+const MAX_DEPTH: usize = 10_000;
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+// in the home directory
+const HISTORY_FILE: &str = ".calculator_history";
 
 fn main() {
+    let worker = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(start)
+        .expect("fail to start the calculator");
+    if worker.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn new_env(mode: Mode) -> Env {
     let mut env = Env::new();
-    loop {
-        // prompt
-        print!(">>> ");
-        io::stdout().flush().expect("flush error");
+    env.set_max_depth(MAX_DEPTH);
+    env.set_mode(mode);
+    env
+}
+
+fn start() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mode = if args.first().is_some_and(|x| x == "--exact") {
+        args.remove(0);
+        Mode::Exact
+    } else {
+        Mode::Float
+    };
+    match args.iter().map(|x| x.as_str()).collect::<Vec<_>>()[..] {
+        [] => repl(mode),
+        ["-h"] | ["--help"] => println!("{}", USAGE),
+        ["-e", expr] => run(mode, None, expr),
+        [path] if !path.starts_with('-') => match fs::read_to_string(path) {
+            Ok(source) => run(mode, Some(path), &source),
+            Err(e) => {
+                eprintln!("can't read {}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
 
-        let mut line = String::with_capacity(8);
-        io::stdin()
-            .read_line(&mut line)
-            .expect("fail to read input");
+// run a script or a one-shot expression, print every result
+fn run(mode: Mode, path: Option<&str>, source: &str) {
+    let mut env = new_env(mode);
+    if let Err(e) = env.run_each(source, print_output) {
+        match path {
+            Some(path) => eprintln!("{}", e.render_file(path, source)),
+            None => eprintln!("{}", e.render(source)),
+        }
+        process::exit(1);
+    }
+}
+
+fn print_output(env: &Env, output: Output) {
+    match output {
+        Output::Value(v) => println!("{}", env.format(&v)),
+        Output::Definition(s) | Output::Text(s) => println!("{}", s),
+    }
+}
+
+// what the REPL can complete, taken from the env before each line
+#[derive(Default)]
+struct Names {
+    // variables, and functions and magics with their '('
+    names: Vec<String>,
+    // the files of `:save` and `:load`
+    files: FilenameCompleter,
+}
+
+impl Names {
+    fn update(&mut self, env: &Env) {
+        let vars = env.vars().into_iter().map(|(name, _)| name.to_owned());
+        let functions = env.functions().into_iter().map(|f| format!("{}(", f.name));
+        let magics = env.magics().into_iter().map(|name| format!("%{}(", name));
+        self.names = vars.chain(functions).chain(magics).collect();
+        self.names.sort();
+        self.names.dedup();
+    }
+}
+
+impl Completer for Names {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        if line.starts_with(':') {
+            return match line[..pos].split_once(' ') {
+                Some((":save" | ":load", _)) => {
+                    let (start, files) = self.files.complete_path(line, pos)?;
+                    Ok((start, files.into_iter().map(|f| f.replacement).collect()))
+                }
+                Some(_) => Ok((pos, Vec::new())),
+                None => {
+                    let word = &line[..pos];
+                    let commands = COMMAND_NAMES.iter().filter(|c| c.starts_with(word));
+                    Ok((0, commands.map(|c| c.to_string()).collect()))
+                }
+            };
+        }
+        // the name before the cursor, with the '%' of a magic
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+            .map_or(0, |i| i + 1);
+        let start = if line[..start].ends_with('%') {
+            start - 1
+        } else {
+            start
+        };
+        let word = &line[start..pos];
+        if word.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+        let names = self
+            .names
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect();
+        Ok((start, names))
+    }
+}
+
+impl Hinter for Names {
+    type Hint = String;
+}
+
+impl Highlighter for Names {}
+
+// a block left open goes on in the next line
+impl Validator for Names {
+    fn validate(&self, ctx: &mut ValidationContext<'_>) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if !input.starts_with(':') && input.matches('{').count() > input.matches('}').count() {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for Names {}
+
+// the history is kept in ~/.calculator_history
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+fn repl(mode: Mode) {
+    let mut env = new_env(mode);
+    // complete the common part first, then list the names like a shell
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .build();
+    let mut editor = match Editor::<Names, DefaultHistory>::with_config(config) {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("can't start the line editor: {}", e);
+            process::exit(1);
+        }
+    };
+    editor.set_helper(Some(Names::default()));
+    let history = history_path();
+    if let Some(path) = &history {
+        // there is no history the first time
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        if let Some(names) = editor.helper_mut() {
+            names.update(&env);
+        }
+        let line = match editor.readline(">>> ") {
+            Ok(line) => line,
+            // Ctrl-C drops the line
+            Err(ReadlineError::Interrupted) => continue,
+            // end of input (Ctrl-D or the end of a pipe)
+            Err(ReadlineError::Eof) => {
+                println!();
+                break;
+            }
+            Err(e) => {
+                eprintln!("fail to read input: {}", e);
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
 
         // exit
-        if line == "exit\n" || line == "exit" || line == "exit()" || line == "exit()\n" {
+        if line.trim() == "exit" || line.trim() == "exit()" {
             break;
         }
 
+        // commands
+        if let Some(command) = line.trim().strip_prefix(':') {
+            run_command(&mut env, mode, command);
+            continue;
+        }
+
         // run
-        if let Some(v) = env.run(&line) {
-            println!("{}", v);
+        let mut last = None;
+        let result = env.run_each(&line, |env, output| {
+            if let Output::Value(v) = &output {
+                last = Some(v.clone());
+            }
+            print_output(env, output);
+        });
+        match result {
+            Ok(()) => {
+                if let Some(v) = last {
+                    env.set_ans(v);
+                }
+            }
+            Err(e) => println!("{}", e.render(&line)),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("can't save the history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+// `:command args` in the REPL, without the ':'
+fn run_command(env: &mut Env, mode: Mode, command: &str) {
+    let (name, arg) = match command.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+    match (name, arg) {
+        ("vars", "") => {
+            for (name, value) in env.vars() {
+                println!("{} = {}", name, env.format(value));
+            }
+        }
+        ("funcs", "") => {
+            let (user, builtin): (Vec<_>, Vec<_>) =
+                env.functions().into_iter().partition(|f| !f.builtin);
+            for f in user {
+                if let Some(fun) = env.show_fn(&f.name) {
+                    println!("{}", fun);
+                }
+            }
+            let names: Vec<_> = builtin.into_iter().map(|f| f.name).collect();
+            println!("builtin: {}", names.join(", "));
+        }
+        ("del", name) if !name.is_empty() => {
+            // a variable and a function can have the same name, a builtin
+            // is only listed if no `fun` shadows it, and it stays
+            let var = env.remove_var(name).is_some();
+            let builtin = env.functions().iter().any(|f| f.name == name && f.builtin);
+            if builtin {
+                if !var {
+                    println!("{} is a builtin function, it can't be removed", name);
+                }
+            } else if !(env.remove_fn(name) || var) {
+                println!("nothing named {}", name);
+            }
+        }
+        ("reset", "") => *env = new_env(mode),
+        ("save", path) if !path.is_empty() => {
+            if let Err(e) = fs::write(path, env.to_source()) {
+                println!("can't write {}: {}", path, e);
+            }
+        }
+        ("load", path) if !path.is_empty() => match fs::read_to_string(path) {
+            Ok(source) => {
+                if let Err(e) = env.run_each(&source, print_output) {
+                    println!("{}", e.render_file(path, &source));
+                }
+            }
+            Err(e) => println!("can't read {}: {}", path, e),
+        },
+        ("help", "") => println!("{}", COMMANDS),
+        _ if COMMAND_NAMES.contains(&format!(":{}", name).as_str()) => {
+            println!("wrong args for :{}, try :help", name)
         }
+        _ => println!("unknown command :{}, try :help", name),
     }
 }
@@ -0,0 +1,768 @@
+// Matrices of real numbers, made by literals like `[[1, 2], [3, 4]]`, and
+// the linear algebra on them. A list goes with a matrix as a vector.
+use crate::{
+    ast::BinaryOp,
+    env::boolean,
+    error::{CalcError, CalcResult, ErrorKind},
+    onemore::OneMore,
+};
+
+// `eig` gives up after this many sweeps, it takes less than 10 on any
+// matrix small enough to type
+const MAX_SWEEPS: usize = 100;
+
+/// A matrix of real numbers, with at least one row and one column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    // row by row
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    pub(crate) fn new(rows: usize, cols: usize, data: Vec<f64>) -> Matrix {
+        debug_assert_eq!(rows * cols, data.len());
+        Matrix { rows, cols, data }
+    }
+
+    /// The rows of a literal, all as long as each other.
+    pub(crate) fn from_rows(rows: Vec<Vec<f64>>) -> CalcResult<Matrix> {
+        let cols = rows[0].len();
+        if let Some(row) = rows.iter().find(|row| row.len() != cols) {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "the rows of a matrix must be as long as each other, but get {} and {}",
+                    cols,
+                    row.len()
+                ),
+            ));
+        }
+        if cols == 0 {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                "a matrix must have at least one column",
+            ));
+        }
+        Ok(Matrix::new(rows.len(), cols, rows.concat()))
+    }
+
+    fn identity(n: usize) -> Matrix {
+        let mut data = vec![0.0; n * n];
+        for i in 0..n {
+            data[i * n + i] = 1.0;
+        }
+        Matrix::new(n, n, data)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// The number in row `i` and column `j`, both from 0.
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.data[i * self.cols + j]
+    }
+
+    /// The numbers in row `i`, from 0.
+    pub fn row(&self, i: usize) -> &[f64] {
+        &self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    /// All the numbers, row by row.
+    pub fn values(&self) -> &[f64] {
+        &self.data
+    }
+
+    /// Like `2x3`, rows first.
+    pub(crate) fn shape(&self) -> String {
+        format!("{}x{}", self.rows, self.cols)
+    }
+
+    pub(crate) fn map(&self, f: impl Fn(f64) -> f64) -> Matrix {
+        Matrix::new(
+            self.rows,
+            self.cols,
+            self.data.iter().map(|x| f(*x)).collect(),
+        )
+    }
+
+    pub(crate) fn try_map<E>(&self, f: impl Fn(f64) -> Result<f64, E>) -> Result<Matrix, E> {
+        let data = self.data.iter().map(|x| f(*x)).collect::<Result<_, _>>()?;
+        Ok(Matrix::new(self.rows, self.cols, data))
+    }
+
+    fn zip_with(
+        &self,
+        other: &Matrix,
+        op: &BinaryOp,
+        f: fn(f64, f64) -> f64,
+    ) -> CalcResult<Matrix> {
+        if self.rows != other.rows || self.cols != other.cols {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "can't {} a {} matrix and a {} one",
+                    verb(op),
+                    self.shape(),
+                    other.shape()
+                ),
+            ));
+        }
+        let data = self.data.iter().zip(&other.data).map(|(a, b)| f(*a, *b));
+        Ok(Matrix::new(self.rows, self.cols, data.collect()))
+    }
+
+    fn transpose(&self) -> Matrix {
+        let mut data = Vec::with_capacity(self.data.len());
+        for j in 0..self.cols {
+            data.extend((0..self.rows).map(|i| self.get(i, j)));
+        }
+        Matrix::new(self.cols, self.rows, data)
+    }
+
+    fn mul(&self, other: &Matrix) -> CalcResult<Matrix> {
+        if self.cols != other.rows {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "can't multiply a {} matrix by a {} one, the columns of the left must be as many as the rows of the right",
+                    self.shape(),
+                    other.shape()
+                ),
+            ));
+        }
+        let mut data = vec![0.0; self.rows * other.cols];
+        for i in 0..self.rows {
+            for k in 0..self.cols {
+                let a = self.get(i, k);
+                for j in 0..other.cols {
+                    data[i * other.cols + j] += a * other.get(k, j);
+                }
+            }
+        }
+        Ok(Matrix::new(self.rows, other.cols, data))
+    }
+
+    fn square(&self, what: &str) -> CalcResult<usize> {
+        if self.rows != self.cols {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "{} need a square matrix, but get a {} one",
+                    what,
+                    self.shape()
+                ),
+            ));
+        }
+        Ok(self.rows)
+    }
+
+    // with partial pivoting, `None` if a pivot is exactly zero, a NaN or an
+    // inf goes on to the determinant
+    fn lu(&self) -> Option<Lu> {
+        let n = self.rows;
+        let mut lu = self.data.clone();
+        let mut perm: Vec<usize> = (0..n).collect();
+        let mut sign = 1.0;
+        for k in 0..n {
+            let p = (k..n)
+                .max_by(|a, b| lu[a * n + k].abs().total_cmp(&lu[b * n + k].abs()))
+                .unwrap();
+            let pivot = lu[p * n + k];
+            if pivot == 0.0 {
+                return None;
+            }
+            if p != k {
+                for j in 0..n {
+                    lu.swap(k * n + j, p * n + j);
+                }
+                perm.swap(k, p);
+                sign = -sign;
+            }
+            for i in k + 1..n {
+                let f = lu[i * n + k] / pivot;
+                lu[i * n + k] = f;
+                for j in k + 1..n {
+                    lu[i * n + j] -= f * lu[k * n + j];
+                }
+            }
+        }
+        Some(Lu { n, lu, perm, sign })
+    }
+
+    fn det(&self) -> CalcResult<f64> {
+        self.square("det")?;
+        Ok(self.lu().map_or(0.0, |lu| lu.det()))
+    }
+
+    // the LU to invert or solve with, `None` also if a pivot is so small
+    // next to the rest of its row that it is rounding noise of a zero
+    fn regular_lu(&self) -> Option<Lu> {
+        let n = self.rows;
+        let lu = self.lu()?;
+        let regular = (0..n).all(|k| {
+            let row = &self.data[lu.perm[k] * n..][..n];
+            let scale = row.iter().fold(0.0, |m: f64, x| m.max(x.abs()));
+            let pivot = lu.lu[k * n + k];
+            !(pivot.abs() <= scale * n as f64 * f64::EPSILON && pivot.is_finite())
+        });
+        regular.then_some(lu)
+    }
+
+    fn inv(&self) -> CalcResult<Matrix> {
+        let n = self.square("inv")?;
+        let lu = self
+            .regular_lu()
+            .ok_or_else(|| singular("it has no inverse"))?;
+        let identity = Matrix::identity(n);
+        Ok(lu.solve_columns(&identity))
+    }
+
+    fn pow(&self, n: f64) -> CalcResult<Matrix> {
+        let size = self.square("'^'")?;
+        if n.fract() != 0.0 || !n.is_finite() {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "a matrix can only be raised to an integer power, but get {}",
+                    n
+                ),
+            ));
+        }
+        let mut base = if n < 0.0 { self.inv()? } else { self.clone() };
+        let mut n = n.abs();
+        let mut v = Matrix::identity(size);
+        while n > 0.0 {
+            if n % 2.0 == 1.0 {
+                v = v.mul(&base)?;
+            }
+            base = base.mul(&base)?;
+            n = (n / 2.0).floor();
+        }
+        Ok(v)
+    }
+
+    // the cyclic Jacobi method, rotating until nothing is off the diagonal
+    fn eig(&self) -> CalcResult<Vec<f64>> {
+        let n = self.square("eig")?;
+        let mut a = self.data.clone();
+        let total: f64 = a.iter().map(|x| x * x).sum();
+        let tolerance = total.sqrt() * 1e-9;
+        for i in 0..n {
+            for j in i + 1..n {
+                if (a[i * n + j] - a[j * n + i]).abs() > tolerance {
+                    return Err(CalcError::new(
+                        ErrorKind::Domain,
+                        "eig only works on symmetric matrices",
+                    ));
+                }
+            }
+        }
+        for _ in 0..MAX_SWEEPS {
+            let off: f64 = (0..n)
+                .flat_map(|i| (0..n).filter(move |j| *j != i).map(move |j| (i, j)))
+                .map(|(i, j)| a[i * n + j] * a[i * n + j])
+                .sum();
+            if off <= total * f64::EPSILON * f64::EPSILON {
+                break;
+            }
+            for p in 0..n {
+                for q in p + 1..n {
+                    let apq = a[p * n + q];
+                    if apq == 0.0 {
+                        continue;
+                    }
+                    // the rotation which makes a[p][q] zero
+                    let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                    let t = if theta == 0.0 {
+                        1.0
+                    } else {
+                        theta.signum() / (theta.abs() + theta.hypot(1.0))
+                    };
+                    let c = 1.0 / t.hypot(1.0);
+                    let s = t * c;
+                    for k in 0..n {
+                        let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                        a[k * n + p] = c * akp - s * akq;
+                        a[k * n + q] = s * akp + c * akq;
+                    }
+                    for k in 0..n {
+                        let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                        a[p * n + k] = c * apk - s * aqk;
+                        a[q * n + k] = s * apk + c * aqk;
+                    }
+                }
+            }
+        }
+        let mut values: Vec<f64> = (0..n).map(|i| a[i * n + i]).collect();
+        values.sort_by(f64::total_cmp);
+        Ok(values)
+    }
+}
+
+impl From<Matrix> for OneMore {
+    fn from(m: Matrix) -> Self {
+        OneMore::Matrix(m)
+    }
+}
+
+// L below the diagonal (its own diagonal is all 1) and U on and above it,
+// of the rows of the matrix in the order of `perm`
+struct Lu {
+    n: usize,
+    lu: Vec<f64>,
+    perm: Vec<usize>,
+    sign: f64,
+}
+
+impl Lu {
+    fn det(&self) -> f64 {
+        (0..self.n)
+            .map(|i| self.lu[i * self.n + i])
+            .product::<f64>()
+            * self.sign
+    }
+
+    // x with A x = b
+    fn solve(&self, b: &[f64]) -> Vec<f64> {
+        let n = self.n;
+        let mut x: Vec<f64> = self.perm.iter().map(|i| b[*i]).collect();
+        for i in 0..n {
+            for j in 0..i {
+                x[i] -= self.lu[i * n + j] * x[j];
+            }
+        }
+        for i in (0..n).rev() {
+            for j in i + 1..n {
+                x[i] -= self.lu[i * n + j] * x[j];
+            }
+            x[i] /= self.lu[i * n + i];
+        }
+        x
+    }
+
+    // X with A X = B, column by column
+    fn solve_columns(&self, b: &Matrix) -> Matrix {
+        let columns = b.transpose();
+        let mut x = Vec::with_capacity(b.data.len());
+        for j in 0..columns.rows {
+            x.extend(self.solve(columns.row(j)));
+        }
+        Matrix::new(b.cols, b.rows, x).transpose()
+    }
+}
+
+#[cold]
+fn singular(what: &str) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        format!("the matrix is singular, {}", what),
+    )
+}
+
+/// `None` if neither side is a matrix and the op is not '@', then the
+/// caller goes on without.
+pub(crate) fn binary(op: &BinaryOp, a: &OneMore, b: &OneMore) -> CalcResult<Option<OneMore>> {
+    if let BinaryOp::MatMul = op {
+        return product(a, b).map(Some);
+    }
+    let v = match (a, b) {
+        (OneMore::Matrix(a), OneMore::Matrix(b)) => match op {
+            BinaryOp::Mult => a.mul(b)?.into(),
+            BinaryOp::Plus => a.zip_with(b, op, |a, b| a + b)?.into(),
+            BinaryOp::Sub => a.zip_with(b, op, |a, b| a - b)?.into(),
+            BinaryOp::Eq => OneMore::One(boolean(a == b)),
+            BinaryOp::NotEq => OneMore::One(boolean(a != b)),
+            _ => return Err(op_error(op)),
+        },
+        // a list is a column on the right and a row on the left
+        (OneMore::Matrix(_), OneMore::More(_)) | (OneMore::More(_), OneMore::Matrix(_))
+            if matches!(op, BinaryOp::Mult) =>
+        {
+            product(a, b)?
+        }
+        (OneMore::Matrix(m), x) => {
+            let x = number(x)?;
+            match op {
+                BinaryOp::Plus => m.map(|a| a + x).into(),
+                BinaryOp::Sub => m.map(|a| a - x).into(),
+                BinaryOp::Mult => m.map(|a| a * x).into(),
+                BinaryOp::Div => m.map(|a| a / x).into(),
+                BinaryOp::Square => m.pow(x)?.into(),
+                _ => return Err(op_error(op)),
+            }
+        }
+        (x, OneMore::Matrix(m)) => {
+            let x = number(x)?;
+            match op {
+                BinaryOp::Plus => m.map(|b| x + b).into(),
+                BinaryOp::Sub => m.map(|b| x - b).into(),
+                BinaryOp::Mult => m.map(|b| x * b).into(),
+                BinaryOp::Div => {
+                    return Err(CalcError::new(
+                        ErrorKind::Domain,
+                        "can't divide by a matrix, use inv or solve",
+                    ))
+                }
+                _ => return Err(op_error(op)),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(v))
+}
+
+// 'a @ b', two lists give their dot product
+fn product(a: &OneMore, b: &OneMore) -> CalcResult<OneMore> {
+    let v = match (a, b) {
+        (OneMore::Matrix(a), OneMore::Matrix(b)) => a.mul(b)?.into(),
+        (OneMore::Matrix(a), OneMore::More(v)) => {
+            OneMore::More(a.mul(&Matrix::new(v.len(), 1, v.clone()))?.data)
+        }
+        (OneMore::More(v), OneMore::Matrix(b)) => {
+            OneMore::More(Matrix::new(1, v.len(), v.clone()).mul(b)?.data)
+        }
+        (OneMore::More(a), OneMore::More(b)) => {
+            if a.len() != b.len() {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!(
+                        "can't multiply lists of different lengths: {} and {}",
+                        a.len(),
+                        b.len()
+                    ),
+                ));
+            }
+            OneMore::One(a.iter().zip(b).map(|(a, b)| a * b).sum())
+        }
+        _ => {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                "'@' need a matrix or a list on both sides",
+            ))
+        }
+    };
+    Ok(v)
+}
+
+fn number(x: &OneMore) -> CalcResult<f64> {
+    x.one().ok_or_else(|| {
+        CalcError::new(
+            ErrorKind::Domain,
+            "only real numbers go with a matrix, or a list multiplied by it",
+        )
+    })
+}
+
+fn verb(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Plus => "add",
+        _ => "subtract",
+    }
+}
+
+#[cold]
+fn op_error(op: &BinaryOp) -> CalcError {
+    let message = match op {
+        BinaryOp::Div => "can't divide by a matrix, use inv or solve",
+        BinaryOp::Square => "can't raise to a matrix",
+        _ => "matrices can't be compared, except with '==' and '!='",
+    };
+    CalcError::new(ErrorKind::Domain, message)
+}
+
+/// Apply `f` element-wise on the matrices in `args` like on lists, the
+/// result has their shape. `None` if there is no matrix.
+pub(crate) fn elementwise(
+    args: &[OneMore],
+    f: impl FnOnce(&[OneMore]) -> CalcResult<OneMore>,
+) -> CalcResult<Option<OneMore>> {
+    let Some(shape) = args.iter().find_map(|x| match x {
+        OneMore::Matrix(m) => Some(m),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+    let mut flat = Vec::with_capacity(args.len());
+    for arg in args {
+        flat.push(match arg {
+            OneMore::Matrix(m) if m.rows != shape.rows || m.cols != shape.cols => {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!(
+                        "can't apply element-wise on matrices of different shapes: {} and {}",
+                        shape.shape(),
+                        m.shape()
+                    ),
+                ))
+            }
+            OneMore::Matrix(m) => OneMore::More(m.data.clone()),
+            OneMore::More(_) => {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    "can't apply element-wise on a matrix and a list",
+                ))
+            }
+            x => x.clone(),
+        });
+    }
+    Ok(Some(match f(&flat)? {
+        OneMore::More(v) => Matrix::new(shape.rows, shape.cols, v).into(),
+        v => v,
+    }))
+}
+
+// a matrix arg of `what`, `None` for a number which it takes as it is
+fn matrix_arg<'a>(arg: &'a OneMore, what: &str) -> CalcResult<Option<&'a Matrix>> {
+    match arg {
+        OneMore::Matrix(m) => Ok(Some(m)),
+        OneMore::More(_) => Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "{} need a matrix like [[1, 2], [3, 4]], but get a list",
+                what
+            ),
+        )),
+        _ => Ok(None),
+    }
+}
+
+// the functions below are the matrix versions of natives, see
+// `NativeFun::matrix`
+
+// transpose(A), a list is a column
+pub(crate) fn transpose(args: &[OneMore]) -> CalcResult<Option<OneMore>> {
+    Ok(match &args[0] {
+        OneMore::Matrix(m) => Some(m.transpose().into()),
+        OneMore::More(v) if !v.is_empty() => Some(Matrix::new(v.len(), 1, v.clone()).into()),
+        _ => None,
+    })
+}
+
+pub(crate) fn det(args: &[OneMore]) -> CalcResult<Option<OneMore>> {
+    match matrix_arg(&args[0], "det")? {
+        Some(m) => Ok(Some(OneMore::One(m.det()?))),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn inv(args: &[OneMore]) -> CalcResult<Option<OneMore>> {
+    match matrix_arg(&args[0], "inv")? {
+        Some(m) => Ok(Some(m.inv()?.into())),
+        None => Ok(None),
+    }
+}
+
+// the eigenvalues of a symmetric matrix, from the smallest
+pub(crate) fn eig(args: &[OneMore]) -> CalcResult<Option<OneMore>> {
+    match matrix_arg(&args[0], "eig")? {
+        Some(m) => Ok(Some(OneMore::More(m.eig()?))),
+        None => Ok(None),
+    }
+}
+
+// solve(A, b), x with A x = b for a list b, or X with A X = B for a matrix
+pub(crate) fn solve(args: &[OneMore]) -> CalcResult<Option<OneMore>> {
+    let Some(a) = matrix_arg(&args[0], "solve")? else {
+        return Ok(None);
+    };
+    let n = a.square("solve")?;
+    let lu = || {
+        a.regular_lu()
+            .ok_or_else(|| singular("the system has no single solution"))
+    };
+    match &args[1] {
+        OneMore::More(b) if b.len() == n => Ok(Some(OneMore::More(lu()?.solve(b)))),
+        OneMore::Matrix(b) if b.rows == n => Ok(Some(lu()?.solve_columns(b).into())),
+        _ => Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "solve need b as a list of {} numbers, or a matrix with {} rows",
+                n, n
+            ),
+        )),
+    }
+}
+
+/// The rows one per line with the columns aligned, every number shown by
+/// `real`, it reads back as the same matrix.
+pub(crate) fn show(m: &Matrix, real: impl Fn(f64) -> String) -> String {
+    let cells: Vec<String> = m.data.iter().map(|x| real(*x)).collect();
+    let widths: Vec<usize> = (0..m.cols)
+        .map(|j| {
+            (0..m.rows)
+                .map(|i| cells[i * m.cols + j].chars().count())
+                .max()
+                .unwrap()
+        })
+        .collect();
+    let rows: Vec<String> = (0..m.rows)
+        .map(|i| {
+            let row: Vec<String> = (0..m.cols)
+                .map(|j| format!("{:>1$}", cells[i * m.cols + j], widths[j]))
+                .collect();
+            format!("[{}]", row.join(", "))
+        })
+        .collect();
+    format!("[{}]", rows.join(",\n "))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Env, ErrorKind, OneMore};
+
+    fn close(a: &[f64], b: &[f64]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9)
+    }
+
+    #[test]
+    fn test_matrix() {
+        let mut env = Env::new();
+        env.run("A = [[1, 2], [3, 4]]; B = [[0, 1], [1, 0]]")
+            .unwrap();
+        let run = |env: &mut Env, s: &str| env.run(s).unwrap().unwrap();
+        let m = |env: &mut Env, s: &str| match run(env, s) {
+            OneMore::Matrix(m) => (m.rows(), m.cols(), m.values().to_vec()),
+            v => panic!("{} is not a matrix but {:?}", s, v),
+        };
+        assert_eq!(m(&mut env, "A @ B"), (2, 2, vec![2.0, 1.0, 4.0, 3.0]));
+        assert_eq!(m(&mut env, "A * B"), (2, 2, vec![2.0, 1.0, 4.0, 3.0]));
+        assert_eq!(m(&mut env, "A + 1"), (2, 2, vec![2.0, 3.0, 4.0, 5.0]));
+        assert_eq!(m(&mut env, "2 * A - B"), (2, 2, vec![2.0, 3.0, 5.0, 8.0]));
+        assert_eq!(m(&mut env, "-A / 2"), (2, 2, vec![-0.5, -1.0, -1.5, -2.0]));
+        assert_eq!(m(&mut env, "A^2"), (2, 2, vec![7.0, 10.0, 15.0, 22.0]));
+        assert_eq!(m(&mut env, "A^0"), (2, 2, vec![1.0, 0.0, 0.0, 1.0]));
+        assert_eq!(
+            m(&mut env, "transpose(A)"),
+            (2, 2, vec![1.0, 3.0, 2.0, 4.0])
+        );
+        assert_eq!(
+            m(&mut env, "transpose([1, 2, 3])"),
+            (3, 1, vec![1.0, 2.0, 3.0])
+        );
+        assert_eq!(
+            m(&mut env, "[[1, 2, 3]] @ [[1], [2], [3]]"),
+            (1, 1, vec![14.0])
+        );
+        assert_eq!(m(&mut env, "abs(-A)"), (2, 2, vec![1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(
+            m(&mut env, "round(A / 3, 1)"),
+            (2, 2, vec![0.3, 0.7, 1.0, 1.3])
+        );
+        assert_eq!(run(&mut env, "sum(A) + max(A)"), OneMore::One(14.0));
+        assert_eq!(run(&mut env, "A == [[1, 2], [3, 4]]"), OneMore::One(1.0));
+
+        // a list is a vector
+        let list = |v: &[f64]| OneMore::More(v.to_vec());
+        assert_eq!(run(&mut env, "A @ [1, 1]"), list(&[3.0, 7.0]));
+        assert_eq!(run(&mut env, "A * [1, 1]"), list(&[3.0, 7.0]));
+        assert_eq!(run(&mut env, "[1, 1] @ A"), list(&[4.0, 6.0]));
+        assert_eq!(run(&mut env, "[1, 2] @ [3, 4]"), OneMore::One(11.0));
+
+        let values = |env: &mut Env, s: &str| run(env, s).values().into_owned();
+        assert_eq!(run(&mut env, "det(A)"), OneMore::One(-2.0));
+        assert_eq!(
+            run(&mut env, "det([[2, 0, 1], [1, 3, 0], [0, 1, 4]])"),
+            OneMore::One(25.0)
+        );
+        assert_eq!(run(&mut env, "det([[1, 2], [2, 4]])"), OneMore::One(0.0));
+        // the cutoff of inv is per row, det is only 0 on an exact zero
+        assert_eq!(
+            run(&mut env, "det([[1, 0], [0, 1e-17]])"),
+            OneMore::One(1e-17)
+        );
+        assert_eq!(
+            values(&mut env, "inv([[1, 0], [0, 1e-17]])"),
+            [1.0, 0.0, 0.0, 1e17]
+        );
+        assert_eq!(
+            run(&mut env, "det([[1/0, 0], [0, 1]])"),
+            OneMore::One(f64::INFINITY)
+        );
+        assert!(values(&mut env, "det([[0/0]])")[0].is_nan());
+        assert!(values(&mut env, "det([[1, 0/0], [2, 3]])")[0].is_nan());
+        assert!(values(&mut env, "inv([[1, 0/0], [2, 3]])")
+            .iter()
+            .all(|x| x.is_nan()));
+        assert!(close(&values(&mut env, "inv(A)"), &[-2.0, 1.0, 1.5, -0.5]));
+        assert!(close(&values(&mut env, "A @ A^-1"), &[1.0, 0.0, 0.0, 1.0]));
+        assert!(close(
+            &values(&mut env, "solve([[2, 1], [1, 3]], [3, 5])"),
+            &[0.8, 1.4]
+        ));
+        assert!(close(
+            &values(&mut env, "solve(A, A @ B)"),
+            &[0.0, 1.0, 1.0, 0.0]
+        ));
+        assert!(close(
+            &values(&mut env, "eig([[2, 1], [1, 2]])"),
+            &[1.0, 3.0]
+        ));
+        let s = std::f64::consts::SQRT_2;
+        assert!(close(
+            &values(&mut env, "eig([[2, -1, 0], [-1, 2, -1], [0, -1, 2]])"),
+            &[2.0 - s, 2.0, 2.0 + s]
+        ));
+        // on a number they are trivial
+        assert_eq!(
+            run(
+                &mut env,
+                "[det(5), inv(4), solve(2, 6), eig(7), transpose(8)]"
+            ),
+            list(&[5.0, 0.25, 3.0, 7.0, 8.0])
+        );
+
+        env.run("fun sq(M) = M @ M").unwrap();
+        assert_eq!(m(&mut env, "sq(B)"), (2, 2, vec![1.0, 0.0, 0.0, 1.0]));
+        assert_eq!(env.show_fn("sq").unwrap(), "fun sq(M) = M @ M");
+
+        let err = |env: &mut Env, s: &str| env.run(s).unwrap_err();
+        for s in [
+            "[[1, 2], [3]]",
+            "[[1, 2], 3]",
+            "[[]]",
+            "A @ [[1, 2, 3]]",
+            "A @ 2",
+            "A + [1, 2]",
+            "A < B",
+            "1 / A",
+            "A^0.5",
+            "[[1, 2, 3]]^2",
+            "A + 2i",
+            "sin(A) + sin([1, 2])",
+            "max(A, [[1]]) + atan2(A, [[1, 2]])",
+            "det([1, 2])",
+            "det([[1, 2, 3]])",
+            "inv([[1, 2], [2, 4]])",
+            "solve(A, [1, 2, 3])",
+            "eig(A)",
+            "A ? 1 : 2",
+        ] {
+            assert_eq!(err(&mut env, s).kind(), ErrorKind::Domain, "{}", s);
+        }
+        assert_eq!(
+            err(&mut env, "det([[1, 2, 3]])").message(),
+            "det need a square matrix, but get a 1x3 one"
+        );
+        assert_eq!(err(&mut env, "eig(A)").name(), Some("eig"));
+    }
+
+    #[test]
+    fn test_show() {
+        let mut env = Env::new();
+        let a = env.run("[[1, -2.5], [10, 3]]").unwrap().unwrap();
+        assert_eq!(env.format(&a), "[[ 1, -2.5],\n [10,    3]]");
+        assert_eq!(a.to_string(), "[[ 1, -2.5],\n [10,    3]]");
+        env.run("%format(fix, 1)").unwrap();
+        assert_eq!(env.format(&a), "[[ 1.0, -2.5],\n [10.0,  3.0]]");
+
+        env.run("%format(plain); M = [[1, 2], [3, 1/0]]").unwrap();
+        assert!(env.to_source().contains("M = [[1, 2], [3, 1/0]]"));
+    }
+}
@@ -1,35 +1,160 @@
+use std::borrow::Cow;
 use std::fmt::{self, Display};
 
-// all of the function only return an value
-// the More is not usued yet now
-#[derive(PartialEq, PartialOrd, Clone, Debug)]
-#[allow(unused)]
+use num_complex::Complex64;
+use num_rational::BigRational;
+
+use crate::{complex, exact, matrix, matrix::Matrix, units::Quantity};
+
+// a single number or a list of numbers
+#[derive(PartialEq, Clone, Debug)]
 pub enum OneMore {
     One(f64),
     More(Vec<f64>),
+    /// A single exact number, from `Mode::Exact`.
+    Exact(BigRational),
+    /// A single complex number, never with a zero imaginary part.
+    Complex(Complex64),
+    /// A single number with a unit, never a dimensionless one.
+    Quantity(Quantity),
+    /// A matrix of real numbers, like `[[1, 2], [3, 4]]`.
+    Matrix(Matrix),
 }
 
 impl OneMore {
+    /// The single real number, an exact one is rounded to the nearest float.
     pub fn one(&self) -> Option<f64> {
         match self {
             OneMore::One(v) => Some(*v),
-            OneMore::More(_) => None,
+            OneMore::Exact(v) => Some(exact::to_f64(v)),
+            OneMore::More(_) | OneMore::Complex(_) | OneMore::Quantity(_) | OneMore::Matrix(_) => {
+                None
+            }
         }
     }
 
     pub fn more(&self) -> Option<&[f64]> {
         match self {
-            OneMore::One(_) => None,
             OneMore::More(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// All the real numbers, a single number is a slice of one, a matrix
+    /// gives them row by row and a complex number or a quantity has none.
+    pub fn values(&self) -> Cow<'_, [f64]> {
+        match self {
+            OneMore::One(v) => Cow::Borrowed(std::slice::from_ref(v)),
+            OneMore::More(v) => Cow::Borrowed(v),
+            OneMore::Exact(v) => Cow::Owned(vec![exact::to_f64(v)]),
+            OneMore::Matrix(m) => Cow::Borrowed(m.values()),
+            OneMore::Complex(_) | OneMore::Quantity(_) => Cow::Borrowed(&[]),
+        }
+    }
+
+    // the functions below work on floats, an exact number becomes a float,
+    // a complex one or a quantity must be handled before
+
+    pub(crate) fn try_map<F, E>(&self, f: F) -> Result<OneMore, E>
+    where
+        F: Fn(f64) -> Result<f64, E>,
+    {
+        match self {
+            OneMore::More(v) => v
+                .iter()
+                .map(|x| f(*x))
+                .collect::<Result<_, _>>()
+                .map(OneMore::More),
+            OneMore::Matrix(m) => m.try_map(f).map(OneMore::Matrix),
+            OneMore::Complex(_) | OneMore::Quantity(_) => unreachable!("map on {:?}", self),
+            v => f(v.one().unwrap()).map(OneMore::One),
+        }
+    }
+
+    pub(crate) fn map<F>(&self, f: F) -> OneMore
+    where
+        F: Fn(f64) -> f64,
+    {
+        match self {
+            OneMore::More(v) => OneMore::More(v.iter().map(|x| f(*x)).collect()),
+            OneMore::Matrix(m) => OneMore::Matrix(m.map(f)),
+            OneMore::Complex(_) | OneMore::Quantity(_) => unreachable!("map on {:?}", self),
+            v => OneMore::One(f(v.one().unwrap())),
         }
     }
+
+    /// Element-wise `f`, a single number goes with every element of a list.
+    /// `None` if both are lists of different lengths.
+    pub(crate) fn zip_with<F>(&self, other: &OneMore, f: F) -> Option<OneMore>
+    where
+        F: Fn(f64, f64) -> f64,
+    {
+        let v = match (self.more(), other.more()) {
+            (None, None) => OneMore::One(f(self.one()?, other.one()?)),
+            (None, Some(b)) => {
+                let a = self.one()?;
+                OneMore::More(b.iter().map(|b| f(a, *b)).collect())
+            }
+            (Some(a), None) => {
+                let b = other.one()?;
+                OneMore::More(a.iter().map(|a| f(*a, b)).collect())
+            }
+            (Some(a), Some(b)) => {
+                if a.len() != b.len() {
+                    return None;
+                }
+                OneMore::More(a.iter().zip(b).map(|(a, b)| f(*a, *b)).collect())
+            }
+        };
+        Some(v)
+    }
+}
+
+impl From<f64> for OneMore {
+    fn from(v: f64) -> Self {
+        OneMore::One(v)
+    }
+}
+
+impl From<BigRational> for OneMore {
+    fn from(v: BigRational) -> Self {
+        OneMore::Exact(v)
+    }
+}
+
+impl From<Complex64> for OneMore {
+    fn from(v: Complex64) -> Self {
+        complex::normalize(v)
+    }
+}
+
+impl From<Vec<f64>> for OneMore {
+    fn from(v: Vec<f64>) -> Self {
+        OneMore::More(v)
+    }
 }
 
 impl Display for OneMore {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             OneMore::One(v) => write!(f, "{}", v),
-            OneMore::More(v) => write!(f, "{:?}", v),
+            OneMore::Exact(v) => write!(f, "{}", v),
+            // `{:#}` is the polar form
+            OneMore::Complex(z) => {
+                write!(f, "{}", complex::show(z, f.alternate(), |x| x.to_string()))
+            }
+            OneMore::Quantity(q) => write!(f, "{}", q),
+            OneMore::Matrix(m) => write!(f, "{}", matrix::show(m, |x| x.to_string())),
+            OneMore::More(v) => {
+                write!(f, "[")?;
+                for (i, x) in v.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
@@ -1,91 +1,125 @@
 use std::collections::HashMap;
+use std::iter::Peekable;
 use std::mem::discriminant;
 
-use crate::ast::{BinaryOp, Expr, MagicKind, Stmt, UnaryOp, Valuable};
+use crate::ast::{BinaryOp, Expr, MagicExpr, MagicName, Stmt, UnaryOp, Valuable};
+use crate::error::{CalcError, CalcResult, ErrorKind, Span};
 use crate::lexer::Token;
-use crate::utils::{hash_it, print_err};
+use crate::magic::MagicArg;
+use crate::units::{self, Unit};
 
 // this file is an impl of recursive descent parser
 // {} 0-inf times
 // [] 0 or 1 times
 // | or
 // () group
-// prog = { stmt }
-// stmt = fun | assign | magic
-// magic = '%' idx '(' { ( idx | expr ) } ')'
-// fun = idx '(' {idx [',']} ')' = expr
-// assign = expr | (idx '=' expr)
-// expr = plus_sub
+// prog = { [stmt] ( ';' | '\n' ) } [stmt]
+// stmt = fun | assign | magic | diff
+// magic = '%' idx '(' { ( name | '[' name { ',' name } ']' | expr ) [','] } ')'
+//       the args as the `MagicArg`s of the magic say
+// name = idx | lambda
+// fun = ['memo'] 'fun' idx '(' {idx [',']} ')' = expr
+// diff = 'diff' '(' idx ')'
+// assign = expr | (idx '=' expr)  // 'f = x -> x^2' is 'fun f(x) = x^2'
+// expr = lambda | let | ( cond [ ( 'in' | 'to' ) unit ] )
+// lambda = ( idx | '(' { idx [','] } ')' ) '->' expr
+// let = 'let' idx '=' cond { ',' idx '=' cond } 'in' expr
+// cond = or [ '?' expr ':' cond ]
+// or = { and '||' } and
+// and = { cmp '&&' } cmp
+// cmp = range [ ('<'|'<='|'=='|'!='|'>'|'>=') range ]
+// range = plus_sub [ '..' plus_sub ]
 // plus_sub = { mult_div ('+'|'-') } mult_div
-// mult_div = { square ('*'|'/') } square
-// minus = ( '-' minus ) | square
+// mult_div = { square ('*'|'/'|'@') } square
+// minus = ( ('-'|'!') minus ) | square
 // square = { factorial '^' } minus  // dangerous, but user-friendly
 // factorial = call ['!']
-// call = primary | idx '(' call {call} ')'
-// primary = idx | number | ( '(' expr ')' )
+// call = primary | idx '(' {expr [',']} ')' | 'if' '(' expr [','] expr [','] expr ')'
+// primary = idx | ( number [ unit ] ) | ( '(' expr ')' ) | ( '[' {expr [',']} ']' ) | block
+// block = '{' { idx '=' expr ( ';' | '\n' ) } expr '}'  // blank lines are skipped
+// unit = unit_pow { ( '*' | '/' ) unit_pow }  // no spaces inside after a number
+// unit_pow = idx [ '^' [ '-' ] number ]
 //
 // from lexer
 // idx = hash(name)
-// name = ( '_' | 'a-z A-Z' ) { 'a-z A-Z 0=9' }
-// number = '0-9' { '0-9' } [ '.' '0-9' { '0-9' } ]
+// name = ( '_' | 'a-z A-Z' ) { 'a-z A-Z 0=9' } { '\'' }
+// number = ( '0x' hex { hex } ) | ( '0b' '0-1' { '0-1' } ) | ( '0o' '0-7' { '0-7' } )
+//     | ( '0-9' { '0-9' } [ '.' '0-9' { '0-9' } ] [ ( 'e' | 'E' ) [ '+' | '-' ] '0-9' { '0-9' } ] [ 'i' ] )
 
-pub(crate) struct Parser<T: Iterator<Item = Token>> {
-    tokens: T,
+// the names of a let with their values, and its body
+type LetParts = (Vec<(u64, Expr)>, Box<Expr>);
+
+pub(crate) struct Parser<T: Iterator<Item = (Token, Span)> + Clone> {
+    tokens: Peekable<T>,
     next: Option<Token>,
+    next_span: Span,
+    // span of the last consumed token, every rule is called right after its
+    // start token is consumed, so this is where the rule begins
+    prev: Span,
     args: HashMap<u64, usize>,
+    // how many args are in scope, the params of a lambda and the names of
+    // a let come after them
+    locals: usize,
     magic: HashMap<u64, Vec<MagicArg>>,
     namespace: Option<HashMap<u64, String>>,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) enum MagicArg {
-    Idx,
-    Expr,
-}
-
-impl<T: Iterator<Item = Token>> Parser<T> {
+impl<T: Iterator<Item = (Token, Span)> + Clone> Parser<T> {
     pub(crate) fn new(tokens: T) -> Self {
         let mut parser = Parser {
-            tokens,
+            tokens: tokens.peekable(),
             next: None,
+            next_span: Span::default(),
+            prev: Span::default(),
             args: HashMap::new(),
+            locals: 0,
             magic: HashMap::new(),
             namespace: None,
         };
-        parser.magic_plot();
 
         parser.eat();
         parser
     }
 
-    pub(crate) fn parse(&mut self) -> Option<Box<Stmt>> {
-        let start = self.next()?;
+    /// Parse the whole program, empty statements are skipped.
+    pub(crate) fn parse(&mut self) -> CalcResult<Vec<Stmt>> {
+        let mut prog = Vec::new();
+        loop {
+            while self.expect(Token::Semi) {}
+            if self.expect(Token::Eof) {
+                return Ok(prog);
+            }
 
-        let stmt = match start {
-            Token::Fun => self.fun(),
-            Token::Ident(_) => self.assign(start),
-            Token::Percent => self.magic(),
-            Token::Eof => {
-                return None;
+            prog.push(*self.stmt()?);
+
+            if let Some(Token::Unknown(id)) = self.next {
+                return Err(self.unknown(id, self.next_span));
             }
-            Token::Unknown(id) => {
-                let lexme = self.find_name(id).unwrap_or("");
-                print_err!("invalid syntax: {}", lexme);
-                None
+            if !self.is_at_end() {
+                return Err(self.error("invalid syntax, unknown where is end of statement"));
+            }
+        }
+    }
+
+    fn stmt(&mut self) -> CalcResult<Box<Stmt>> {
+        let start = self.next_token()?;
+
+        match start {
+            Token::Fun => self.fun(false),
+            Token::Memo => {
+                if !self.expect(Token::Fun) {
+                    return Err(self.error("expect 'fun' after 'memo'"));
+                }
+                self.fun(true)
             }
+            Token::Ident(_) => self.assign(start),
+            Token::Percent => self.magic(),
+            Token::Diff => self.diff(),
             _ => {
                 let expr = self.expr(start)?;
-                let stmt = Stmt::Expr { expr };
-                Some(Box::new(stmt))
+                Ok(Box::new(Stmt::Expr { expr }))
             }
-        };
-
-        if !self.expect(Token::Eof) {
-            print_err!("invalid syntax, unknown where is end of input");
-            return None;
         }
-
-        stmt
     }
 
     pub(crate) fn push_namespace(&mut self, namespace: HashMap<u64, String>) {
@@ -93,51 +127,64 @@ impl<T: Iterator<Item = Token>> Parser<T> {
     }
 
     pub(crate) fn pop_namespace(&mut self) -> HashMap<u64, String> {
-        if let Some(namespace) = self.namespace.take() {
-            namespace
-        } else {
-            HashMap::new()
-        }
+        self.namespace.take().unwrap_or_default()
     }
 
-    fn magic(&mut self) -> Option<Box<Stmt>> {
-        if self.is_at_end() {
-            print_err!("expect a name after 'fun'");
-            return None;
-        }
+    // how the args of each magic are parsed
+    pub(crate) fn push_magic(&mut self, magic: HashMap<u64, Vec<MagicArg>>) {
+        self.magic = magic;
+    }
 
+    fn magic(&mut self) -> CalcResult<Box<Stmt>> {
+        let begin = self.prev;
         let magic_name = if let Some(Token::Ident(idx)) = self.next() {
             idx
         } else {
-            print_err!("expect a name after '%'");
-            return None;
+            return Err(self.error_at(self.prev, "expect a name after '%'"));
         };
 
         if !self.expect(Token::LeftParen) {
-            print_err!(
-                "expect '(' after '{}'",
-                self.find_name(magic_name).unwrap_or("Unknown")
-            );
-            return None;
+            let name = self.find_name(magic_name);
+            return Err(self
+                .error(format!("expect '(' after '{}'", name.unwrap_or("Unknown")))
+                .with_name(name));
         }
 
-        let (mut idxs, mut exprs) = (Vec::new(), Vec::new());
+        let mut given = Vec::new();
         let magic = self.magic.clone();
         if let Some(args) = magic.get(&magic_name) {
             for arg in args {
                 match arg {
                     MagicArg::Idx => {
-                        if let Some(Token::Ident(idx)) = self.next() {
-                            idxs.push(idx);
-                        } else {
-                            print_err!("expect a name in magic function arg");
-                            return None;
-                        };
+                        let start = self.next_span;
+                        let name = self.magic_name()?;
+                        given.push(MagicExpr::Names(vec![name], start.to(self.prev)));
+                    }
+                    MagicArg::Idxs => {
+                        let (start, mut idxs) = (self.next_span, Vec::new());
+                        let bracket = self.expect(Token::LeftBracket);
+                        loop {
+                            idxs.push(self.magic_name()?);
+                            if !bracket || self.expect(Token::RightBracket) {
+                                break;
+                            }
+                            if !self.expect(Token::Comma) {
+                                return Err(self.error("expect ',' or ']' after the name"));
+                            }
+                        }
+                        given.push(MagicExpr::Names(idxs, start.to(self.prev)));
                     }
                     MagicArg::Expr => {
-                        let t = self.next()?;
-                        let expr = self.expr(t)?;
-                        exprs.push(expr);
+                        let t = self.next_token()?;
+                        given.push(MagicExpr::Expr(self.expr(t)?));
+                    }
+                    MagicArg::OptExpr => {
+                        if self.check(Token::RightParen) {
+                            given.push(MagicExpr::Omitted(self.next_span));
+                        } else {
+                            let t = self.next_token()?;
+                            given.push(MagicExpr::Expr(self.expr(t)?));
+                        }
                     }
                 }
                 if self.check(Token::Comma) {
@@ -145,104 +192,115 @@ impl<T: Iterator<Item = Token>> Parser<T> {
                 }
             }
         } else {
-            print_err!(
-                "can't find magic function named: {}",
-                self.find_name(magic_name).unwrap_or("Unknown")
-            );
-            return None;
+            let name = self.find_name(magic_name);
+            return Err(CalcError::new(
+                ErrorKind::UnknownName,
+                format!(
+                    "can't find magic function named: {}",
+                    name.unwrap_or("Unknown")
+                ),
+            )
+            .with_name(name)
+            .with_span(self.prev));
         };
 
         if !self.expect(Token::RightParen) {
-            print_err!("missing ')'");
-            return None;
+            return Err(self.error("missing ')'"));
         }
+        Ok(Box::new(Stmt::Magic {
+            idx: magic_name,
+            args: given,
+            span: begin.to(self.prev),
+        }))
+    }
 
-        match magic_name {
-            p if p == hash_it(&"plot2d") => {
-                if idxs.len() != 1 || exprs.len() != 3 {
-                    print_err!(
-                        "magic function plot2d need 4 args: function name, start, end, step"
-                    );
-                    return None;
-                }
+    // a name in the args of a magic, or a function in place of it
+    fn magic_name(&mut self) -> CalcResult<MagicName> {
+        match self.next() {
+            Some(start) if self.is_lambda(&start) => Ok(MagicName::Lambda(self.lambda(start)?)),
+            Some(Token::Ident(idx)) => Ok(MagicName::Idx(idx)),
+            _ => Err(self.error_at(self.prev, "expect a name in magic function arg")),
+        }
+    }
 
-                let expr3 = exprs.pop().unwrap();
-                let expr2 = exprs.pop().unwrap();
-                let expr1 = exprs.pop().unwrap();
-                Some(Box::new(Stmt::Magic {
-                    kind: MagicKind::Plot2d(idxs[0], expr1, expr2, expr3),
-                }))
-            }
-            _ => None, // impossible
+    fn diff(&mut self) -> CalcResult<Box<Stmt>> {
+        let begin = self.prev;
+        if !self.expect(Token::LeftParen) {
+            return Err(self.error("expect '(' after 'diff'"));
         }
+        let idx = if let Some(Token::Ident(idx)) = self.next() {
+            idx
+        } else {
+            return Err(self.error_at(self.prev, "expect a function name in 'diff'"));
+        };
+        if !self.expect(Token::RightParen) {
+            return Err(self.error("missing ')'"));
+        }
+        Ok(Box::new(Stmt::Diff {
+            idx,
+            span: begin.to(self.prev),
+        }))
     }
 
-    fn assign(&mut self, start: Token) -> Option<Box<Stmt>> {
+    fn assign(&mut self, start: Token) -> CalcResult<Box<Stmt>> {
         if !self.check(Token::Eq) {
             let expr = self.expr(start)?;
-            return Some(Box::new(Stmt::Expr { expr }));
+            return Ok(Box::new(Stmt::Expr { expr }));
         }
         self.eat();
 
         if self.is_at_end() {
-            print_err!("expect a expression after '='");
-            return None;
+            return Err(self.error("expect a expression after '='"));
         }
 
-        let expr_start = self.next().unwrap();
+        let expr_start = self.next_token()?;
         let expr = self.expr(expr_start)?;
 
         let idx = if let Token::Ident(idx) = start {
             idx
         } else {
-            // never reach
-            print_err!("expect a name but get {:?}, this is a bug!", start);
-            return None;
+            unreachable!("expect a name but get {:?}", start);
         };
 
-        Some(Box::new(Stmt::Assign { idx, expr }))
-    }
-
-    fn fun(&mut self) -> Option<Box<Stmt>> {
-        if self.is_at_end() {
-            print_err!("expect a name after 'fun'");
-            return None;
+        if let Expr::Lambda { params, body, .. } = *expr {
+            return Ok(Box::new(Stmt::Fun {
+                idx,
+                params,
+                body,
+                memo: false,
+            }));
         }
+        Ok(Box::new(Stmt::Assign { idx, expr }))
+    }
 
+    fn fun(&mut self, memo: bool) -> CalcResult<Box<Stmt>> {
         let idx = if let Some(Token::Ident(idx)) = self.next() {
             idx
         } else {
-            print_err!("expect a name after 'fun'");
-            return None;
+            return Err(self.error_at(self.prev, "expect a name after 'fun'"));
         };
 
         if !self.expect(Token::LeftParen) {
-            print_err!(
-                "expect '(' after '{}'",
-                self.find_name(idx).unwrap_or("Unknown")
-            );
-            return None;
+            let name = self.find_name(idx);
+            return Err(self
+                .error(format!("expect '(' after '{}'", name.unwrap_or("Unknown")))
+                .with_name(name));
         }
 
-        let mut count = 0;
+        // the args from a failed definition must not leak into the next one
+        self.args.clear();
+        self.locals = 0;
+        let mut params = Vec::new();
         // don't use while let, that will eat the self.next
         while self.check(Token::Ident(0)) {
-            let idx = if let Some(Token::Ident(idx)) = self.next.take() {
-                self.eat();
+            let idx = if let Some(Token::Ident(idx)) = self.next() {
                 idx
             } else {
-                // never reach
-                print_err!("expect a name, this is a bug!");
-                return None;
+                unreachable!("checked the next token is a name");
             };
 
-            if count == usize::MAX {
-                print_err!("to many args");
-                return None;
-            }
-
-            self.args.insert(idx, count);
-            count += 1;
+            self.args.insert(idx, params.len());
+            params.push(idx);
 
             if self.check(Token::Comma) {
                 self.eat();
@@ -250,98 +308,445 @@ impl<T: Iterator<Item = Token>> Parser<T> {
         }
 
         if !self.expect(Token::RightParen) {
-            print_err!("missing ')'");
-            return None;
+            return Err(self.error("missing ')'"));
         }
+        self.locals = params.len();
 
         if !self.expect(Token::Eq) {
-            print_err!("expect '='");
-            return None;
+            return Err(self.error("expect '='"));
         }
 
         if self.is_at_end() {
-            print_err!("expect a expression after '='");
-            return None;
+            return Err(self.error("expect a expression after '='"));
         }
 
-        let start = self.next().unwrap();
+        let start = self.next_token()?;
 
         let body = self.expr(start)?;
-        let stmt = Stmt::Fun { idx, body };
+        let stmt = Stmt::Fun {
+            idx,
+            params,
+            body,
+            memo,
+        };
 
         // dont forget clear the args!
         self.args.clear();
-        Some(Box::new(stmt))
+        self.locals = 0;
+        Ok(Box::new(stmt))
+    }
+
+    fn expr(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        if self.is_lambda(&start) {
+            return self.lambda(start);
+        }
+        if let Token::Let = start {
+            return self.let_in();
+        }
+        let expr = self.cond(start)?;
+
+        if !self.expect(Token::In) {
+            return Ok(expr);
+        }
+        let unit = match self.next() {
+            Some(Token::Ident(idx)) => self.unit(idx, false)?,
+            _ => return Err(self.error_at(self.prev, "expect a unit after 'in'")),
+        };
+        let span = expr.span().to(self.prev);
+        Ok(Box::new(Expr::Convert {
+            expr,
+            unit: Box::new(unit),
+            span,
+        }))
+    }
+
+    // 'x ->' or '(x, y) ->' after `start`, looking ahead past the ')'
+    fn is_lambda(&self, start: &Token) -> bool {
+        match start {
+            Token::Ident(_) => self.check(Token::Arrow),
+            Token::LeftParen => {
+                let after = self.tokens.clone().map(|(t, _)| t);
+                let mut tokens = self.next.iter().cloned().chain(after);
+                loop {
+                    match tokens.next() {
+                        Some(Token::Ident(_) | Token::Comma) => {}
+                        Some(Token::RightParen) => {
+                            return matches!(tokens.next(), Some(Token::Arrow))
+                        }
+                        _ => return false,
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn lambda(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let begin = self.prev;
+        let mut params = Vec::new();
+        if let Token::Ident(idx) = start {
+            params.push(idx);
+        } else {
+            while let Some(Token::Ident(idx)) = self.next {
+                params.push(idx);
+                self.eat();
+                if self.check(Token::Comma) {
+                    self.eat();
+                }
+            }
+            // checked by `is_lambda`
+            self.eat();
+        }
+        self.eat();
+
+        if self.is_at_end() {
+            return Err(self.error("expect a expression after '->'"));
+        }
+
+        // the body still sees the args in scope, its params come after them
+        let (outer, base) = (self.args.clone(), self.locals);
+        for (i, idx) in params.iter().enumerate() {
+            self.args.insert(*idx, base + i);
+        }
+        self.locals = base + params.len();
+        let body = self.next_token().and_then(|start| self.expr(start));
+        self.args = outer;
+        self.locals = base;
+
+        let body = body?;
+        let span = begin.to(body.span());
+        Ok(Box::new(Expr::Lambda { params, body, span }))
+    }
+
+    fn let_in(&mut self) -> CalcResult<Box<Expr>> {
+        let begin = self.prev;
+        let (outer, base) = (self.args.clone(), self.locals);
+        let parts = self.let_parts();
+        self.args = outer;
+        self.locals = base;
+
+        let (bindings, body) = parts?;
+        let span = begin.to(body.span());
+        Ok(Box::new(Expr::Let {
+            bindings,
+            body,
+            block: false,
+            span,
+        }))
+    }
+
+    fn let_parts(&mut self) -> CalcResult<LetParts> {
+        let mut bindings = Vec::new();
+        loop {
+            let idx = match self.next() {
+                Some(Token::Ident(idx)) => idx,
+                _ => return Err(self.error_at(self.prev, "expect a name after 'let'")),
+            };
+            if !self.expect(Token::Eq) {
+                return Err(self.error("expect '=' after the name"));
+            }
+            if self.is_at_end() {
+                return Err(self.error("expect a expression after '='"));
+            }
+            // not `expr`, the 'in' after it is the one before the body
+            let start = self.next_token()?;
+            let value = self.cond(start)?;
+            self.bind(&mut bindings, idx, *value);
+
+            if self.expect(Token::In) {
+                break;
+            }
+            if !self.expect(Token::Comma) {
+                return Err(self.error("expect ',' or 'in' after the value"));
+            }
+        }
+
+        if self.is_at_end() {
+            return Err(self.error("expect a expression after 'in'"));
+        }
+        let start = self.next_token()?;
+        let body = self.expr(start)?;
+        Ok((bindings, body))
+    }
+
+    fn block(&mut self) -> CalcResult<Box<Expr>> {
+        let begin = self.prev;
+        let (outer, base) = (self.args.clone(), self.locals);
+        let parts = self.block_parts();
+        self.args = outer;
+        self.locals = base;
+
+        let (bindings, body) = parts?;
+        Ok(Box::new(Expr::Let {
+            bindings,
+            body,
+            block: true,
+            span: begin.to(self.prev),
+        }))
+    }
+
+    // the statements of a block after the '{', new lines are ';' in it
+    fn block_parts(&mut self) -> CalcResult<LetParts> {
+        let mut bindings = Vec::new();
+        loop {
+            while self.expect(Token::Semi) {}
+            match self.next_token()? {
+                Token::Ident(idx) if self.check(Token::Eq) => {
+                    self.eat();
+                    if self.is_at_end() {
+                        return Err(self.error("expect a expression after '='"));
+                    }
+                    let start = self.next_token()?;
+                    let value = self.expr(start)?;
+                    if !self.check(Token::Semi) {
+                        return Err(self.error("expect ';' or a new line after the value"));
+                    }
+                    self.bind(&mut bindings, idx, *value);
+                }
+                Token::RightBrace | Token::Eof => {
+                    return Err(
+                        self.error_at(self.prev, "expect a expression at the end of the block")
+                    )
+                }
+                start => {
+                    let body = self.expr(start)?;
+                    while self.expect(Token::Semi) {}
+                    if !self.expect(Token::RightBrace) {
+                        return Err(self.error("missing '}'"));
+                    }
+                    return Ok((bindings, body));
+                }
+            }
+        }
     }
 
-    fn expr(&mut self, start: Token) -> Option<Box<Expr>> {
-        self.plus_sub(start)
+    // `idx` is the next arg in scope, in the rest of the let or block
+    fn bind(&mut self, bindings: &mut Vec<(u64, Expr)>, idx: u64, value: Expr) {
+        self.args.insert(idx, self.locals);
+        self.locals += 1;
+        bindings.push((idx, value));
     }
 
-    fn plus_sub(&mut self, start: Token) -> Option<Box<Expr>> {
+    fn cond(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let cond = self.or(start)?;
+
+        if !self.expect(Token::Question) {
+            return Ok(cond);
+        }
+
+        if self.is_at_end() {
+            return Err(self.error("expect a expression after '?'"));
+        }
+        let start = self.next_token()?;
+        let then = self.expr(start)?;
+
+        if !self.expect(Token::Colon) {
+            return Err(self.error("expect ':'"));
+        }
+
+        if self.is_at_end() {
+            return Err(self.error("expect a expression after ':'"));
+        }
+        let start = self.next_token()?;
+        let other = self.cond(start)?;
+
+        let span = cond.span().to(other.span());
+        Ok(Box::new(Expr::Cond {
+            cond,
+            then,
+            other,
+            span,
+        }))
+    }
+
+    fn or(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let mut left = self.and(start)?;
+
+        while self.expect(Token::OrOr) {
+            if self.is_at_end() {
+                return Err(self.error("expect a expression after '||'"));
+            }
+
+            let start = self.next_token()?;
+            let right = self.and(start)?;
+            let span = left.span().to(right.span());
+            left = Box::new(Expr::Binary {
+                left,
+                op: BinaryOp::Or,
+                right,
+                span,
+            })
+        }
+
+        Ok(left)
+    }
+
+    fn and(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let mut left = self.cmp(start)?;
+
+        while self.expect(Token::AndAnd) {
+            if self.is_at_end() {
+                return Err(self.error("expect a expression after '&&'"));
+            }
+
+            let start = self.next_token()?;
+            let right = self.cmp(start)?;
+            let span = left.span().to(right.span());
+            left = Box::new(Expr::Binary {
+                left,
+                op: BinaryOp::And,
+                right,
+                span,
+            })
+        }
+
+        Ok(left)
+    }
+
+    fn cmp(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let left = self.range(start)?;
+
+        let op = match self.cmp_op() {
+            Some(op) => op,
+            None => return Ok(left),
+        };
+        self.eat();
+
+        if self.is_at_end() {
+            return Err(self.error("expect a expression after the comparison"));
+        }
+
+        let start = self.next_token()?;
+        let right = self.range(start)?;
+
+        // `a < b < c` means nothing useful with numbers as booleans
+        if self.cmp_op().is_some() {
+            return Err(self.error("comparisons can't be chained, use '&&' instead"));
+        }
+
+        let span = left.span().to(right.span());
+        Ok(Box::new(Expr::Binary {
+            left,
+            op,
+            right,
+            span,
+        }))
+    }
+
+    fn range(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let left = self.plus_sub(start)?;
+
+        if !self.expect(Token::DotDot) {
+            return Ok(left);
+        }
+
+        if self.is_at_end() {
+            return Err(self.error("expect a expression after '..'"));
+        }
+
+        let start = self.next_token()?;
+        let right = self.plus_sub(start)?;
+        let span = left.span().to(right.span());
+        Ok(Box::new(Expr::Binary {
+            left,
+            op: BinaryOp::Range,
+            right,
+            span,
+        }))
+    }
+
+    fn cmp_op(&self) -> Option<BinaryOp> {
+        let op = match self.next.as_ref()? {
+            Token::Less => BinaryOp::Less,
+            Token::LessEq => BinaryOp::LessEq,
+            Token::EqEq => BinaryOp::Eq,
+            Token::BangEq => BinaryOp::NotEq,
+            Token::Greater => BinaryOp::Greater,
+            Token::GreaterEq => BinaryOp::GreaterEq,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    fn plus_sub(&mut self, start: Token) -> CalcResult<Box<Expr>> {
         let mut left = self.mult_div(start)?;
 
         while self.check(Token::Plus) || self.check(Token::Minus) {
             let op = match self.next().unwrap() {
                 Token::Plus => BinaryOp::Plus,
                 Token::Minus => BinaryOp::Sub,
-                _ => BinaryOp::Plus, // impossible
+                _ => unreachable!(),
             };
 
             if self.is_at_end() {
-                print_err!("expect a expression after '+' or '-'");
-                return None;
+                return Err(self.error("expect a expression after '+' or '-'"));
             }
 
-            let start = self.next().unwrap();
+            let start = self.next_token()?;
             let right = self.mult_div(start)?;
-            left = Box::new(Expr::Binary { left, op, right })
+            let span = left.span().to(right.span());
+            left = Box::new(Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            })
         }
 
-        Some(left)
+        Ok(left)
     }
 
-    fn mult_div(&mut self, start: Token) -> Option<Box<Expr>> {
+    fn mult_div(&mut self, start: Token) -> CalcResult<Box<Expr>> {
         let mut left = self.minus(start)?;
 
-        while self.check(Token::Star) || self.check(Token::Slash) {
+        while self.check(Token::Star) || self.check(Token::Slash) || self.check(Token::At) {
             let op = match self.next().unwrap() {
                 Token::Star => BinaryOp::Mult,
                 Token::Slash => BinaryOp::Div,
-                _ => BinaryOp::Mult, // impossible
+                Token::At => BinaryOp::MatMul,
+                _ => unreachable!(),
             };
 
             if self.is_at_end() {
-                print_err!("expect a expression after '*' or '/'");
-                return None;
+                return Err(self.error("expect a expression after '*', '/' or '@'"));
             }
 
-            let start = self.next().unwrap();
+            let start = self.next_token()?;
             let right = self.minus(start)?;
-            left = Box::new(Expr::Binary { left, op, right })
+            let span = left.span().to(right.span());
+            left = Box::new(Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            })
         }
 
-        Some(left)
+        Ok(left)
     }
 
-    fn minus(&mut self, start: Token) -> Option<Box<Expr>> {
-        if let Token::Minus = start {
-            let op = UnaryOp::Minus;
+    fn minus(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        if let Token::Minus | Token::Bang = start {
+            let begin = self.prev;
+            let (op, name) = match start {
+                Token::Minus => (UnaryOp::Minus, '-'),
+                _ => (UnaryOp::Not, '!'),
+            };
 
             if self.is_at_end() {
-                print_err!("expect a expression after '-'");
-                return None;
+                return Err(self.error(format!("expect a expression after '{}'", name)));
             }
 
-            let start = self.next().unwrap();
+            let start = self.next_token()?;
             let operand = self.minus(start)?;
-            return Some(Box::new(Expr::Unary { op, operand }));
+            let span = begin.to(operand.span());
+            return Ok(Box::new(Expr::Unary { op, operand, span }));
         }
 
         self.square(start)
     }
 
-    fn square(&mut self, start: Token) -> Option<Box<Expr>> {
+    fn square(&mut self, start: Token) -> CalcResult<Box<Expr>> {
         let mut left = self.factorial(start)?;
 
         while self.check(Token::Square) {
@@ -349,104 +754,242 @@ impl<T: Iterator<Item = Token>> Parser<T> {
             let op = BinaryOp::Square;
 
             if self.is_at_end() {
-                print_err!("expect a expression after '^'");
-                return None;
+                return Err(self.error("expect a expression after '^'"));
             }
 
-            let start = self.next().unwrap();
+            let start = self.next_token()?;
             let right = self.minus(start)?;
-            left = Box::new(Expr::Binary { left, op, right })
+            let span = left.span().to(right.span());
+            left = Box::new(Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            })
         }
 
-        Some(left)
+        Ok(left)
     }
 
-    fn factorial(&mut self, start: Token) -> Option<Box<Expr>> {
+    fn factorial(&mut self, start: Token) -> CalcResult<Box<Expr>> {
         let mut operand = self.call(start)?;
 
         if self.check(Token::Bang) {
             let op = UnaryOp::Ftl;
             self.eat();
-            operand = Box::new(Expr::Unary { op, operand });
+            let span = operand.span().to(self.prev);
+            operand = Box::new(Expr::Unary { op, operand, span });
         }
-        Some(operand)
+        Ok(operand)
     }
 
-    fn call(&mut self, start: Token) -> Option<Box<Expr>> {
-        if let (Token::Ident(_), Some(Token::LeftParen)) = (&start, &self.next) {
-            let idx = if let Token::Ident(idx) = start {
-                idx
-            } else {
-                // never reach
-                print_err!("expect a name to call a function");
-                return None;
-            };
+    fn call(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        if let (Token::Ident(idx), Some(Token::LeftParen)) = (&start, &self.next) {
+            let idx = *idx;
+            let begin = self.prev;
             self.eat();
 
-            let mut values = Vec::new();
+            let values = self.args()?;
 
-            while !self.check(Token::RightParen) {
-                let t = self.next()?;
-                let expr = *self.expr(t)?;
-                values.push(expr);
+            let span = begin.to(self.prev);
+            return Ok(Box::new(Expr::Call {
+                idx,
+                args: values,
+                span,
+            }));
+        }
 
-                if self.check(Token::Comma) {
-                    self.eat();
-                }
+        if let Token::If = start {
+            let begin = self.prev;
+            if !self.expect(Token::LeftParen) {
+                return Err(self.error("expect '(' after 'if'"));
             }
 
-            if !self.expect(Token::RightParen) {
-                print_err!("missing ')'");
-                return None;
+            let mut values = self.args()?;
+
+            let span = begin.to(self.prev);
+            if values.len() != 3 {
+                return Err(CalcError::new(
+                    ErrorKind::Arity,
+                    format!(
+                        "if need 3 args: condition, then, else, but get {}",
+                        values.len()
+                    ),
+                )
+                .with_name(Some("if"))
+                .with_span(span));
             }
 
-            return Some(Box::new(Expr::Call { idx, args: values }));
+            let other = Box::new(values.pop().unwrap());
+            let then = Box::new(values.pop().unwrap());
+            let cond = Box::new(values.pop().unwrap());
+            return Ok(Box::new(Expr::Cond {
+                cond,
+                then,
+                other,
+                span,
+            }));
         }
 
         self.primary(start)
     }
 
-    fn primary(&mut self, start: Token) -> Option<Box<Expr>> {
+    // the args of a call after the '(', the ',' between them is optional
+    fn args(&mut self) -> CalcResult<Vec<Expr>> {
+        let mut values = Vec::new();
+
+        while !self.check(Token::RightParen) {
+            if self.is_at_end() {
+                return Err(self.error("missing ')'"));
+            }
+            let t = self.next_token()?;
+            let expr = *self.expr(t)?;
+            values.push(expr);
+
+            if self.check(Token::Comma) {
+                self.eat();
+            }
+        }
+
+        if !self.expect(Token::RightParen) {
+            return Err(self.error("missing ')'"));
+        }
+
+        Ok(values)
+    }
+
+    fn primary(&mut self, start: Token) -> CalcResult<Box<Expr>> {
+        let span = self.prev;
         match start {
             Token::Ident(idx) => {
                 if let Some(i) = self.args.get(&idx) {
-                    Some(Box::new(Expr::Literal {
+                    Ok(Box::new(Expr::Literal {
                         value: Valuable::Arg(*i),
+                        span,
                     }))
                 } else {
-                    Some(Box::new(Expr::Literal {
+                    Ok(Box::new(Expr::Literal {
                         value: Valuable::Var(idx),
+                        span,
                     }))
                 }
             }
-            Token::Number(num) => Some(Box::new(Expr::Literal {
-                value: Valuable::Value(num),
+            Token::Number(num, exact) => {
+                // '3 km', unless 'km' is an arg of the function
+                let unit = match self.next {
+                    Some(Token::Ident(idx)) if self.is_unit(idx) => {
+                        self.eat();
+                        Some(self.unit(idx, true)?)
+                    }
+                    _ => None,
+                };
+                let value = match unit {
+                    Some(unit) => Valuable::Quantity(num, Box::new(unit)),
+                    None => Valuable::Value(num, exact),
+                };
+                Ok(Box::new(Expr::Literal {
+                    value,
+                    span: span.to(self.prev),
+                }))
+            }
+            Token::Imag(num) => Ok(Box::new(Expr::Literal {
+                value: Valuable::Imag(num),
+                span,
             })),
             Token::LeftParen => {
-                let start = self.next()?;
+                let start = self.next_token()?;
                 let v = self.expr(start)?;
                 if !self.expect(Token::RightParen) {
-                    print_err!("missing ')'");
-                    return None;
+                    return Err(self.error("missing ')'"));
                 }
-                Some(Box::new(Expr::Group { body: v }))
+                Ok(Box::new(Expr::Group {
+                    body: v,
+                    span: span.to(self.prev),
+                }))
             }
-            _ => {
-                print_err!("invalid syntax");
-                None
+            Token::LeftBrace => self.block(),
+            Token::LeftBracket => {
+                let mut items = Vec::new();
+                while !self.check(Token::RightBracket) {
+                    if self.is_at_end() {
+                        return Err(self.error("missing ']'"));
+                    }
+                    let t = self.next_token()?;
+                    items.push(*self.expr(t)?);
+
+                    if self.check(Token::Comma) {
+                        self.eat();
+                    }
+                }
+                self.eat();
+                Ok(Box::new(Expr::List {
+                    items,
+                    span: span.to(self.prev),
+                }))
             }
+            Token::Unknown(id) => Err(self.unknown(id, span)),
+            _ => Err(self.error_at(span, "invalid syntax")),
         }
     }
 
-    fn magic_plot(&mut self) {
-        let args = vec![
-            MagicArg::Idx,
-            MagicArg::Expr,
-            MagicArg::Expr,
-            MagicArg::Expr,
-        ];
-        let hash = hash_it(&"plot2d");
-        self.magic.insert(hash, args);
+    fn is_unit(&self, idx: u64) -> bool {
+        !self.args.contains_key(&idx) && self.find_name(idx).and_then(units::lookup).is_some()
+    }
+
+    // a unit like 'km/h' or 'kg*m/s^2' after its first name is consumed,
+    // after a number it only goes on while there are no spaces, so in
+    // '3 km / 20 min' the unit is only 'km'
+    fn unit(&mut self, idx: u64, tight: bool) -> CalcResult<Unit> {
+        let mut unit = self.unit_pow(idx, tight)?;
+        loop {
+            let div = match self.next {
+                Some(Token::Star) => false,
+                Some(Token::Slash) => true,
+                _ => return Ok(unit),
+            };
+            let (idx, span) = match self.tokens.peek() {
+                Some((Token::Ident(idx), span)) => (*idx, *span),
+                _ => return Ok(unit),
+            };
+            let joined = self.prev.end == self.next_span.start
+                && self.next_span.end == span.start
+                && self.is_unit(idx);
+            if tight && !joined {
+                return Ok(unit);
+            }
+            // the '*' or '/', and then the name
+            self.eat();
+            self.eat();
+            let other = self.unit_pow(idx, tight)?;
+            unit = if div {
+                unit.div(&other)
+            } else {
+                unit.mul(&other)
+            };
+        }
+    }
+
+    fn unit_pow(&mut self, idx: u64, tight: bool) -> CalcResult<Unit> {
+        let name = self.find_name(idx).unwrap_or("Unknown");
+        let unit = units::lookup(name).ok_or_else(|| {
+            CalcError::new(
+                ErrorKind::UnknownName,
+                format!("can't find unit named '{}'", name),
+            )
+            .with_name(Some(name))
+            .with_span(self.prev)
+        })?;
+        if !self.check(Token::Square) || (tight && self.prev.end != self.next_span.start) {
+            return Ok(unit);
+        }
+        self.eat();
+        let minus = self.expect(Token::Minus);
+        match self.next() {
+            Some(Token::Number(n, _)) if n.fract() == 0.0 && n <= 16.0 => {
+                Ok(unit.powi(if minus { -(n as i32) } else { n as i32 }))
+            }
+            _ => Err(self.error_at(self.prev, "expect a small integer power of a unit")),
+        }
     }
 
     fn find_name(&self, idx: u64) -> Option<&str> {
@@ -457,14 +1000,47 @@ impl<T: Iterator<Item = Token>> Parser<T> {
         }
     }
 
+    // an error at the token we are looking at
+    fn error(&self, message: impl Into<String>) -> CalcError {
+        self.error_at(self.next_span, message)
+    }
+
+    fn error_at(&self, span: Span, message: impl Into<String>) -> CalcError {
+        CalcError::new(ErrorKind::Parse, message).with_span(span)
+    }
+
+    fn unknown(&self, id: u64, span: Span) -> CalcError {
+        let lexme = self.find_name(id);
+        CalcError::new(
+            ErrorKind::Lex,
+            format!("invalid syntax: {}", lexme.unwrap_or("")),
+        )
+        .with_name(lexme)
+        .with_span(span)
+    }
+
     fn next(&mut self) -> Option<Token> {
         let next = self.next.take();
         self.eat();
         next
     }
 
+    // the scanner always ends the tokens with Eof, so running out of
+    // tokens only happens after Eof has been consumed
+    fn next_token(&mut self) -> CalcResult<Token> {
+        self.next()
+            .ok_or_else(|| self.error("unexpected end of input"))
+    }
+
     fn eat(&mut self) {
-        self.next = self.tokens.next();
+        self.prev = self.next_span;
+        match self.tokens.next() {
+            Some((token, span)) => {
+                self.next = Some(token);
+                self.next_span = span;
+            }
+            None => self.next = None,
+        }
     }
 
     fn check(&self, token: Token) -> bool {
@@ -478,8 +1054,9 @@ impl<T: Iterator<Item = Token>> Parser<T> {
         }
     }
 
+    // the end of the statement
     fn is_at_end(&self) -> bool {
-        self.check(Token::Eof)
+        self.check(Token::Eof) || self.check(Token::Semi)
     }
 
     fn expect(&mut self, token: Token) -> bool {
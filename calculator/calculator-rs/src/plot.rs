@@ -0,0 +1,516 @@
+// Draw curves in braille, scaled into a viewport that fits the terminal.
+use std::env;
+
+use drawille::Canvas;
+
+use crate::error::{CalcError, CalcResult, ErrorKind};
+
+// used when neither $COLUMNS and $LINES nor the tty tell the size
+const DEFAULT_COLUMNS: usize = 80;
+const DEFAULT_LINES: usize = 24;
+// the lines under the plot: x axis, x labels, legend and the next prompt
+const MARGIN_LINES: usize = 4;
+const MIN_WIDTH: usize = 10;
+const MIN_HEIGHT: usize = 5;
+
+// the colors of the curves, in order
+const COLORS: [&str; 6] = [
+    "\x1b[31m", "\x1b[32m", "\x1b[34m", "\x1b[33m", "\x1b[35m", "\x1b[36m",
+];
+const RESET: &str = "\x1b[0m";
+
+// braille characters starts at 0x2800, each dot is a bit
+const BRAILLE: u32 = 0x2800;
+
+/// A curve is its name and its points, a point that isn't finite breaks
+/// the line.
+pub(crate) struct Curve {
+    pub(crate) name: String,
+    pub(crate) points: Vec<(f64, f64)>,
+}
+
+pub(crate) struct Figure {
+    pub(crate) curves: Vec<Curve>,
+    // the ranges to show, or from the points when they are None
+    pub(crate) x_range: Option<(f64, f64)>,
+    pub(crate) y_range: Option<(f64, f64)>,
+    // the same scale on both axes, for curves that aren't functions of x
+    pub(crate) equal: bool,
+    // the size of the terminal, in characters
+    pub(crate) columns: usize,
+    pub(crate) lines: usize,
+    // color the curves and the legend with ANSI escapes
+    pub(crate) color: bool,
+}
+
+/// The size of the terminal, `$COLUMNS` and `$LINES` win over what the tty
+/// tells, 80x24 if there is no tty.
+pub(crate) fn terminal_size() -> (usize, usize) {
+    let get = |name| {
+        env::var(name)
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .filter(|&v: &usize| v > 0)
+    };
+    let (columns, lines) = tty_size().unwrap_or((DEFAULT_COLUMNS, DEFAULT_LINES));
+    (
+        get("COLUMNS").unwrap_or(columns),
+        get("LINES").unwrap_or(lines),
+    )
+}
+
+// the size of the tty on stdout, None when stdout is a file or a pipe
+#[cfg(unix)]
+fn tty_size() -> Option<(usize, usize)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes a winsize to the pointer given
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    (ok && size.ws_col > 0 && size.ws_row > 0)
+        .then_some((size.ws_col as usize, size.ws_row as usize))
+}
+
+#[cfg(not(unix))]
+fn tty_size() -> Option<(usize, usize)> {
+    None
+}
+
+// the smallest and largest of `values`, widened when they are the same
+fn range(values: impl Iterator<Item = f64>) -> Option<(f64, f64)> {
+    let (lo, hi) = values
+        .filter(|v| v.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+            (lo.min(v), hi.max(v))
+        });
+    if lo > hi {
+        None
+    } else if lo == hi {
+        let pad = if lo == 0.0 { 1.0 } else { lo.abs() / 10.0 };
+        Some((lo - pad, hi + pad))
+    } else {
+        Some((lo, hi))
+    }
+}
+
+/// A short label for an axis, about 4 significant digits.
+fn tick(v: f64) -> String {
+    if v == 0.0 {
+        return "0".to_string();
+    }
+    let digits = v.abs().log10().floor() as i32;
+    if !(-4..6).contains(&digits) {
+        return format!("{:.2e}", v);
+    }
+    let s = format!("{:.*}", (3 - digits).max(0) as usize, v);
+    let s = if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &s
+    };
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+// a line between two pixels, `Canvas::line` can't draw one point
+fn segment(c: &mut Canvas, (x1, y1): (f64, f64), (x2, y2): (f64, f64)) {
+    if x1.round() == x2.round() && y1.round() == y2.round() {
+        c.set(x1, y1);
+    } else {
+        c.line(x1, y1, x2, y2);
+    }
+}
+
+// the dots of each character of the canvas
+fn dots(c: &mut Canvas, width: usize) -> Vec<Vec<u32>> {
+    c.frame()
+        .lines()
+        .map(|row| {
+            let mut row: Vec<u32> = row.chars().map(|ch| ch as u32 - BRAILLE).collect();
+            row.resize(width, 0);
+            row
+        })
+        .collect()
+}
+
+// the labels go on the left of the rows at the top and bottom, and of the
+// row with the x axis, or else the middle one
+fn labels((y0, y1): (f64, f64), height: usize) -> [(usize, String); 3] {
+    let px_h = (height * 4) as f64;
+    let mid = if y0 < 0.0 && 0.0 < y1 {
+        0.0
+    } else {
+        (y0 + y1) / 2.0
+    };
+    let mid_row = ((y1 - mid) / (y1 - y0) * (px_h - 1.0)).round() as usize / 4;
+    [(0, tick(y1)), (mid_row, tick(mid)), (height - 1, tick(y0))]
+}
+
+// widen one of the ranges so a dot is as long across as it is high, a
+// braille dot is about square, so a circle stays round
+fn equal(x: (f64, f64), y: (f64, f64), px_w: f64, px_h: f64) -> ((f64, f64), (f64, f64)) {
+    let scale = ((x.1 - x.0) / (px_w - 1.0)).max((y.1 - y.0) / (px_h - 1.0));
+    let widen = |(lo, hi): (f64, f64), px: f64| {
+        let (mid, half) = ((lo + hi) / 2.0, scale * (px - 1.0) / 2.0);
+        (mid - half, mid + half)
+    };
+    (widen(x, px_w), widen(y, px_h))
+}
+
+impl Figure {
+    pub(crate) fn render(&self) -> CalcResult<String> {
+        let points = || self.curves.iter().flat_map(|c| c.points.iter());
+        let nothing = || {
+            CalcError::new(
+                ErrorKind::Domain,
+                "nothing to plot, all the values are NaN or infinite",
+            )
+        };
+        let x_range = match self.x_range {
+            Some(r) => r,
+            None => range(points().filter(|p| p.1.is_finite()).map(|p| p.0)).ok_or_else(nothing)?,
+        };
+        let y_range = match self.y_range {
+            Some(r) => r,
+            None => range(
+                points()
+                    .filter(|p| p.0.is_finite() && x_range.0 <= p.0 && p.0 <= x_range.1)
+                    .map(|p| p.1),
+            )
+            .ok_or_else(nothing)?,
+        };
+
+        // the width of the labels depends on the y range, which depends on
+        // the width when the scales are equal, it settles in a few rounds
+        let height = self.lines.saturating_sub(MARGIN_LINES).max(MIN_HEIGHT);
+        let px_h = (height * 4) as f64;
+        let mut label_width = 0;
+        let (width, (x0, x1), (y0, y1), labels) = loop {
+            let width = self.columns.saturating_sub(label_width + 2).max(MIN_WIDTH);
+            let (x, y) = if self.equal {
+                equal(x_range, y_range, (width * 2) as f64, px_h)
+            } else {
+                (x_range, y_range)
+            };
+            let labels = labels(y, height);
+            let new_width = labels.iter().map(|(_, l)| l.len()).max().unwrap_or(0);
+            if new_width <= label_width {
+                break (width, x, y, labels);
+            }
+            label_width = new_width;
+        };
+        let px_w = (width * 2) as f64;
+
+        let to_pixel = |(x, y): (f64, f64)| {
+            (
+                ((x - x0) / (x1 - x0) * (px_w - 1.0)).clamp(0.0, px_w - 1.0),
+                ((y1 - y) / (y1 - y0) * (px_h - 1.0)).clamp(0.0, px_h - 1.0),
+            )
+        };
+
+        // the axes where x or y is 0, under the curves
+        let mut axes = Canvas::with_capcity(width, height);
+        if y0 < 0.0 && 0.0 < y1 {
+            segment(&mut axes, to_pixel((x0, 0.0)), to_pixel((x1, 0.0)));
+        }
+        if x0 < 0.0 && 0.0 < x1 {
+            segment(&mut axes, to_pixel((0.0, y0)), to_pixel((0.0, y1)));
+        }
+        let axes = dots(&mut axes, width);
+
+        let curves = self
+            .curves
+            .iter()
+            .map(|curve| {
+                let mut c = Canvas::with_capcity(width, height);
+                let mut last = None;
+                for &(x, y) in &curve.points {
+                    let inside = x0 <= x && x <= x1 && y0 <= y && y <= y1;
+                    if !inside {
+                        last = None;
+                        continue;
+                    }
+                    let p = to_pixel((x, y));
+                    segment(&mut c, last.unwrap_or(p), p);
+                    last = Some(p);
+                }
+                dots(&mut c, width)
+            })
+            .collect::<Vec<_>>();
+
+        let mut out = String::new();
+        for row in 0..height {
+            match labels.iter().find(|(r, _)| *r == row) {
+                Some((_, label)) => out += &format!("{:>w$} ┤", label, w = label_width),
+                None => out += &format!("{:w$} │", "", w = label_width),
+            }
+            for col in 0..width {
+                let mut bits = axes[row][col];
+                // the last curve on a character gives its color
+                let mut color = None;
+                for (i, curve) in curves.iter().enumerate() {
+                    if curve[row][col] != 0 {
+                        bits |= curve[row][col];
+                        color = Some(COLORS[i % COLORS.len()]);
+                    }
+                }
+                let ch = char::from_u32(BRAILLE + bits).unwrap_or(' ');
+                match color {
+                    Some(color) if self.color => out += &format!("{}{}{}", color, ch, RESET),
+                    _ => out.push(ch),
+                }
+            }
+            out.push('\n');
+        }
+
+        out += &format!("{:w$} └{}\n", "", "─".repeat(width), w = label_width);
+        let mut axis = vec![' '; label_width + 2 + width];
+        let (start, end) = (tick(x0), tick(x1));
+        let end_at = axis.len().saturating_sub(end.len()).max(start.len() + 1);
+        axis.resize(axis.len().max(end_at + end.len()), ' ');
+        axis.splice(
+            label_width + 1..label_width + 1 + start.len(),
+            start.chars(),
+        );
+        axis.splice(end_at..end_at + end.len(), end.chars());
+        out += axis.iter().collect::<String>().trim_end();
+
+        // the names are shown without colors too, in the order of the curves
+        if self.curves.len() > 1 {
+            out.push('\n');
+            out += &format!("{:w$}", "", w = label_width + 2);
+            for (i, curve) in self.curves.iter().enumerate() {
+                if self.color {
+                    let color = COLORS[i % COLORS.len()];
+                    out += &format!("{}──{} {}  ", color, RESET, curve.name);
+                } else {
+                    out += &format!("── {}  ", curve.name);
+                }
+            }
+            out.truncate(out.trim_end().len());
+        }
+        Ok(out)
+    }
+}
+
+/// The line where `f(x, y) = 0` by marching squares over an `nx` x `ny`
+/// grid, as segments split by NaN points.
+pub(crate) fn contour(
+    f: impl Fn(f64, f64) -> CalcResult<f64>,
+    (x0, x1): (f64, f64),
+    (y0, y1): (f64, f64),
+    (nx, ny): (usize, usize),
+) -> CalcResult<Vec<(f64, f64)>> {
+    let at = |i: usize, n: usize, lo: f64, hi: f64| lo + (hi - lo) * i as f64 / n as f64;
+    let mut grid = Vec::with_capacity((nx + 1) * (ny + 1));
+    for j in 0..=ny {
+        for i in 0..=nx {
+            grid.push(f(at(i, nx, x0, x1), at(j, ny, y0, y1))?);
+        }
+    }
+
+    let mut points = Vec::new();
+    for j in 0..ny {
+        for i in 0..nx {
+            // the corners go around the cell, from its bottom left
+            let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+            let v = corners.map(|(i, j)| grid[j * (nx + 1) + i]);
+            if v.iter().any(|v| !v.is_finite()) {
+                continue;
+            }
+            // where the edges from each corner to the next cross 0
+            let mut cross = Vec::new();
+            for k in 0..4 {
+                let (a, b) = (k, (k + 1) % 4);
+                if (v[a] > 0.0) != (v[b] > 0.0) {
+                    let t = v[a] / (v[a] - v[b]);
+                    let (ia, ja) = corners[a];
+                    let (ib, jb) = corners[b];
+                    let i = ia as f64 + (ib as f64 - ia as f64) * t;
+                    let j = ja as f64 + (jb as f64 - ja as f64) * t;
+                    cross.push((
+                        x0 + (x1 - x0) * i / nx as f64,
+                        y0 + (y1 - y0) * j / ny as f64,
+                    ));
+                }
+            }
+            let segments = match cross[..] {
+                [a, b] => vec![(a, b)],
+                // a saddle, the middle of the cell tells which corners the
+                // line cuts off
+                [a, b, c, d] => {
+                    let mid = v.iter().sum::<f64>() / 4.0;
+                    if (mid > 0.0) == (v[0] > 0.0) {
+                        vec![(a, b), (c, d)]
+                    } else {
+                        vec![(d, a), (b, c)]
+                    }
+                }
+                _ => vec![],
+            };
+            for (a, b) in segments {
+                points.extend([a, b, (f64::NAN, f64::NAN)]);
+            }
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Env, Output};
+
+    // the plot a magic gives to the host, it prints nothing itself
+    fn shown(env: &mut Env, s: &str) -> String {
+        let mut outputs = Vec::new();
+        env.run_each(s, |_, output| outputs.push(output)).unwrap();
+        match outputs.pop() {
+            Some(Output::Text(plot)) if outputs.is_empty() => plot,
+            _ => panic!("{} gives no plot", s),
+        }
+    }
+
+    fn figure(curves: Vec<Curve>) -> Figure {
+        Figure {
+            curves,
+            x_range: None,
+            y_range: None,
+            equal: false,
+            columns: 30,
+            lines: 9,
+            color: false,
+        }
+    }
+
+    #[test]
+    fn test_plot() {
+        assert_eq!(tick(0.0), "0");
+        assert_eq!(tick(72.0), "72");
+        assert_eq!(tick(-1.5), "-1.5");
+        assert_eq!(tick(1.0 / 3.0), "0.3333");
+        assert_eq!(tick(1e9), "1.00e9");
+
+        // y = x from -1 to 1, the x axis crosses the middle
+        let points = (-10..=10)
+            .map(|i| (i as f64 / 10.0, i as f64 / 10.0))
+            .collect();
+        let plot = figure(vec![Curve {
+            name: "f".to_string(),
+            points,
+        }])
+        .render()
+        .unwrap();
+        let lines: Vec<&str> = plot.lines().collect();
+        assert_eq!(lines.len(), 5 + 2);
+        assert!(lines[0].starts_with(" 1 ┤"));
+        assert!(lines[4].starts_with("-1 ┤"));
+        assert!(lines[5].starts_with("   └─"));
+        assert!(lines[6].starts_with("   -1"));
+        assert!(lines[6].ends_with(" 1"));
+        // every row is as wide as the terminal
+        assert!(lines[..5].iter().all(|l| l.chars().count() == 30));
+        // the line goes up to the right, math way
+        assert_ne!(lines[0].chars().last(), Some('\u{2800}'));
+        assert_ne!(lines[4].chars().nth(4), Some('\u{2800}'));
+
+        // a gap for the values that aren't finite, two curves with colors
+        let mut f = figure(vec![
+            Curve {
+                name: "f".to_string(),
+                points: vec![(0.0, 1.0), (1.0, f64::NAN), (2.0, 3.0)],
+            },
+            Curve {
+                name: "g".to_string(),
+                points: vec![(0.0, 3.0), (2.0, 1.0)],
+            },
+        ]);
+        let plot = f.render().unwrap();
+        assert!(!plot.contains('\x1b'));
+        assert!(plot.ends_with("── f  ── g"));
+        f.color = true;
+        let plot = f.render().unwrap();
+        assert!(plot.contains(COLORS[0]) && plot.contains(COLORS[1]));
+        assert!(plot.ends_with("g"));
+
+        let nan = figure(vec![Curve {
+            name: "f".to_string(),
+            points: vec![(0.0, f64::NAN)],
+        }]);
+        assert_eq!(nan.render().unwrap_err().kind(), ErrorKind::Domain);
+
+        let mut env = Env::new();
+        env.run("fun f(x) = x^2; fun g(x) = 1 / x; fun h(x y) = x")
+            .unwrap();
+        assert!(env.run("%plot2d([f, g], -1, 1)").unwrap().is_none());
+        let braille = |c| ('\u{2801}'..='\u{28ff}').contains(&c);
+        assert!(shown(&mut env, "%plot2d(f, 0, 1, 0.1)").contains(braille));
+        for (s, kind) in [
+            ("%plot2d(f, 1, 0)", ErrorKind::Domain),
+            ("%plot2d(f, 0, 1, -1)", ErrorKind::Domain),
+            ("%plot2d([f, h], 0, 1)", ErrorKind::Arity),
+            ("%plot2d([f, k], 0, 1)", ErrorKind::UnknownName),
+            ("%plot2d([f g], 0, 1)", ErrorKind::Parse),
+        ] {
+            assert_eq!(env.run(s).unwrap_err().kind(), kind, "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_contour() {
+        // a circle of radius 2, every point of it is on the line
+        let circle = |x: f64, y: f64| Ok(x * x + y * y - 4.0);
+        let points = contour(circle, (-3.0, 3.0), (-3.0, 3.0), (40, 40)).unwrap();
+        let mut on_line = points.iter().filter(|p| p.0.is_finite());
+        assert!(on_line.clone().count() > 40);
+        assert!(on_line.all(|(x, y)| (x.hypot(*y) - 2.0).abs() < 0.05));
+        let never = |x: f64, y: f64| Ok(x * x + y * y + 1.0);
+        assert!(contour(never, (-3.0, 3.0), (-3.0, 3.0), (8, 8))
+            .unwrap()
+            .is_empty());
+
+        // the scales are equal, so the x range of a circle is widened
+        let figure = Figure {
+            curves: vec![Curve {
+                name: String::new(),
+                points: contour(circle, (-3.0, 3.0), (-3.0, 3.0), (40, 40)).unwrap(),
+            }],
+            x_range: None,
+            y_range: None,
+            equal: true,
+            columns: 30,
+            lines: 9,
+            color: false,
+        };
+        let plot = figure.render().unwrap();
+        let start = plot.lines().last().unwrap().split_whitespace().next();
+        assert!(start.unwrap().parse::<f64>().unwrap() < -3.0);
+
+        let mut env = Env::new();
+        env.run("fun c(t) = cos(t); fun s(t) = sin(t); fun h(x y) = x^2 + y^2 - 1")
+            .unwrap();
+        for s in [
+            "%plot_param(c, s, 0, 2 * PI)",
+            "%plot_param(c, s, 0, 2 * PI, 0.1)",
+            "%plot_polar(c, 0, PI, 0.01)",
+            "%plot_implicit(h, -2, 2, -2, 2)",
+        ] {
+            assert!(!shown(&mut env, s).is_empty(), "{}", s);
+        }
+        for (s, kind) in [
+            ("%plot_param(c, h, 0, 1)", ErrorKind::Arity),
+            ("%plot_polar(c, 0, 1, 0)", ErrorKind::Domain),
+            ("%plot_implicit(c, -1, 1, -1, 1)", ErrorKind::Arity),
+            ("%plot_implicit(h, 1, -1, -1, 1)", ErrorKind::Domain),
+            ("%plot_implicit(h, 5, 6, 5, 6)", ErrorKind::Domain),
+        ] {
+            assert_eq!(env.run(s).unwrap_err().kind(), kind, "{}", s);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// The random numbers of `rand`, `randint` and `randn`, reproducible after
+// `seed(n)`.
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::f64::consts::PI;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::error::{CalcError, CalcResult, ErrorKind};
+
+/// A splitmix64 generator, small and good enough for Monte Carlo checks.
+pub(crate) struct Rng {
+    state: Cell<u64>,
+}
+
+impl Rng {
+    /// Seeded differently in every run, until `seed` is called.
+    pub(crate) fn new() -> Self {
+        Rng {
+            state: Cell::new(RandomState::new().build_hasher().finish()),
+        }
+    }
+
+    fn seed(&self, seed: u64) {
+        self.state.set(seed);
+    }
+
+    fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // in 0..1, 1 excluded, with all the 53 bits of a f64
+    fn uniform(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // in 0..n, rejecting the top of the range so every number is as likely
+    fn below(&self, n: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return x % n;
+            }
+        }
+    }
+}
+
+// rand(), in 0..1
+pub(crate) fn rand(rng: &Rng, _: &[f64]) -> CalcResult<f64> {
+    Ok(rng.uniform())
+}
+
+// randint(a, b), both ends included
+pub(crate) fn randint(rng: &Rng, args: &[f64]) -> CalcResult<f64> {
+    let (a, b) = (args[0], args[1]);
+    if a.fract() != 0.0 || b.fract() != 0.0 || !a.is_finite() || !b.is_finite() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!("randint need two integers, but get {} and {}", a, b),
+        ));
+    }
+    // beyond 2^53 the integers in between can't all be a f64
+    if a > b || b - a >= (1u64 << 53) as f64 {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "randint need a <= b and less than 2^53 numbers between, but get {} and {}",
+                a, b
+            ),
+        ));
+    }
+    Ok(a + rng.below((b - a) as u64 + 1) as f64)
+}
+
+// randn(), a standard normal one by the Box-Muller transform
+pub(crate) fn randn(rng: &Rng, _: &[f64]) -> CalcResult<f64> {
+    // in (0, 1], so the log is finite
+    let u = 1.0 - rng.uniform();
+    let v = rng.uniform();
+    Ok((-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos())
+}
+
+// seed(n), the same seed gives the same numbers after it
+pub(crate) fn seed(rng: &Rng, args: &[f64]) -> CalcResult<f64> {
+    let n = args[0];
+    if !n.is_finite() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!("the seed must be a finite number, but get {}", n),
+        ));
+    }
+    // +0.0 so -0 and 0 are the same seed
+    rng.seed((n + 0.0).to_bits());
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random() {
+        let rng = Rng::new();
+        seed(&rng, &[42.0]).unwrap();
+        let first: Vec<f64> = (0..5).map(|_| rand(&rng, &[]).unwrap()).collect();
+        seed(&rng, &[42.0]).unwrap();
+        let again: Vec<f64> = (0..5).map(|_| rand(&rng, &[]).unwrap()).collect();
+        assert_eq!(first, again);
+        assert!(first.iter().all(|x| (0.0..1.0).contains(x)));
+
+        let dice: Vec<f64> = (0..1000)
+            .map(|_| randint(&rng, &[1.0, 6.0]).unwrap())
+            .collect();
+        for side in 1..=6 {
+            assert!(dice.contains(&(side as f64)));
+        }
+        assert!(dice.iter().all(|x| (1.0..=6.0).contains(x)));
+        assert_eq!(randint(&rng, &[-3.0, -3.0]), Ok(-3.0));
+        assert!(randint(&rng, &[2.0, 1.0]).is_err());
+        assert!(randint(&rng, &[0.5, 1.0]).is_err());
+
+        let n = 100_000;
+        let xs: Vec<f64> = (0..n).map(|_| randn(&rng, &[]).unwrap()).collect();
+        let mean = xs.iter().sum::<f64>() / n as f64;
+        let var = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n as f64;
+        assert!(mean.abs() < 0.02 && (var - 1.0).abs() < 0.02);
+    }
+}
@@ -0,0 +1,287 @@
+// Print an expression back in the infix form the parser reads.
+use std::fmt::{self, Display};
+
+use num_traits::Signed;
+
+use crate::{
+    ast::{BinaryOp, Expr, UnaryOp, Valuable},
+    env::Env,
+    exact,
+    onemore::OneMore,
+};
+
+/// `expr` with the names of `env`, `params` are the names of the args.
+pub(crate) struct Show<'a> {
+    pub(crate) env: &'a Env,
+    pub(crate) params: &'a [u64],
+    pub(crate) expr: &'a Expr,
+}
+
+// how tight an expression binds, from the grammar in parser.rs
+const CONVERT: u8 = 0;
+const COND: u8 = 1;
+const OR: u8 = 2;
+const AND: u8 = 3;
+const CMP: u8 = 4;
+const RANGE: u8 = 5;
+const PLUS_SUB: u8 = 6;
+const MULT_DIV: u8 = 7;
+const MINUS: u8 = 8;
+const SQUARE: u8 = 9;
+const FACTORIAL: u8 = 10;
+const PRIMARY: u8 = 11;
+
+fn prec(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Convert { .. } | Expr::Lambda { .. } => CONVERT,
+        Expr::Let { block: true, .. } => PRIMARY,
+        Expr::Let { .. } => CONVERT,
+        Expr::Cond { .. } => COND,
+        Expr::Binary { op, .. } => binary_prec(op),
+        Expr::Unary {
+            op: UnaryOp::Ftl, ..
+        } => FACTORIAL,
+        Expr::Unary { .. } => MINUS,
+        Expr::Group { body, .. } => prec(body),
+        Expr::Literal { value, .. } => match value {
+            Valuable::Value(v, exact) if !exact.is_integer() && !is_decimal(*v, exact) => MULT_DIV,
+            Valuable::Value(v, _) | Valuable::Imag(v) if v.is_sign_negative() => MINUS,
+            Valuable::Quantity(..) => MULT_DIV,
+            _ => PRIMARY,
+        },
+        Expr::Call { .. } | Expr::List { .. } => PRIMARY,
+    }
+}
+
+fn binary_prec(op: &BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Or => OR,
+        BinaryOp::And => AND,
+        BinaryOp::Less
+        | BinaryOp::LessEq
+        | BinaryOp::Eq
+        | BinaryOp::NotEq
+        | BinaryOp::Greater
+        | BinaryOp::GreaterEq => CMP,
+        BinaryOp::Range => RANGE,
+        BinaryOp::Plus | BinaryOp::Sub => PLUS_SUB,
+        BinaryOp::Mult | BinaryOp::Div | BinaryOp::MatMul => MULT_DIV,
+        BinaryOp::Square => SQUARE,
+    }
+}
+
+fn binary_str(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Plus => " + ",
+        BinaryOp::Sub => " - ",
+        BinaryOp::Mult => " * ",
+        BinaryOp::Div => " / ",
+        BinaryOp::MatMul => " @ ",
+        BinaryOp::Square => "^",
+        BinaryOp::Less => " < ",
+        BinaryOp::LessEq => " <= ",
+        BinaryOp::Eq => " == ",
+        BinaryOp::NotEq => " != ",
+        BinaryOp::Greater => " > ",
+        BinaryOp::GreaterEq => " >= ",
+        BinaryOp::And => " && ",
+        BinaryOp::Or => " || ",
+        BinaryOp::Range => "..",
+    }
+}
+
+// the float shows the exact value, so it reads back the same
+fn is_decimal(v: f64, exact: &num_rational::BigRational) -> bool {
+    v.is_finite() && exact::parse_decimal(&v.abs().to_string()) == exact.abs()
+}
+
+impl Show<'_> {
+    fn with<'b>(&'b self, expr: &'b Expr) -> Show<'b> {
+        Show {
+            env: self.env,
+            params: self.params,
+            expr,
+        }
+    }
+
+    // `expr` in parentheses unless it binds at least as tight as `min`
+    fn child(&self, f: &mut fmt::Formatter<'_>, expr: &Expr, min: u8) -> fmt::Result {
+        if prec(expr) < min {
+            write!(f, "({})", self.with(expr))
+        } else {
+            write!(f, "{}", self.with(expr))
+        }
+    }
+
+    fn name(&self, idx: u64) -> &str {
+        self.env.find_name(idx).unwrap_or("Unknown")
+    }
+}
+
+impl Display for Show<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expr {
+            Expr::Literal { value, .. } => match value {
+                Valuable::Value(v, exact) => {
+                    if exact.is_integer() {
+                        write!(f, "{}", exact.numer())
+                    } else if is_decimal(*v, exact) {
+                        write!(f, "{}", v)
+                    } else {
+                        write!(f, "{}/{}", exact.numer(), exact.denom())
+                    }
+                }
+                Valuable::Imag(v) => write!(f, "{}i", v),
+                Valuable::Quantity(v, unit) => write!(f, "{} {}", v, unit.name),
+                Valuable::Arg(i) => match self.params.get(*i) {
+                    Some(idx) => write!(f, "{}", self.name(*idx)),
+                    None => write!(f, "${}", i),
+                },
+                Valuable::Var(idx) => write!(f, "{}", self.name(*idx)),
+            },
+            Expr::Group { body, .. } => write!(f, "{}", self.with(body)),
+            Expr::Unary { op, operand, .. } => match op {
+                UnaryOp::Minus => {
+                    write!(f, "-")?;
+                    self.child(f, operand, MINUS)
+                }
+                UnaryOp::Not => {
+                    write!(f, "!")?;
+                    self.child(f, operand, MINUS)
+                }
+                UnaryOp::Ftl => {
+                    self.child(f, operand, PRIMARY)?;
+                    write!(f, "!")
+                }
+            },
+            Expr::Binary {
+                left, op, right, ..
+            } => {
+                let p = binary_prec(op);
+                let (l, r) = match p {
+                    // 'a^b^c' is 'a^(b^c)' and '2^-1' needs no parentheses
+                    SQUARE => (FACTORIAL, MINUS),
+                    CMP | RANGE => (p + 1, p + 1),
+                    _ => (p, p + 1),
+                };
+                self.child(f, left, l)?;
+                write!(f, "{}", binary_str(op))?;
+                self.child(f, right, r)
+            }
+            Expr::Cond {
+                cond, then, other, ..
+            } => {
+                self.child(f, cond, OR)?;
+                write!(f, " ? {} : ", self.with(then))?;
+                self.child(f, other, COND)
+            }
+            Expr::Call { idx, args, .. } => {
+                write!(f, "{}(", self.name(*idx))?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", self.with(arg))?;
+                }
+                write!(f, ")")
+            }
+            Expr::List { items, .. } => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", self.with(item))?;
+                }
+                write!(f, "]")
+            }
+            Expr::Convert { expr, unit, .. } => {
+                self.child(f, expr, COND)?;
+                write!(f, " in {}", unit.name)
+            }
+            Expr::Lambda { params, body, .. } => {
+                let names: Vec<&str> = params.iter().map(|idx| self.name(*idx)).collect();
+                match names[..] {
+                    [name] => write!(f, "{} -> ", name)?,
+                    _ => write!(f, "({}) -> ", names.join(", "))?,
+                }
+                // the params are the args after the ones in scope
+                let params: Vec<u64> = self.params.iter().chain(params).copied().collect();
+                let body = Show {
+                    env: self.env,
+                    params: &params,
+                    expr: body,
+                };
+                write!(f, "{}", body)
+            }
+            Expr::Let {
+                bindings,
+                body,
+                block,
+                ..
+            } => {
+                // each value sees the names before it, like the body
+                let mut params = self.params.to_vec();
+                write!(f, "{}", if *block { "{ " } else { "let " })?;
+                for (i, (idx, value)) in bindings.iter().enumerate() {
+                    if i != 0 && !*block {
+                        write!(f, ", ")?;
+                    }
+                    let value = Show {
+                        env: self.env,
+                        params: &params,
+                        expr: value,
+                    };
+                    // the 'in' of a let value would end it
+                    if !*block && prec(value.expr) < COND {
+                        write!(f, "{} = ({})", self.name(*idx), value)?;
+                    } else {
+                        write!(f, "{} = {}", self.name(*idx), value)?;
+                    }
+                    if *block {
+                        write!(f, "; ")?;
+                    }
+                    params.push(*idx);
+                }
+                let body = Show {
+                    env: self.env,
+                    params: &params,
+                    expr: body,
+                };
+                if *block {
+                    write!(f, "{} }}", body)
+                } else {
+                    write!(f, " in {}", body)
+                }
+            }
+        }
+    }
+}
+
+/// A value as an expression which gives it back, `inf` and `NaN` can't be
+/// written as literals so they are divisions by 0.
+pub(crate) fn value_source(value: &OneMore) -> String {
+    fn real(v: f64) -> String {
+        if v.is_nan() {
+            "0/0".to_owned()
+        } else if v.is_infinite() {
+            format!("{}1/0", if v < 0.0 { "-" } else { "" })
+        } else {
+            v.to_string()
+        }
+    }
+    match value {
+        OneMore::One(v) => real(*v),
+        OneMore::More(v) => format!(
+            "[{}]",
+            v.iter().map(|v| real(*v)).collect::<Vec<_>>().join(", ")
+        ),
+        OneMore::Matrix(m) => {
+            let rows: Vec<String> = (0..m.rows())
+                .map(|i| value_source(&OneMore::More(m.row(i).to_vec())))
+                .collect();
+            format!("[{}]", rows.join(", "))
+        }
+        v => v.to_string(),
+    }
+}
@@ -0,0 +1,385 @@
+// Numeric solvers on user functions, for `%root`, `%integrate`, `%minimize`
+// and `%solve`.
+use crate::error::{CalcError, CalcResult, ErrorKind};
+
+const MAX_ITER: usize = 200;
+// intervals of the adaptive integration
+const MAX_INTERVALS: usize = 1000;
+const ABS_TOL: f64 = 1e-12;
+const REL_TOL: f64 = 1e-12;
+
+/// A zero of `f` between `a` and `b`, where `f(a)` and `f(b)` have different
+/// signs, by Brent's method.
+pub(crate) fn root(mut f: impl FnMut(f64) -> CalcResult<f64>, a: f64, b: f64) -> CalcResult<f64> {
+    let (mut a, mut b) = (a, b);
+    let (mut fa, mut fb) = (finite(&mut f, a)?, finite(&mut f, b)?);
+    if fa == 0.0 {
+        return Ok(a);
+    }
+    if fb == 0.0 {
+        return Ok(b);
+    }
+    if fa.signum() == fb.signum() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "the function must have different signs at {} and {}, but get {} and {}",
+                a, b, fa, fb
+            ),
+        ));
+    }
+
+    // b is the best guess, a the last one, and the root is between b and c
+    let (mut c, mut fc) = (a, fa);
+    let (mut d, mut e) = (b - a, b - a);
+    for _ in 0..MAX_ITER {
+        if fb.signum() == fc.signum() {
+            (c, fc) = (a, fa);
+            d = b - a;
+            e = d;
+        }
+        if fc.abs() < fb.abs() {
+            (a, fa) = (b, fb);
+            (b, fb) = (c, fc);
+            (c, fc) = (a, fa);
+        }
+        let tol = 2.0 * f64::EPSILON * b.abs() + f64::MIN_POSITIVE;
+        let m = 0.5 * (c - b);
+        if m.abs() <= tol || fb == 0.0 {
+            return Ok(b);
+        }
+
+        if e.abs() >= tol && fa.abs() > fb.abs() {
+            // secant or inverse quadratic interpolation
+            let s = fb / fa;
+            let (mut p, mut q) = if a == c {
+                (2.0 * m * s, 1.0 - s)
+            } else {
+                let (q, r) = (fa / fc, fb / fc);
+                (
+                    s * (2.0 * m * q * (q - r) - (b - a) * (r - 1.0)),
+                    (q - 1.0) * (r - 1.0) * (s - 1.0),
+                )
+            };
+            if p > 0.0 {
+                q = -q;
+            } else {
+                p = -p;
+            }
+            if 2.0 * p < (3.0 * m * q - (tol * q).abs()).min((e * q).abs()) {
+                e = d;
+                d = p / q;
+            } else {
+                d = m;
+                e = m;
+            }
+        } else {
+            // bisection
+            d = m;
+            e = m;
+        }
+        (a, fa) = (b, fb);
+        b += if d.abs() > tol { d } else { tol.copysign(m) };
+        fb = finite(&mut f, b)?;
+    }
+    Ok(b)
+}
+
+/// The integral of `f` from `a` to `b` and an estimate of its error, by
+/// adaptive Gauss-Kronrod quadrature (7 and 15 points).
+pub(crate) fn integrate(
+    mut f: impl FnMut(f64) -> CalcResult<f64>,
+    a: f64,
+    b: f64,
+) -> CalcResult<(f64, f64)> {
+    if !a.is_finite() || !b.is_finite() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!("the bounds must be finite, but get {} and {}", a, b),
+        ));
+    }
+    // (from, to, integral, error) of every interval
+    let mut parts = vec![{
+        let (v, err) = kronrod(&mut f, a, b)?;
+        (a, b, v, err)
+    }];
+    loop {
+        let value: f64 = parts.iter().map(|p| p.2).sum();
+        let error: f64 = parts.iter().map(|p| p.3).sum();
+        if error <= ABS_TOL.max(REL_TOL * value.abs()) || parts.len() >= MAX_INTERVALS {
+            return Ok((value, error));
+        }
+        // split the worst interval in half
+        let worst = (0..parts.len())
+            .max_by(|i, j| parts[*i].3.total_cmp(&parts[*j].3))
+            .unwrap();
+        let (from, to, _, _) = parts.swap_remove(worst);
+        let mid = 0.5 * (from + to);
+        if mid == from || mid == to {
+            return Ok((value, error));
+        }
+        let (v, err) = kronrod(&mut f, from, mid)?;
+        parts.push((from, mid, v, err));
+        let (v, err) = kronrod(&mut f, mid, to)?;
+        parts.push((mid, to, v, err));
+    }
+}
+
+// the nodes of the 15 points Kronrod rule on [-1, 1], every second one is
+// also a node of the 7 points Gauss rule
+const KRONROD_NODES: [f64; 8] = [
+    0.9914553711208126,
+    0.9491079123427585,
+    0.8648644233597691,
+    0.7415311855993945,
+    0.5860872354676911,
+    0.4058451513773972,
+    0.20778495500789848,
+    0.0,
+];
+const KRONROD_WEIGHTS: [f64; 8] = [
+    0.022935322010529224,
+    0.06309209262997856,
+    0.10479001032225019,
+    0.14065325971552592,
+    0.1690047266392679,
+    0.19035057806478542,
+    0.20443294007529889,
+    0.20948214108472782,
+];
+const GAUSS_WEIGHTS: [f64; 4] = [
+    0.1294849661688697,
+    0.27970539148927664,
+    0.3818300505051189,
+    0.4179591836734694,
+];
+
+// the integral on [a, b] by the Kronrod rule, and how far the Gauss rule is
+fn kronrod(f: &mut impl FnMut(f64) -> CalcResult<f64>, a: f64, b: f64) -> CalcResult<(f64, f64)> {
+    let (center, half) = (0.5 * (a + b), 0.5 * (b - a));
+    let y = finite(f, center)?;
+    let mut kronrod = y * KRONROD_WEIGHTS[7];
+    let mut gauss = y * GAUSS_WEIGHTS[3];
+    for i in 0..7 {
+        let dx = half * KRONROD_NODES[i];
+        let y = finite(f, center - dx)? + finite(f, center + dx)?;
+        kronrod += KRONROD_WEIGHTS[i] * y;
+        if i % 2 == 1 {
+            gauss += GAUSS_WEIGHTS[i / 2] * y;
+        }
+    }
+    Ok((kronrod * half, ((kronrod - gauss) * half).abs()))
+}
+
+/// The smallest value of `f` between `a` and `b`, as `(x, f(x))`, by
+/// Brent's method. It may be a local minimum if `f` has many.
+pub(crate) fn minimize(
+    mut f: impl FnMut(f64) -> CalcResult<f64>,
+    a: f64,
+    b: f64,
+) -> CalcResult<(f64, f64)> {
+    const GOLDEN: f64 = 0.381_966_011_250_105_1;
+    let (mut a, mut b) = (a.min(b), a.max(b));
+    let mut x = a + GOLDEN * (b - a);
+    let (mut w, mut v) = (x, x);
+    let mut fx = finite(&mut f, x)?;
+    let (mut fw, mut fv) = (fx, fx);
+    let (mut d, mut e) = (0.0f64, 0.0f64);
+    for _ in 0..MAX_ITER {
+        let mid = 0.5 * (a + b);
+        let tol = f64::EPSILON.sqrt() * x.abs() + 1e-10;
+        if (x - mid).abs() <= 2.0 * tol - 0.5 * (b - a) {
+            break;
+        }
+        let mut golden = true;
+        if e.abs() > tol {
+            // a parabola through x, w and v
+            let r = (x - w) * (fx - fv);
+            let q = (x - v) * (fx - fw);
+            let mut p = (x - v) * q - (x - w) * r;
+            let mut q = 2.0 * (q - r);
+            if q > 0.0 {
+                p = -p;
+            }
+            q = q.abs();
+            if p.abs() < (0.5 * q * e).abs() && p > q * (a - x) && p < q * (b - x) {
+                e = d;
+                d = p / q;
+                let u = x + d;
+                if u - a < 2.0 * tol || b - u < 2.0 * tol {
+                    d = tol.copysign(mid - x);
+                }
+                golden = false;
+            }
+        }
+        if golden {
+            e = if x >= mid { a - x } else { b - x };
+            d = GOLDEN * e;
+        }
+        let u = if d.abs() >= tol {
+            x + d
+        } else {
+            x + tol.copysign(d)
+        };
+        let fu = finite(&mut f, u)?;
+        if fu <= fx {
+            if u >= x {
+                a = x;
+            } else {
+                b = x;
+            }
+            (v, fv) = (w, fw);
+            (w, fw) = (x, fx);
+            (x, fx) = (u, fu);
+        } else {
+            if u < x {
+                a = u;
+            } else {
+                b = u;
+            }
+            if fu <= fw || w == x {
+                (v, fv) = (w, fw);
+                (w, fw) = (u, fu);
+            } else if fu <= fv || v == x || v == w {
+                (v, fv) = (u, fu);
+            }
+        }
+    }
+    Ok((x, fx))
+}
+
+/// A solution of `f(x, y) = 0, g(x, y) = 0` near `(x, y)`, by Newton's
+/// method with a numeric Jacobian.
+pub(crate) fn solve(
+    mut f: impl FnMut(f64, f64) -> CalcResult<(f64, f64)>,
+    x: f64,
+    y: f64,
+) -> CalcResult<(f64, f64)> {
+    let (x0, y0) = (x, y);
+    let (mut x, mut y) = (x, y);
+    let (mut f1, mut f2) = f(x, y)?;
+    for _ in 0..MAX_ITER {
+        let norm = f1.hypot(f2);
+        if norm <= ABS_TOL {
+            return Ok((x, y));
+        }
+        // the Jacobian by central differences
+        let (hx, hy) = (1e-7 * x.abs().max(1.0), 1e-7 * y.abs().max(1.0));
+        let (a1, a2) = f(x + hx, y)?;
+        let (b1, b2) = f(x - hx, y)?;
+        let (c1, c2) = f(x, y + hy)?;
+        let (d1, d2) = f(x, y - hy)?;
+        let (j11, j21) = ((a1 - b1) / (2.0 * hx), (a2 - b2) / (2.0 * hx));
+        let (j12, j22) = ((c1 - d1) / (2.0 * hy), (c2 - d2) / (2.0 * hy));
+        let det = j11 * j22 - j12 * j21;
+        if det == 0.0 || !det.is_finite() {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!("the system is singular at ({}, {})", x, y),
+            ));
+        }
+        let dx = (f1 * j22 - f2 * j12) / det;
+        let dy = (j11 * f2 - j21 * f1) / det;
+
+        // a shorter step if the full one makes it worse
+        let mut t = 1.0;
+        loop {
+            let (nx, ny) = (x - t * dx, y - t * dy);
+            let (g1, g2) = f(nx, ny)?;
+            if g1.hypot(g2) < norm || t < 1e-4 {
+                (x, y, f1, f2) = (nx, ny, g1, g2);
+                break;
+            }
+            t *= 0.5;
+        }
+        let tiny = |d: f64, v: f64| (t * d).abs() <= f64::EPSILON * v.abs().max(1.0);
+        if tiny(dx, x) && tiny(dy, y) {
+            break;
+        }
+    }
+    if f1.hypot(f2) <= 1e-8 {
+        return Ok((x, y));
+    }
+    Err(CalcError::new(
+        ErrorKind::Domain,
+        format!("can't find a solution near ({}, {})", x0, y0),
+    ))
+}
+
+fn finite(f: &mut impl FnMut(f64) -> CalcResult<f64>, x: f64) -> CalcResult<f64> {
+    let y = f(x)?;
+    if y.is_nan() {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!("the function is not a number at {}", x),
+        ));
+    }
+    Ok(y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Env, OneMore};
+
+    #[test]
+    fn test_solve() {
+        let x = root(|x| Ok(x * x - 2.0), 0.0, 2.0).unwrap();
+        assert!((x - 2f64.sqrt()).abs() < 1e-14);
+        let x = root(|x| Ok(x.cos() - x), 0.0, 1.0).unwrap();
+        assert!((x.cos() - x).abs() < 1e-14);
+        assert!(root(|x| Ok(x * x + 1.0), -1.0, 1.0).is_err());
+
+        let (v, err) = integrate(|x| Ok(x.sin()), 0.0, std::f64::consts::PI).unwrap();
+        assert!((v - 2.0).abs() < 1e-13 && err < 1e-11);
+        let (v, _) = integrate(|x| Ok(x.ln()), 0.0, 1.0).unwrap();
+        assert!((v + 1.0).abs() < 1e-9);
+        let (v, _) = integrate(Ok, 1.0, 0.0).unwrap();
+        assert!((v + 0.5).abs() < 1e-15);
+        assert!(integrate(Ok, 0.0, f64::INFINITY).is_err());
+
+        let (x, y) = minimize(|x| Ok((x - 1.0) * (x - 1.0) + 3.0), -5.0, 5.0).unwrap();
+        assert!((x - 1.0).abs() < 1e-7 && (y - 3.0).abs() < 1e-14);
+
+        // a circle and a line
+        let (x, y) = solve(|x, y| Ok((x * x + y * y - 4.0, x - y)), 1.0, 2.0).unwrap();
+        assert!((x - 2f64.sqrt()).abs() < 1e-12 && (y - 2f64.sqrt()).abs() < 1e-12);
+        assert!(solve(|x, y| Ok((x * x + y * y + 1.0, x - y)), 1.0, 2.0).is_err());
+    }
+
+    #[test]
+    fn test_magic() {
+        let mut env = Env::new();
+        env.run("fun f(x) = x^2 - 2; fun c(x y) = x^2 + y^2 - 4; fun l(x y) = x - y")
+            .unwrap();
+        let run = |env: &mut Env, s| env.run(s).unwrap().unwrap();
+        let x = run(&mut env, "%root(f, 0, 2)").one().unwrap();
+        assert!((x - 2f64.sqrt()).abs() < 1e-15);
+        let v = run(&mut env, "%integrate(f, 0, 3)");
+        assert!((v.values()[0] - 3.0).abs() < 1e-14 && v.values()[1] < 1e-12);
+        let v = run(&mut env, "%minimize(f, -1, 2)");
+        assert!(v.values()[0].abs() < 1e-7 && (v.values()[1] + 2.0).abs() < 1e-14);
+        let v = run(&mut env, "%solve(c, l, 1, 2)");
+        assert!(v.values().iter().all(|x| (x - 2f64.sqrt()).abs() < 1e-12));
+
+        // a body with lists is walked instead of compiled
+        env.run("fun w(x) = sum([x, -1])").unwrap();
+        assert_eq!(run(&mut env, "%root(w, 0, 3)"), OneMore::One(1.0));
+
+        // globals named like the args the function is compiled with
+        env.run("x0 = 100; x1 = 5; fun i(x) = x + x0; fun z(x) = x - x1")
+            .unwrap();
+        let v = run(&mut env, "%integrate(i, 0, 1)");
+        assert!((v.values()[0] - 100.5).abs() < 1e-12);
+        assert_eq!(run(&mut env, "%root(z, 0, 10)"), OneMore::One(5.0));
+
+        for (s, kind) in [
+            ("%root(f, 3, 4)", ErrorKind::Domain),
+            ("%root(c, 0, 1)", ErrorKind::Arity),
+            ("%solve(f, l, 0, 1)", ErrorKind::Arity),
+            ("%integrate(g, 0, 1)", ErrorKind::UnknownName),
+        ] {
+            assert_eq!(env.run(s).unwrap_err().kind(), kind, "{}", s);
+        }
+    }
+}
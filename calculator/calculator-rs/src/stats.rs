@@ -0,0 +1,433 @@
+// Statistics, combinatorics and probability distributions, and the special
+// functions they need.
+use std::f64::consts::{PI, SQRT_2};
+
+use crate::error::{CalcError, CalcResult, ErrorKind};
+use crate::onemore::OneMore;
+
+// the coefficients of the Lanczos approximation with g = 7, about 15 digits
+const LANCZOS_G: f64 = 7.0;
+const LANCZOS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+fn is_integer(x: f64) -> bool {
+    x.fract() == 0.0 && x.is_finite()
+}
+
+/// `x!`, the product for integers and `gamma(x + 1)` for the others, it is
+/// `NaN` on negative integers.
+pub(crate) fn factorial(x: f64) -> f64 {
+    if !is_integer(x) {
+        return gamma(x + 1.0);
+    }
+    if x < 0.0 {
+        return f64::NAN;
+    }
+    // 171! is already too big for a f64
+    if x > 170.0 {
+        return f64::INFINITY;
+    }
+    (2..=x as u32).fold(1.0, |acc, i| acc * i as f64)
+}
+
+/// The gamma function, `NaN` on its poles 0, -1, -2, ...
+pub(crate) fn gamma(x: f64) -> f64 {
+    if is_integer(x) {
+        return factorial(x - 1.0);
+    }
+    if x < 0.5 {
+        // the reflection formula, gamma(x) gamma(1 - x) = pi / sin(pi x)
+        return PI / ((PI * x).sin() * gamma(1.0 - x));
+    }
+    if x > 171.7 {
+        return f64::INFINITY;
+    }
+    let x = x - 1.0;
+    let t = x + LANCZOS_G + 0.5;
+    let sum = LANCZOS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS[0], |acc, (i, c)| acc + c / (x + i as f64 + 1.0));
+    // t^(x + 0.5) in two halves, so it doesn't overflow before exp(-t)
+    let half = t.powf((x + 0.5) / 2.0);
+    (2.0 * PI).sqrt() * half * (-t).exp() * half * sum
+}
+
+// ln(gamma(x)) for x > 0, for the distributions with big numbers
+fn ln_gamma(x: f64) -> f64 {
+    if x < 0.5 {
+        return (PI / (PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + LANCZOS_G + 0.5;
+    let sum = LANCZOS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS[0], |acc, (i, c)| acc + c / (x + i as f64 + 1.0));
+    0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+/// The error function.
+pub(crate) fn erf(x: f64) -> f64 {
+    if x.abs() >= 3.0 {
+        return x.signum() * (1.0 - erfc(x.abs()));
+    }
+    // 2/sqrt(pi) exp(-x^2) sum(2^n x^(2n+1) / (1 3 5 ... (2n+1))), the terms
+    // are all positive so nothing cancels
+    let (mut term, mut sum) = (x, x);
+    let mut n = 0.0;
+    while term.abs() > sum.abs() * f64::EPSILON {
+        n += 1.0;
+        term *= 2.0 * x * x / (2.0 * n + 1.0);
+        sum += term;
+    }
+    2.0 / PI.sqrt() * (-x * x).exp() * sum
+}
+
+// 1 - erf(x), without losing the digits of the tails
+fn erfc(x: f64) -> f64 {
+    if x < 3.0 {
+        return if x > -3.0 {
+            1.0 - erf(x)
+        } else {
+            2.0 - erfc(-x)
+        };
+    }
+    // exp(-x^2) / sqrt(pi) / (x + (1/2) / (x + 1 / (x + (3/2) / (x + ...))))
+    let fraction = (1..60).rev().fold(x, |acc, k| x + k as f64 / 2.0 / acc);
+    (-x * x).exp() / PI.sqrt() / fraction
+}
+
+fn domain<T>(msg: String) -> CalcResult<T> {
+    Err(CalcError::new(ErrorKind::Domain, msg))
+}
+
+fn count(x: f64, what: &str) -> CalcResult<f64> {
+    if !is_integer(x) || x < 0.0 {
+        return domain(format!(
+            "{} must be a non-negative integer, but get {}",
+            what, x
+        ));
+    }
+    Ok(x)
+}
+
+fn probability(p: f64) -> CalcResult<f64> {
+    if !(0.0..=1.0).contains(&p) {
+        return domain(format!("a probability must be in 0..1, but get {}", p));
+    }
+    Ok(p)
+}
+
+// the mean and the standard deviation of a normal distribution, 0 and 1 if
+// they are left out
+fn normal(args: &[f64]) -> CalcResult<(f64, f64)> {
+    let mu = args.get(1).copied().unwrap_or(0.0);
+    let sigma = args.get(2).copied().unwrap_or(1.0);
+    if sigma <= 0.0 || sigma.is_nan() {
+        return domain(format!(
+            "the standard deviation must be positive, but get {}",
+            sigma
+        ));
+    }
+    Ok((mu, sigma))
+}
+
+fn sorted(xs: &[f64]) -> CalcResult<Vec<f64>> {
+    if xs.is_empty() {
+        return domain("expect at least one number, but get an empty list".to_owned());
+    }
+    if xs.iter().any(|x| x.is_nan()) {
+        return domain("can't order the numbers, one of them is NaN".to_owned());
+    }
+    let mut xs = xs.to_vec();
+    xs.sort_by(f64::total_cmp);
+    Ok(xs)
+}
+
+// the value at `p` in 0..1 of the sorted numbers, between the two nearest
+fn quantile(xs: &[f64], p: f64) -> f64 {
+    let at = p * (xs.len() - 1) as f64;
+    let (i, frac) = (at.floor() as usize, at.fract());
+    match xs.get(i + 1) {
+        Some(next) if frac != 0.0 => xs[i] + (next - xs[i]) * frac,
+        _ => xs[i],
+    }
+}
+
+pub(crate) fn median(xs: &[f64]) -> CalcResult<f64> {
+    Ok(quantile(&sorted(xs)?, 0.5))
+}
+
+// percentile(x, p) of a single number, which is x for every percent
+pub(crate) fn percentile(args: &[f64]) -> CalcResult<f64> {
+    percent_of(&args[..1], args[1])
+}
+
+// percentile(xs, p) of a list or a matrix, taken whole and not element-wise,
+// see `NativeFun::matrix`
+pub(crate) fn percentile_list(args: &[OneMore]) -> CalcResult<Option<OneMore>> {
+    let xs = match &args[0] {
+        xs @ (OneMore::More(_) | OneMore::Matrix(_)) => xs.values(),
+        _ => return Ok(None),
+    };
+    match args[1] {
+        OneMore::One(p) => Ok(Some(OneMore::One(percent_of(&xs, p)?))),
+        _ => domain("the percent of percentile must be a number".to_owned()),
+    }
+}
+
+fn percent_of(xs: &[f64], p: f64) -> CalcResult<f64> {
+    if !(0.0..=100.0).contains(&p) {
+        return domain(format!("the percent must be in 0..100, but get {}", p));
+    }
+    Ok(quantile(&sorted(xs)?, p / 100.0))
+}
+
+/// The sample variance, with `n - 1` in the denominator.
+pub(crate) fn var(xs: &[f64]) -> CalcResult<f64> {
+    if xs.len() < 2 {
+        return domain(format!(
+            "the sample variance need at least 2 numbers, but get {}",
+            xs.len()
+        ));
+    }
+    let n = xs.len() as f64;
+    let mean = xs.iter().sum::<f64>() / n;
+    Ok(xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0))
+}
+
+pub(crate) fn stdev(xs: &[f64]) -> CalcResult<f64> {
+    var(xs).map(f64::sqrt)
+}
+
+/// The ways to pick `r` of `n` things in order.
+pub(crate) fn npr(args: &[f64]) -> CalcResult<f64> {
+    let (n, r) = (count(args[0], "n")?, count(args[1], "r")?);
+    if r > n {
+        return Ok(0.0);
+    }
+    // the product only grows, past 170 factors it is always inf, so a huge
+    // r doesn't take r steps
+    let mut acc = 1.0;
+    for i in 0..r as u64 {
+        acc *= n - i as f64;
+        if acc.is_infinite() {
+            break;
+        }
+    }
+    Ok(acc)
+}
+
+/// The ways to pick `r` of `n` things, the order doesn't matter.
+pub(crate) fn ncr(args: &[f64]) -> CalcResult<f64> {
+    let (n, r) = (count(args[0], "n")?, count(args[1], "r")?);
+    if r > n {
+        return Ok(0.0);
+    }
+    // every partial product is itself a binomial, so it stays an integer,
+    // and they grow at least like 2^i, so a huge r stops at inf soon
+    let r = r.min(n - r);
+    let mut acc = 1.0;
+    for i in 1..=r as u64 {
+        acc = acc * (n - r + i as f64) / i as f64;
+        if acc.is_infinite() {
+            break;
+        }
+    }
+    Ok(acc)
+}
+
+pub(crate) fn gamma_checked(args: &[f64]) -> CalcResult<f64> {
+    let x = args[0];
+    if x <= 0.0 && is_integer(x) {
+        return domain(format!(
+            "gamma is not defined on 0 and negative integers, but get {}",
+            x
+        ));
+    }
+    Ok(gamma(x))
+}
+
+// normpdf(x [, mu, sigma])
+pub(crate) fn normpdf(args: &[f64]) -> CalcResult<f64> {
+    let (mu, sigma) = normal(args)?;
+    let z = (args[0] - mu) / sigma;
+    Ok((-z * z / 2.0).exp() / (sigma * (2.0 * PI).sqrt()))
+}
+
+// normcdf(x [, mu, sigma])
+pub(crate) fn normcdf(args: &[f64]) -> CalcResult<f64> {
+    let (mu, sigma) = normal(args)?;
+    Ok(erfc(-(args[0] - mu) / (sigma * SQRT_2)) / 2.0)
+}
+
+// norminv(p [, mu, sigma]), the x with normcdf(x) = p
+pub(crate) fn norminv(args: &[f64]) -> CalcResult<f64> {
+    let p = probability(args[0])?;
+    let (mu, sigma) = normal(args)?;
+    Ok(mu + sigma * probit(p))
+}
+
+// the inverse of the standard normal cdf, by Acklam's rational approximation
+// and a step of Halley's method to the full precision
+fn probit(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const LOW: f64 = 0.024_25;
+    if p <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p >= 1.0 {
+        return f64::INFINITY;
+    }
+    let poly = |cs: &[f64], x: f64| cs.iter().fold(0.0, |acc, c| acc * x + c);
+    let tail = |p: f64| {
+        let q = (-2.0 * p.ln()).sqrt();
+        poly(&C, q) / (poly(&D, q) * q + 1.0)
+    };
+    let x = if p < LOW {
+        tail(p)
+    } else if p > 1.0 - LOW {
+        -tail(1.0 - p)
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        poly(&A, r) * q / (poly(&B, r) * r + 1.0)
+    };
+    let e = erfc(-x / SQRT_2) / 2.0 - p;
+    let u = e * (2.0 * PI).sqrt() * (x * x / 2.0).exp();
+    x - u / (1.0 + x * u / 2.0)
+}
+
+// binompdf(n, p, k), the chance of k successes in n trials
+pub(crate) fn binompdf(args: &[f64]) -> CalcResult<f64> {
+    let n = count(args[0], "n")?;
+    let p = probability(args[1])?;
+    let k = args[2];
+    if !is_integer(k) || k < 0.0 || k > n {
+        return Ok(0.0);
+    }
+    let ways = ncr(&[n, k])?;
+    if ways.is_finite() {
+        return Ok(ways * p.powf(k) * (1.0 - p).powf(n - k));
+    }
+    let ln_ways = ln_gamma(n + 1.0) - ln_gamma(k + 1.0) - ln_gamma(n - k + 1.0);
+    Ok((ln_ways + k * p.ln() + (n - k) * (1.0 - p).ln()).exp())
+}
+
+// poissonpdf(lambda, k), the chance of k events with lambda on average
+pub(crate) fn poissonpdf(args: &[f64]) -> CalcResult<f64> {
+    let (lambda, k) = (args[0], args[1]);
+    if lambda <= 0.0 || lambda.is_nan() {
+        return domain(format!(
+            "the mean of a poisson distribution must be positive, but get {}",
+            lambda
+        ));
+    }
+    if !is_integer(k) || k < 0.0 {
+        return Ok(0.0);
+    }
+    let v = lambda.powf(k) * (-lambda).exp() / factorial(k);
+    if v.is_finite() && v != 0.0 {
+        return Ok(v);
+    }
+    Ok((k * lambda.ln() - lambda - ln_gamma(k + 1.0)).exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-12 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn test_special() {
+        assert_eq!(factorial(5.0), 120.0);
+        assert_eq!(factorial(171.0), f64::INFINITY);
+        assert!(factorial(-1.0).is_nan());
+        assert!(close(factorial(0.5), PI.sqrt() / 2.0));
+        assert!(close(gamma(-0.5), -2.0 * PI.sqrt()));
+        assert!(close(gamma(10.5), 1_133_278.388_948_785_4));
+        assert!(close(ln_gamma(100.0), 359.134_205_369_575_4));
+
+        assert_eq!(erf(0.0), 0.0);
+        assert!(close(erf(0.5), 0.520_499_877_813_046_5));
+        assert!(close(erf(-2.0), -0.995_322_265_018_952_7));
+        assert!(close(erfc(4.0), 1.541_725_790_028_002e-8));
+        assert!(close(erfc(-4.0), 2.0 - 1.541_725_790_028_002e-8));
+
+        assert_eq!(ncr(&[1e12, 5e11]), Ok(f64::INFINITY));
+        assert_eq!(npr(&[1e12, 1e11]), Ok(f64::INFINITY));
+        assert_eq!(ncr(&[1e12, 1e12 - 2.0]), Ok(1e12 * (1e12 - 1.0) / 2.0));
+        assert_eq!(npr(&[1e12, 2.0]), Ok(1e12 * (1e12 - 1.0)));
+    }
+
+    #[test]
+    fn test_distribution() {
+        assert!(close(normcdf(&[1.96]).unwrap(), 0.975_002_104_851_779_5));
+        assert!(close(normcdf(&[-10.0]).unwrap(), 7.619_853_024_160_593e-24));
+        for p in [1e-10, 0.01, 0.3, 0.5, 0.975, 0.999_999] {
+            assert!(close(normcdf(&[norminv(&[p]).unwrap()]).unwrap(), p));
+        }
+        assert!(close(
+            norminv(&[0.975, 100.0, 15.0]).unwrap(),
+            129.399_459_768_100_8
+        ));
+        assert!(norminv(&[1.5]).is_err());
+        assert!(normpdf(&[0.0, 0.0, -1.0]).is_err());
+
+        assert_eq!(binompdf(&[4.0, 0.5, 2.0]), Ok(0.375));
+        assert_eq!(binompdf(&[4.0, 0.5, 5.0]), Ok(0.0));
+        assert!(close(
+            binompdf(&[2000.0, 0.5, 1000.0]).unwrap(),
+            0.017_839_011_145_854_32
+        ));
+        assert!(close(
+            poissonpdf(&[2.0, 3.0]).unwrap(),
+            0.180_447_044_315_483_6
+        ));
+        assert!(close(
+            poissonpdf(&[1000.0, 1000.0]).unwrap(),
+            0.012_614_611_348_721_5
+        ));
+    }
+}
@@ -0,0 +1,375 @@
+// Physical units, a quantity is a number with a unit like `3 km`.
+use std::fmt::{self, Display};
+
+use crate::{
+    ast::BinaryOp,
+    env::boolean,
+    error::{CalcError, CalcResult, ErrorKind},
+    onemore::OneMore,
+};
+
+// the powers of m, kg, s, A, K, mol and cd
+pub(crate) type Dim = [i8; 7];
+
+const BASE: [&str; 7] = ["m", "kg", "s", "A", "K", "mol", "cd"];
+
+const PREFIXES: [(&str, f64); 21] = [
+    ("Y", 1e24),
+    ("Z", 1e21),
+    ("E", 1e18),
+    ("P", 1e15),
+    ("T", 1e12),
+    ("G", 1e9),
+    ("M", 1e6),
+    ("k", 1e3),
+    ("h", 1e2),
+    ("da", 1e1),
+    ("d", 1e-1),
+    ("c", 1e-2),
+    ("m", 1e-3),
+    ("u", 1e-6),
+    ("µ", 1e-6),
+    ("n", 1e-9),
+    ("p", 1e-12),
+    ("f", 1e-15),
+    ("a", 1e-18),
+    ("z", 1e-21),
+    ("y", 1e-24),
+];
+
+// name, how many SI units, dimension, and if SI prefixes can be used
+#[rustfmt::skip]
+const UNITS: [(&str, f64, Dim, bool); 36] = [
+    ("m", 1.0, [1, 0, 0, 0, 0, 0, 0], true),
+    ("g", 1e-3, [0, 1, 0, 0, 0, 0, 0], true),
+    ("s", 1.0, [0, 0, 1, 0, 0, 0, 0], true),
+    ("A", 1.0, [0, 0, 0, 1, 0, 0, 0], true),
+    ("K", 1.0, [0, 0, 0, 0, 1, 0, 0], true),
+    ("mol", 1.0, [0, 0, 0, 0, 0, 1, 0], true),
+    ("cd", 1.0, [0, 0, 0, 0, 0, 0, 1], true),
+    // derived units, the first one of a dimension is used to show it
+    ("Hz", 1.0, [0, 0, -1, 0, 0, 0, 0], true),
+    ("N", 1.0, [1, 1, -2, 0, 0, 0, 0], true),
+    ("Pa", 1.0, [-1, 1, -2, 0, 0, 0, 0], true),
+    ("J", 1.0, [2, 1, -2, 0, 0, 0, 0], true),
+    ("W", 1.0, [2, 1, -3, 0, 0, 0, 0], true),
+    ("C", 1.0, [0, 0, 1, 1, 0, 0, 0], true),
+    ("V", 1.0, [2, 1, -3, -1, 0, 0, 0], true),
+    ("ohm", 1.0, [2, 1, -3, -2, 0, 0, 0], true),
+    ("F", 1.0, [-2, -1, 4, 2, 0, 0, 0], true),
+    ("T", 1.0, [0, 1, -2, -1, 0, 0, 0], true),
+    ("Wb", 1.0, [2, 1, -2, -1, 0, 0, 0], true),
+    ("H", 1.0, [2, 1, -2, -2, 0, 0, 0], true),
+    // other common units
+    ("L", 1e-3, [3, 0, 0, 0, 0, 0, 0], true),
+    ("t", 1e3, [0, 1, 0, 0, 0, 0, 0], false),
+    ("min", 60.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("h", 3600.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("d", 86400.0, [0, 0, 1, 0, 0, 0, 0], false),
+    ("eV", 1.602176634e-19, [2, 1, -2, 0, 0, 0, 0], true),
+    ("Wh", 3600.0, [2, 1, -2, 0, 0, 0, 0], true),
+    ("cal", 4.184, [2, 1, -2, 0, 0, 0, 0], true),
+    ("bar", 1e5, [-1, 1, -2, 0, 0, 0, 0], true),
+    ("atm", 101325.0, [-1, 1, -2, 0, 0, 0, 0], false),
+    ("inch", 0.0254, [1, 0, 0, 0, 0, 0, 0], false),
+    ("ft", 0.3048, [1, 0, 0, 0, 0, 0, 0], false),
+    ("mi", 1609.344, [1, 0, 0, 0, 0, 0, 0], false),
+    ("au", 149597870700.0, [1, 0, 0, 0, 0, 0, 0], false),
+    ("lb", 0.45359237, [0, 1, 0, 0, 0, 0, 0], false),
+    ("mph", 0.44704, [1, 0, -1, 0, 0, 0, 0], false),
+    ("kmh", 1.0 / 3.6, [1, 0, -1, 0, 0, 0, 0], false),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Unit {
+    pub(crate) name: String,
+    // how many SI units
+    pub(crate) factor: f64,
+    pub(crate) dim: Dim,
+}
+
+impl Unit {
+    pub(crate) fn mul(&self, other: &Unit) -> Unit {
+        Unit {
+            name: format!("{}*{}", self.name, other.name),
+            factor: self.factor * other.factor,
+            dim: add(self.dim, other.dim, 1),
+        }
+    }
+
+    pub(crate) fn div(&self, other: &Unit) -> Unit {
+        Unit {
+            name: format!("{}/{}", self.name, other.name),
+            factor: self.factor / other.factor,
+            dim: add(self.dim, other.dim, -1),
+        }
+    }
+
+    pub(crate) fn powi(&self, n: i32) -> Unit {
+        Unit {
+            name: format!("{}^{}", self.name, n),
+            factor: self.factor.powi(n),
+            dim: self.dim.map(|d| d * n as i8),
+        }
+    }
+}
+
+/// A unit by its name, like `km`, `kWh` or `min`.
+pub(crate) fn lookup(name: &str) -> Option<Unit> {
+    let unit = |(_, factor, dim, _): &(&str, f64, Dim, bool), scale: f64| Unit {
+        name: name.to_owned(),
+        factor: factor * scale,
+        dim: *dim,
+    };
+    if let Some(u) = UNITS.iter().find(|u| u.0 == name) {
+        return Some(unit(u, 1.0));
+    }
+    PREFIXES.iter().find_map(|(prefix, scale)| {
+        let rest = name.strip_prefix(prefix)?;
+        let u = UNITS.iter().find(|u| u.0 == rest && u.3)?;
+        Some(unit(u, *scale))
+    })
+}
+
+// the SI unit to show a dimension, like `N` or `m/s^2`
+fn si_unit(dim: Dim) -> Unit {
+    if let Some(u) = UNITS[7..19].iter().find(|u| u.2 == dim) {
+        return lookup(u.0).unwrap();
+    }
+    let part = |i: usize, d: i8| match d {
+        1 => BASE[i].to_owned(),
+        d => format!("{}^{}", BASE[i], d),
+    };
+    let num: Vec<String> = (0..7)
+        .filter(|i| dim[*i] > 0)
+        .map(|i| part(i, dim[i]))
+        .collect();
+    let den: Vec<String> = (0..7)
+        .filter(|i| dim[*i] < 0)
+        .map(|i| part(i, -dim[i]))
+        .collect();
+    let mut name = if num.is_empty() {
+        "1".to_owned()
+    } else {
+        num.join("*")
+    };
+    if !den.is_empty() {
+        name = format!("{}/{}", name, den.join("/"));
+    }
+    Unit {
+        name,
+        factor: 1.0,
+        dim,
+    }
+}
+
+fn add(a: Dim, b: Dim, sign: i8) -> Dim {
+    let mut dim = a;
+    for (d, b) in dim.iter_mut().zip(b) {
+        *d += sign * b;
+    }
+    dim
+}
+
+/// A number with a unit, like `3 km`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+    // the value in `unit`
+    value: f64,
+    unit: Unit,
+}
+
+/// A quantity, or a plain number if `unit` has no dimension like `m/km`.
+pub(crate) fn quantity(value: f64, unit: Unit) -> OneMore {
+    if unit.dim == [0; 7] {
+        return OneMore::One(value * unit.factor);
+    }
+    OneMore::Quantity(Quantity { value, unit })
+}
+
+fn si(value: f64, dim: Dim) -> OneMore {
+    quantity(value, si_unit(dim))
+}
+
+impl Quantity {
+    /// The number, in the unit given by `unit`.
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit.name
+    }
+
+    /// The number in SI base units.
+    pub fn si_value(&self) -> f64 {
+        self.value * self.unit.factor
+    }
+
+    pub(crate) fn neg(&self) -> OneMore {
+        quantity(-self.value, self.unit.clone())
+    }
+}
+
+/// `value` in `unit`, which must be of the same dimension.
+pub(crate) fn convert(value: &OneMore, unit: &Unit) -> CalcResult<OneMore> {
+    let (si, dim) = match value {
+        OneMore::Quantity(q) => (q.si_value(), q.unit.dim),
+        v => match v.one() {
+            Some(x) => (x, [0; 7]),
+            None => {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    "only real numbers and quantities can be converted",
+                ))
+            }
+        },
+    };
+    if dim != unit.dim {
+        return Err(CalcError::new(
+            ErrorKind::Domain,
+            format!(
+                "can't convert {} to {}",
+                if dim == [0; 7] {
+                    "a number".to_owned()
+                } else {
+                    si_unit(dim).name
+                },
+                si_unit(unit.dim).name
+            ),
+        ));
+    }
+    Ok(quantity(si / unit.factor, unit.clone()))
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.value, self.unit.name)
+    }
+}
+
+/// `None` if neither side has a unit, then the caller goes on without.
+pub(crate) fn binary(op: &BinaryOp, a: &OneMore, b: &OneMore) -> CalcResult<Option<OneMore>> {
+    let (qa, qb) = match (a, b) {
+        (OneMore::Quantity(a), OneMore::Quantity(b)) => (Some(a), Some(b)),
+        (OneMore::Quantity(a), _) => (Some(a), None),
+        (_, OneMore::Quantity(b)) => (None, Some(b)),
+        _ => return Ok(None),
+    };
+    let number = |v: &OneMore| {
+        v.one().ok_or_else(|| {
+            CalcError::new(
+                ErrorKind::Domain,
+                "only real numbers can go with a quantity",
+            )
+        })
+    };
+    let v = match (op, qa, qb) {
+        (BinaryOp::Mult, Some(a), Some(b)) => {
+            si(a.si_value() * b.si_value(), add(a.unit.dim, b.unit.dim, 1))
+        }
+        (BinaryOp::Div, Some(a), Some(b)) => {
+            si(a.si_value() / b.si_value(), add(a.unit.dim, b.unit.dim, -1))
+        }
+        // a plain number keeps the unit
+        (BinaryOp::Mult, Some(a), None) => quantity(a.value * number(b)?, a.unit.clone()),
+        (BinaryOp::Mult, None, Some(b)) => quantity(number(a)? * b.value, b.unit.clone()),
+        (BinaryOp::Div, Some(a), None) => quantity(a.value / number(b)?, a.unit.clone()),
+        (BinaryOp::Div, None, Some(b)) => si(number(a)? / b.si_value(), b.unit.dim.map(|d| -d)),
+        (BinaryOp::Square, Some(a), None) => {
+            let n = number(b)?;
+            if n.fract() != 0.0 || n.abs() > 16.0 {
+                return Err(CalcError::new(
+                    ErrorKind::Domain,
+                    format!(
+                        "a quantity can only be raised to a small integer, but get {}",
+                        n
+                    ),
+                ));
+            }
+            quantity(a.value.powi(n as i32), a.unit.powi(n as i32))
+        }
+        (_, Some(a), Some(b)) => {
+            if a.unit.dim != b.unit.dim {
+                return Err(mismatch(op, &a.unit, &b.unit));
+            }
+            let b = b.si_value() / a.unit.factor;
+            match op {
+                BinaryOp::Plus => quantity(a.value + b, a.unit.clone()),
+                BinaryOp::Sub => quantity(a.value - b, a.unit.clone()),
+                BinaryOp::Less => OneMore::One(boolean(a.value < b)),
+                BinaryOp::LessEq => OneMore::One(boolean(a.value <= b)),
+                BinaryOp::Eq => OneMore::One(boolean(a.value == b)),
+                BinaryOp::NotEq => OneMore::One(boolean(a.value != b)),
+                BinaryOp::Greater => OneMore::One(boolean(a.value > b)),
+                BinaryOp::GreaterEq => OneMore::One(boolean(a.value >= b)),
+                _ => return Err(op_error(op)),
+            }
+        }
+        (_, Some(q), None) | (_, None, Some(q)) => {
+            return Err(CalcError::new(
+                ErrorKind::Domain,
+                format!(
+                    "can't {} {} and a number without unit",
+                    verb(op),
+                    si_unit(q.unit.dim).name
+                ),
+            ))
+        }
+        (_, None, None) => unreachable!(),
+    };
+    Ok(Some(v))
+}
+
+fn verb(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Plus => "add",
+        BinaryOp::Sub => "subtract",
+        BinaryOp::Square => "raise",
+        _ => "compare",
+    }
+}
+
+#[cold]
+fn mismatch(op: &BinaryOp, a: &Unit, b: &Unit) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        format!(
+            "can't {} {} and {}",
+            verb(op),
+            si_unit(a.dim).name,
+            si_unit(b.dim).name
+        ),
+    )
+}
+
+#[cold]
+fn op_error(op: &BinaryOp) -> CalcError {
+    CalcError::new(
+        ErrorKind::Domain,
+        format!("{:?} doesn't work on quantities", op),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let km = lookup("km").unwrap();
+        assert_eq!((km.factor, km.dim), (1e3, [1, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(lookup("kg").unwrap().factor, 1.0);
+        assert_eq!(lookup("ms").unwrap().factor, 1e-3);
+        assert_eq!(lookup("min").unwrap().factor, 60.0);
+        assert_eq!(lookup("kWh").unwrap().factor, 3.6e6);
+        assert_eq!(lookup("µs").unwrap().factor, 1e-6);
+        assert!(lookup("kmin").is_none());
+        assert!(lookup("x").is_none());
+
+        assert_eq!(si_unit([1, 1, -2, 0, 0, 0, 0]).name, "N");
+        assert_eq!(si_unit([1, 0, -2, 0, 0, 0, 0]).name, "m/s^2");
+        assert_eq!(si_unit([0, 0, 0, 0, 0, 0, 0]).name, "1");
+        assert_eq!(km.div(&lookup("h").unwrap()).name, "km/h");
+    }
+}
@@ -13,22 +13,6 @@ pub(crate) fn is_number(c: char) -> bool {
     c.is_ascii_digit()
 }
 
-macro_rules! print_err {
-    ($($arg:tt)*) => {
-        println!("{}", format_args!($($arg)*));
-    };
-}
-
-pub(crate) use print_err;
-
-pub(crate) fn factorial(num: u32) -> f64 {
-    let mut result: u32 = 1;
-    for i in 2..=num {
-        result = result.wrapping_mul(i);
-    }
-    result as f64
-}
-
 pub(crate) fn hash_it<T>(v: &T) -> u64
 where
     T: Hash + ?Sized,